@@ -7,25 +7,52 @@
 // declare dependencies on other crates
 extern crate clap; // 3rd party command line parser
 extern crate mercurial; // mercurial stuff
+extern crate mercurial_types;
 #[macro_use]
 extern crate error_chain;
+#[macro_use]
+extern crate serde_derive;
+extern crate serde_json;
 
 // Import symbols from std:: (standard library)
-use std::io::Write;
+use std::io::{self, Read, Write};
 use std::str;
 use std::str::FromStr;
 use std::fs::File;
+use std::time::{Duration, Instant};
 
 // Just need `App` from clap
 use clap::App;
 
 // Get `Revlog` from the mercurial revlog module
-use mercurial::revlog::Revlog;
+use mercurial::changeset::RevlogChangeset;
+use mercurial::manifest::revlog as manifest;
+use mercurial::revlog::{RevIdx, Revlog};
+use mercurial_types::{BlobNode, Changeset, NodeHash};
 
 mod errors {
     use mercurial;
 
     error_chain! {
+        errors {
+            Load(path: String) {
+                description("failed to load revlog")
+                display("failed to load idx and data from {}", path)
+            }
+            Read(msg: String) {
+                description("failed to read revlog")
+                display("{}", msg)
+            }
+            Write(path: String) {
+                description("failed to write output")
+                display("failed to write {}", path)
+            }
+            HashMismatch(idx: ::mercurial::revlog::RevIdx, msg: String) {
+                description("revision doesn't match its nodeid")
+                display("rev {}: {}", idx, msg)
+            }
+        }
+
         links {
             Mercurial(mercurial::Error, mercurial::ErrorKind);
         }
@@ -34,70 +61,350 @@ mod errors {
 
 use errors::*;
 
+// Exit statuses, as listed in the help text.
+const EXIT_FAILED: i32 = 1;
+const EXIT_NO_SUCH_REV: i32 = 2;
+const EXIT_HASH_MISMATCH: i32 = 3;
+const EXIT_IO: i32 = 4;
+
+static EXIT_STATUSES: &'static str = "EXIT STATUS:
+    0    success
+    1    any other failure, such as invalid arguments
+    2    the revision or nodeid doesn't exist
+    3    a revision doesn't match its nodeid (with --verify)
+    4    the revlog couldn't be read or parsed, or the output couldn't be written";
+
+fn exit_status(err: &Error) -> i32 {
+    use mercurial::ErrorKind as HgErrorKind;
+
+    match err.kind() {
+        &ErrorKind::HashMismatch(..) => EXIT_HASH_MISMATCH,
+        &ErrorKind::Load(_) | &ErrorKind::Read(_) | &ErrorKind::Write(_) => EXIT_IO,
+        &ErrorKind::Mercurial(ref kind) => match kind {
+            &HgErrorKind::NoSuchRevision(_) |
+            &HgErrorKind::NoSuchNode(_) |
+            &HgErrorKind::NoMatchingNode(_) |
+            &HgErrorKind::AmbiguousPrefix(..) => EXIT_NO_SUCH_REV,
+            &HgErrorKind::Io(_) |
+            &HgErrorKind::Revlog(_) |
+            &HgErrorKind::UnknownCompression(_) |
+            &HgErrorKind::Censored(_) |
+            &HgErrorKind::UnsupportedFlag(..) => EXIT_IO,
+            _ => EXIT_FAILED,
+        },
+        _ => EXIT_FAILED,
+    }
+}
+
+// Wrap an error reading the revlog, so that it's reported with `EXIT_IO`.
+fn read_error<S: Into<String>>(msg: S) -> ErrorKind {
+    ErrorKind::Read(msg.into())
+}
+
+// What to do with each selected revision
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Mode {
+    Dump,
+    Json,
+    Verify,
+    Chain,
+    Raw,
+    Diff,
+}
+
+// How to interpret the content of revisions when dumping them
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Decode {
+    Changelog,
+    Manifest,
+}
+
 fn run() -> Result<()> {
     // Define command line args and parse command line
     let matches = App::new("dumprev")
         .version("0.0.0")
         .about("extract a revision from a revlog")
+        .after_help(EXIT_STATUSES)
         .args_from_usage(concat!(
-            "-d, --data=[DATAFILE]  'Data file if not inline'\n",
+            "-d, --data=[DATAFILE]  'Data file if not inline, or - to read it from stdin; \
+                                     defaults to the index path with a .d extension'\n",
             "-w, --write=[DUMPFILE]  'Write data to file'\n",
-            "<IDXFILE>               'index file'\n",
-            "<REV>                   'revision index'"
+            "--json                  'Print entry metadata as JSON'\n",
+            "--verify                'Only check that the revision matches its nodeid'\n",
+            "--chain                 'Print the delta chain used to store the revision'\n",
+            "--list                  'List every entry in the index, like hg debugindex'\n",
+            "--raw                   'Dump the bytes stored for the revision, which may be a \
+                                      compressed delta rather than the content'\n",
+            "--diff=[FROM]           'Print the deltas which turn revision FROM into the \
+                                      revision'\n",
+            "--decode=[TYPE]         'Print the fields of each revision rather than its \
+                                      content, for TYPE changelog or manifest'\n",
+            "--full-hash             'Show full nodeids when listing'\n",
+            "--progress              'Report progress on stderr when processing a range or \
+                                      listing'\n",
+            "<IDXFILE>               'index file, or - to read it from stdin'\n",
+            "[REV]                   'revision index or nodeid (prefix), or range start..end'"
         ))
         .get_matches();
     // Get path of index file; `unwrap()` is safe because parameter is non-optional
     let idxpath = matches.value_of("IDXFILE").unwrap();
 
     // Get optional datapath
-    let datapath = matches.value_of("DATAFILE");
+    let datapath = matches.value_of("data");
 
     // Also optional dumpfile
     let dumpfile = matches.value_of("write");
 
-    // Get non-optional revision
-    let revidx = FromStr::from_str(matches.value_of("REV").unwrap())
-        .chain_err(|| "idx malformed")?;
+    let mode = if matches.is_present("verify") {
+        Mode::Verify
+    } else if matches.is_present("chain") {
+        Mode::Chain
+    } else if matches.is_present("raw") {
+        Mode::Raw
+    } else if matches.is_present("diff") {
+        Mode::Diff
+    } else if matches.is_present("json") {
+        Mode::Json
+    } else {
+        Mode::Dump
+    };
+
+    let decode = match matches.value_of("decode") {
+        None => None,
+        Some("changelog") => Some(Decode::Changelog),
+        Some("manifest") => Some(Decode::Manifest),
+        Some(other) => bail!("can't decode {}, only changelog or manifest", other),
+    };
 
     // Construct a `Revlog`
-    let revlog = Revlog::from_idx_data(idxpath, datapath)
-        .chain_err(|| "failed to load idx and data")?;
-    println!("made revlog {:?}", revlog.get_header());
+    let revlog = load_revlog(idxpath, datapath)?;
+
+    let progress = matches.is_present("progress");
+
+    if matches.is_present("list") {
+        return list_revs(&revlog, matches.is_present("full-hash"), progress);
+    }
+
+    if mode == Mode::Dump {
+        println!("made revlog {:?}", revlog.get_header());
+    }
+
+    let diff_from = match matches.value_of("diff") {
+        Some(rev) => Some(parse_existing_rev(&revlog, rev)?),
+        None => None,
+    };
+
+    let dump = |revidx: RevIdx, dumpfile: Option<&str>| match mode {
+        Mode::Dump => dump_rev(&revlog, revidx, dumpfile, decode),
+        Mode::Json => dump_json(&revlog, revidx, dumpfile),
+        Mode::Verify => verify_rev(&revlog, revidx),
+        Mode::Chain => dump_chain(&revlog, revidx),
+        Mode::Raw => dump_raw(&revlog, revidx, dumpfile),
+        Mode::Diff => dump_diff(&revlog, diff_from.expect("no FROM"), revidx),
+    };
+
+    // Get revision or range of revisions, which is required unless listing
+    let revspec = match matches.value_of("REV") {
+        Some(revspec) => revspec,
+        None => bail!("REV is required"),
+    };
+    let range = match revspec.find("..") {
+        None => None,
+        Some(pos) => Some((
+            parse_rev(&revlog, &revspec[..pos])?,
+            parse_rev(&revlog, &revspec[pos + 2..])?,
+        )),
+    };
+
+    match range {
+        None => dump(parse_existing_rev(&revlog, revspec)?, dumpfile),
+        Some((start, end)) => {
+            let len = revlog.entries().count();
+            if end > RevIdx::from(len) {
+                // The last revision in the range doesn't exist.
+                let msg = format!(
+                    "range end {} is past the end of the revlog ({} revisions)",
+                    end,
+                    len
+                );
+                let missing = mercurial::ErrorKind::NoSuchRevision(end.pred());
+                return Err(Error::with_chain(Error::from(msg), ErrorKind::Mercurial(missing)));
+            }
+
+            let mut progress = if progress {
+                let total = u32::from(end).saturating_sub(u32::from(start));
+                Some(Progress::new(total as usize))
+            } else {
+                None
+            };
+
+            // Write each revision to its own file
+            for revidx in start.range_to(end) {
+                let dumpfile = dumpfile.map(|dumpfile| format!("{}.{}", dumpfile, revidx));
+                dump(revidx, dumpfile.as_ref().map(String::as_str))?;
+                if let Some(ref mut progress) = progress {
+                    progress.tick();
+                }
+            }
+            Ok(())
+        }
+    }
+}
+
+// How often `Progress` reports
+const PROGRESS_INTERVAL_SECS: u64 = 1;
+
+// Reports how many revisions have been processed on stderr, at most once per interval and
+// when the last one is done, so it doesn't mix with output on stdout.
+struct Progress {
+    total: usize,
+    done: usize,
+    start: Instant,
+    last: Instant,
+}
+
+impl Progress {
+    fn new(total: usize) -> Self {
+        let now = Instant::now();
+        Progress {
+            total: total,
+            done: 0,
+            start: now,
+            last: now,
+        }
+    }
+
+    // Record that another revision has been processed
+    fn tick(&mut self) {
+        self.done += 1;
+
+        let now = Instant::now();
+        if self.done < self.total &&
+            now.duration_since(self.last) < Duration::from_secs(PROGRESS_INTERVAL_SECS)
+        {
+            return;
+        }
+        self.last = now;
+
+        let elapsed = now.duration_since(self.start);
+        let secs = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1e9;
+        let rate = if secs > 0.0 {
+            self.done as f64 / secs
+        } else {
+            0.0
+        };
+        let _ = writeln!(
+            io::stderr(),
+            "{}/{} revisions ({:.0} revs/s)",
+            self.done,
+            self.total,
+            rate
+        );
+    }
+}
+
+// Path which means stdin rather than a file
+const STDIN: &'static str = "-";
+
+// Open the revlog, reading the index or the data from stdin if its path is `STDIN`. Since
+// stdin can't be mapped, that file is read into memory, and so is the other one.
+fn load_revlog(idxpath: &str, datapath: Option<&str>) -> Result<Revlog> {
+    let load_error = || {
+        let path = if idxpath == STDIN { "stdin" } else { idxpath };
+        ErrorKind::Load(path.to_string())
+    };
+
+    match (idxpath == STDIN, datapath) {
+        (true, Some(STDIN)) => bail!("only one of IDXFILE and --data can be read from stdin"),
+        (false, Some(STDIN)) => {
+            let idx = read_input(idxpath)?;
+            let data = read_input(STDIN)?;
+            Revlog::from_idx_data_bytes(idx, Some(data)).chain_err(load_error)
+        }
+        (true, datapath) => {
+            let idx = read_input(STDIN)?;
+            let data = match datapath {
+                Some(datapath) => Some(read_input(datapath)?),
+                None => None,
+            };
+            Revlog::from_idx_data_bytes(idx, data).chain_err(load_error)
+        }
+        (false, datapath) => Revlog::from_idx_data(idxpath, datapath).chain_err(load_error),
+    }
+}
+
+// Read the whole of a file, or of stdin if `path` is `STDIN`.
+fn read_input(path: &str) -> Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    if path == STDIN {
+        let stdin = io::stdin();
+        let mut stdin = stdin.lock();
+        stdin
+            .read_to_end(&mut buf)
+            .chain_err(|| ErrorKind::Load("stdin".into()))?;
+    } else {
+        File::open(path)
+            .and_then(|mut file| file.read_to_end(&mut buf))
+            .chain_err(|| ErrorKind::Load(path.to_string()))?;
+    }
+    Ok(buf)
+}
+
+// Like `parse_rev`, but fail with `NoSuchRevision` unless the revision is in the revlog.
+fn parse_existing_rev(revlog: &Revlog, rev: &str) -> Result<RevIdx> {
+    let revidx = parse_rev(revlog, rev)?;
+    revlog.get_entry(revidx)?;
+    Ok(revidx)
+}
+
+// Parse a revision, which is either a decimal index or a full or abbreviated nodeid. As with hg,
+// anything which looks like an index is taken to be one.
+fn parse_rev(revlog: &Revlog, rev: &str) -> Result<RevIdx> {
+    if rev.len() < 40 && rev.chars().all(|c| c.is_digit(10)) {
+        return FromStr::from_str(rev).chain_err(|| "idx malformed");
+    }
+    let prefix = NodeHash::from_prefix(rev)
+        .chain_err(|| format!("{} is neither a revision index nor a nodeid", rev))?;
+    let nodeid = revlog.resolve_prefix(&prefix)?;
+
+    Ok(revlog.get_idx_by_nodeid(&nodeid)?)
+}
 
+fn dump_rev(
+    revlog: &Revlog,
+    revidx: RevIdx,
+    dumpfile: Option<&str>,
+    decode: Option<Decode>,
+) -> Result<()> {
     let entry = revlog
         .get_entry(revidx)
-        .chain_err(|| "failed to get entry")?;
+        .chain_err(|| read_error("failed to get entry"))?;
 
     println!("Revlog[{:?}] = {:?}", revidx, entry);
     match revlog.get_rev(revidx) {
         Ok(ref rev) if rev.nodeid().is_some() => {
-            if entry.nodeid() != &rev.nodeid().unwrap() {
-                println!(
-                    "NOTE: hash mismatch: expected {}, got {}",
-                    entry.nodeid(),
-                    rev.nodeid().unwrap()
-                )
+            if let Some(mismatch) = hash_mismatch(entry.nodeid(), rev) {
+                println!("NOTE: {}", mismatch)
             }
             if let Some(revdata) = rev.as_blob().as_slice() {
                 if let Some(dumpfile) = dumpfile {
-                    let mut file = match File::create(dumpfile) {
-                        Ok(file) => file,
-                        Err(err) => bail!("Failed to create file {}: {:?}", dumpfile, err),
-                    };
                     println!(
                         "Writing rev {:?} to {}",
                         rev.nodeid().expect("no id"),
                         dumpfile
                     );
-                    if let Err(err) = file.write_all(revdata) {
-                        bail!("Failed to write {}: {:?}", dumpfile, err);
-                    }
+                    write_file(dumpfile, revdata)?;
                 } else {
-                    println!(
-                        "rev {:?}:\n{}",
-                        rev.nodeid().expect("no id"),
-                        String::from_utf8_lossy(revdata)
-                    );
+                    println!("rev {:?}:", rev.nodeid().expect("no id"));
+                    match decode.map(|decode| decode_rev(decode, rev)) {
+                        Some(Ok(fields)) => println!("{}", fields),
+                        Some(Err(err)) => {
+                            // Show what's there instead
+                            println!("NOTE: failed to decode: {}", err);
+                            println!("{}", String::from_utf8_lossy(revdata));
+                        }
+                        None => println!("{}", String::from_utf8_lossy(revdata)),
+                    }
                 }
             } else {
                 println!("Dataless rev {:?}", rev.nodeid().expect("no id"));
@@ -110,12 +417,254 @@ fn run() -> Result<()> {
                 entry.nodeid()
             )
         }
-        Err(err) => bail!("failed to get chunk {:?}: {}", revidx, err),
+        Err(mercurial::Error(mercurial::ErrorKind::Censored(_), _)) => {
+            println!("Revlog[{:?}] is censored", revidx)
+        }
+        Err(err) => {
+            return Err(Error::with_chain(
+                err,
+                read_error(format!("failed to get chunk {:?}", revidx)),
+            ))
+        }
+    };
+
+    Ok(())
+}
+
+// Format the fields of a changelog or manifest revision, one per line
+fn decode_rev(decode: Decode, rev: &BlobNode) -> Result<String> {
+    let mut lines = Vec::new();
+    match decode {
+        Decode::Changelog => {
+            let cs = RevlogChangeset::new(rev.clone())?;
+            lines.push(format!("manifest: {}", cs.manifestid()));
+            lines.push(format!("user: {}", String::from_utf8_lossy(cs.user())));
+            lines.push(format!("date: {} {}", cs.time().time, cs.time().tz));
+            for (key, value) in cs.extra() {
+                lines.push(format!(
+                    "extra: {}={}",
+                    String::from_utf8_lossy(key),
+                    String::from_utf8_lossy(value)
+                ));
+            }
+            for file in cs.files() {
+                lines.push(format!("file: {}", file));
+            }
+            lines.push(format!(
+                "description:\n{}",
+                String::from_utf8_lossy(cs.comments())
+            ));
+        }
+        Decode::Manifest => {
+            let data = rev.as_blob().as_slice().unwrap_or(&[]);
+            for (path, details) in manifest::parse(data)? {
+                lines.push(format!("{} {}{}", path, details.nodeid(), details.flag()));
+            }
+        }
+    }
+    Ok(lines.join("\n"))
+}
+
+// Describe the mismatch if a revision's content and parents don't hash to its nodeid.
+fn hash_mismatch(expected: &NodeHash, rev: &BlobNode) -> Option<String> {
+    match rev.nodeid() {
+        Some(ref actual) if actual == expected => None,
+        Some(actual) => Some(format!(
+            "hash mismatch: expected {}, got {}",
+            expected,
+            actual
+        )),
+        None => Some(format!("hash mismatch: expected {}, got no data", expected)),
+    }
+}
+
+fn verify_rev(revlog: &Revlog, revidx: RevIdx) -> Result<()> {
+    let entry = revlog
+        .get_entry(revidx)
+        .chain_err(|| read_error("failed to get entry"))?;
+    let rev = revlog
+        .get_rev(revidx)
+        .chain_err(|| read_error(format!("failed to get rev {}", revidx)))?;
+
+    match hash_mismatch(entry.nodeid(), &rev) {
+        None => Ok(()),
+        Some(mismatch) => bail!(ErrorKind::HashMismatch(revidx, mismatch)),
+    }
+}
+
+// Print one line per revision in the delta chain, from the revision to its snapshot
+fn dump_chain(revlog: &Revlog, revidx: RevIdx) -> Result<()> {
+    let chain = revlog
+        .delta_chain(revidx)
+        .chain_err(|| read_error(format!("failed to get delta chain for {}", revidx)))?;
+
+    println!("rev {}: chain length {}", revidx, chain.len());
+    for (link, base) in chain.iter().zip(chain.iter().skip(1).map(Some).chain(Some(None))) {
+        let entry = revlog
+            .get_entry(*link)
+            .chain_err(|| read_error("failed to get entry"))?;
+        match base {
+            Some(base) => println!(
+                "  {}: delta against {} ({} bytes)",
+                link,
+                base,
+                entry.compressed_len
+            ),
+            None => println!("  {}: snapshot ({} bytes)", link, entry.compressed_len),
+        }
+    }
+
+    Ok(())
+}
+
+// Dump the stored chunk for a revision without resolving deltas or checking its hash. This is
+// not the content of the revision unless it happens to be stored uncompressed and in full.
+fn dump_raw(revlog: &Revlog, revidx: RevIdx, dumpfile: Option<&str>) -> Result<()> {
+    let chunk = revlog
+        .get_raw_chunk(revidx)
+        .chain_err(|| read_error(format!("failed to get chunk for {}", revidx)))?;
+
+    match dumpfile {
+        Some(dumpfile) => write_file(dumpfile, &chunk),
+        None => {
+            let stdout = io::stdout();
+            let mut stdout = stdout.lock();
+            stdout
+                .write_all(&chunk)
+                .chain_err(|| ErrorKind::Write("stdout".into()))
+        }
+    }
+}
+
+// Print each delta turning one revision into another as its byte range in the old revision,
+// followed by the replacement text
+fn dump_diff(revlog: &Revlog, from: RevIdx, to: RevIdx) -> Result<()> {
+    let deltas = revlog
+        .delta(from, to)
+        .chain_err(|| read_error(format!("failed to diff {} and {}", from, to)))?;
+
+    println!("rev {} -> {}: {} deltas", from, to, deltas.len());
+    for delta in deltas {
+        println!(
+            "@@ {}..{} +{} @@\n{}",
+            delta.start,
+            delta.end,
+            delta.content.len(),
+            String::from_utf8_lossy(&delta.content)
+        );
+    }
+
+    Ok(())
+}
+
+// Print a table of all the entries in the revlog
+fn list_revs(revlog: &Revlog, full_hash: bool, progress: bool) -> Result<()> {
+    let width = if full_hash { 40 } else { 12 };
+    let short = |nodeid: Option<NodeHash>| {
+        let hex = nodeid.unwrap_or(NodeHash::null()).to_hex();
+        hex.as_str()[..width].to_string()
+    };
+
+    println!(
+        "{:>6} {:>10} {:>8} {:>6} {:>7} {:<w$} {:<w$} {:<w$}",
+        "rev",
+        "offset",
+        "length",
+        "base",
+        "linkrev",
+        "p1",
+        "p2",
+        "nodeid",
+        w = width
+    );
+    let mut progress = if progress {
+        Some(Progress::new(revlog.entries().count()))
+    } else {
+        None
     };
+    for (idx, entry) in revlog.entries().enumerate() {
+        let revidx = RevIdx::from(idx);
+        let entry = entry.chain_err(|| read_error(format!("failed to get entry {}", revidx)))?;
+        let (p1, p2) = revlog
+            .parents(revidx)
+            .chain_err(|| read_error("failed to get parents"))?;
+
+        println!(
+            "{:>6} {:>10} {:>8} {:>6} {:>7} {:<w$} {:<w$} {:<w$}",
+            revidx,
+            entry.offset,
+            entry.compressed_len,
+            entry.baserev.unwrap_or(revidx),
+            entry.linkrev().map_or("-1".to_string(), |linkrev| linkrev.to_string()),
+            short(p1),
+            short(p2),
+            short(Some(*entry.nodeid())),
+            w = width
+        );
+        if let Some(ref mut progress) = progress {
+            progress.tick();
+        }
+    }
 
     Ok(())
 }
 
+// Entry metadata printed by `--json`
+#[derive(Serialize)]
+struct JsonEntry {
+    rev: u32,
+    nodeid: NodeHash,
+    p1: Option<NodeHash>,
+    p2: Option<NodeHash>,
+    linkrev: Option<u32>,
+    len: Option<u32>,
+    compressed_len: u32,
+    flags: u16,
+    // Where the content was written, with `--write`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    path: Option<String>,
+}
+
+fn dump_json(revlog: &Revlog, revidx: RevIdx, dumpfile: Option<&str>) -> Result<()> {
+    let entry = revlog
+        .get_entry(revidx)
+        .chain_err(|| read_error("failed to get entry"))?;
+    let (p1, p2) = revlog
+        .parents(revidx)
+        .chain_err(|| read_error("failed to get parents"))?;
+
+    if let Some(dumpfile) = dumpfile {
+        let rev = revlog
+            .get_rev(revidx)
+            .chain_err(|| read_error(format!("failed to get rev {}", revidx)))?;
+        if let Some(revdata) = rev.as_blob().as_slice() {
+            write_file(dumpfile, revdata)?;
+        }
+    }
+
+    let json = JsonEntry {
+        rev: revidx.into(),
+        nodeid: *entry.nodeid(),
+        p1: p1,
+        p2: p2,
+        linkrev: entry.linkrev().map(Into::into),
+        len: entry.len,
+        compressed_len: entry.compressed_len,
+        flags: entry.flags.bits(),
+        path: dumpfile.map(String::from),
+    };
+    let json = serde_json::to_string(&json).chain_err(|| "failed to serialize entry")?;
+    println!("{}", json);
+
+    Ok(())
+}
+
+fn write_file(dumpfile: &str, data: &[u8]) -> Result<()> {
+    File::create(dumpfile)
+        .and_then(|mut file| file.write_all(data))
+        .chain_err(|| ErrorKind::Write(dumpfile.to_string()))
+}
+
 fn main() {
     if let Err(ref e) = run() {
         println!("Failed: {}", e);
@@ -124,6 +673,6 @@ fn main() {
             println!("caused by: {}", e);
         }
 
-        std::process::exit(1);
+        std::process::exit(exit_status(e));
     }
 }
@@ -8,40 +8,105 @@
 
 extern crate heads;
 
+extern crate bincode;
 #[macro_use]
 extern crate error_chain;
 extern crate futures;
 extern crate futures_cpupool;
+extern crate nix;
+extern crate notify;
 extern crate serde;
 #[macro_use]
 extern crate serde_derive;
 extern crate serde_urlencoded;
 #[cfg(test)]
-extern crate tempdir;
+extern crate memheads;
 #[cfg(test)]
+extern crate tempdir;
 extern crate mercurial_types;
 
-use std::fs::{self, File};
-use std::io;
+use std::collections::HashSet;
+use std::fs::{self, DirEntry, File};
+use std::io::{self, Read, Write};
+use std::iter;
 use std::marker::PhantomData;
-use std::path::{Path, PathBuf};
-use std::sync::Arc;
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{self, Path, PathBuf};
+use std::result;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{ATOMIC_USIZE_INIT, AtomicUsize, Ordering};
+use std::sync::mpsc as std_mpsc;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use futures::Async;
+use bincode::{Infinite, deserialize, serialize};
+use futures::{Async, Sink};
 use futures::future::{BoxFuture, Future, IntoFuture, poll_fn};
 use futures::stream::{self, BoxStream, Stream};
+use futures::sync::mpsc;
 use futures_cpupool::CpuPool;
+use nix::fcntl::{self, FlockArg};
+use notify::{RawEvent, RecursiveMode, Watcher, op, raw_watcher};
 use serde::Serialize;
 use serde::de::DeserializeOwned;
 use serde_urlencoded::{from_str, to_string};
 
-use heads::Heads;
+use heads::{EvictionPolicy, HeadOp, Heads};
+use mercurial_types::NodeHash;
+use mercurial_types::hash::Sha1;
+
+mod limit;
+mod namespace;
+
+use limit::Spawner;
+pub use heads::HeadEvent;
+pub use namespace::{Namespaced, NamespacedHeads};
 
 mod errors {
     error_chain!{
+        errors {
+            InvalidKey(key: String) {
+                description("invalid head key")
+                display("head key '{}' is not a valid file name", key)
+            }
+            InvalidPrefix(prefix: String) {
+                description("invalid head file prefix")
+                display("'{}' is not a valid head file prefix", prefix)
+            }
+            NotADirectory(path: ::std::path::PathBuf) {
+                description("not a directory")
+                display("'{}' is not a directory", path.display())
+            }
+            CaseInsensitive(path: ::std::path::PathBuf) {
+                description("directory is on a case-insensitive filesystem")
+                display("'{}' is on a case-insensitive filesystem", path.display())
+            }
+            InvalidHexKey(name: String) {
+                description("invalid hex head key")
+                display("'{}' is not a 40 digit lowercase hex hash", name)
+            }
+            HeadExists(path: ::std::path::PathBuf) {
+                description("head already exists")
+                display("head '{}' already exists", path.display())
+            }
+            InvalidNamespace(ns: String) {
+                description("invalid head namespace")
+                display("'{}' is not a valid head namespace", ns)
+            }
+        }
+
+        links {
+            Heads(::heads::Error, ::heads::ErrorKind);
+        }
+
         foreign_links {
+            Bincode(::bincode::Error);
             De(::serde::de::value::Error);
             Io(::std::io::Error);
+            Nix(::nix::Error);
+            Notify(::notify::Error);
             Ser(::serde_urlencoded::ser::Error);
         }
     }
@@ -50,6 +115,9 @@ pub use errors::*;
 
 static PREFIX: &'static str = "head:";
 
+// Name of the file locked by `FileHeads::lock`. It starts with '.', so it can't be a head file.
+static LOCK_FILE: &'static str = ".lock";
+
 /// Wrapper struct to work around the fact that serde_urlencoded can only operate on
 /// non-tuple structs and maps.
 #[derive(Debug, Deserialize, Serialize)]
@@ -63,32 +131,220 @@ impl<K> UrlEncodeWrapper<K> {
     }
 }
 
+/// Encode a key in the form used for head file names (excluding the prefix). Other head
+/// stores can use this to stay compatible with `FileHeads`.
+pub fn encode_key<T: Serialize>(key: &T) -> Result<String> {
+    Ok(to_string(UrlEncodeWrapper::new(key))?)
+}
+
+/// Decode a key produced by `encode_key`.
+pub fn decode_key<T: DeserializeOwned>(encoded: &str) -> Result<T> {
+    Ok(from_str::<UrlEncodeWrapper<T>>(encoded)?.key)
+}
+
+/// Converts keys to and from the strings used in head file names (excluding the prefix).
+pub trait HeadKeyCodec<T>: Send + Sync + 'static {
+    fn encode(key: &T) -> Result<String>;
+    fn decode(encoded: &str) -> Result<T>;
+}
+
+/// The default codec, which urlencodes keys with `encode_key`. Any serializable key can be
+/// stored, but names are of the form `key=...`.
+pub struct UrlEncodeCodec;
+
+impl<T: Serialize + DeserializeOwned> HeadKeyCodec<T> for UrlEncodeCodec {
+    fn encode(key: &T) -> Result<String> {
+        encode_key(key)
+    }
+
+    fn decode(encoded: &str) -> Result<T> {
+        decode_key(encoded)
+    }
+}
+
+/// A codec for `NodeHash` keys which uses the plain 40 digit lowercase hex form of the hash.
+pub struct HexCodec;
+
+impl HeadKeyCodec<NodeHash> for HexCodec {
+    fn encode(key: &NodeHash) -> Result<String> {
+        Ok(key.to_hex().as_str().to_string())
+    }
+
+    fn decode(encoded: &str) -> Result<NodeHash> {
+        // Only accept what `encode` produces, so each head has a single file name.
+        let canonical = encoded.len() == 40 &&
+            encoded.chars().all(|c| c.is_digit(16) && !c.is_uppercase());
+        match NodeHash::from_str(encoded) {
+            Ok(hash) if canonical => Ok(hash),
+            _ => bail!(ErrorKind::InvalidHexKey(encoded.to_string())),
+        }
+    }
+}
+
+/// How hard `FileHeads` tries to make sure an added head survives a crash.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Durability {
+    /// Just create the file. A crash shortly afterwards may lose the head even though the
+    /// add completed successfully.
+    None,
+    /// Fsync the new file and its directory before completing the add.
+    Fsync,
+}
+
+impl Default for Durability {
+    fn default() -> Self {
+        Durability::None
+    }
+}
+
+/// A head file whose name couldn't be decoded, reported by `FileHeads::heads_results`.
+#[derive(Debug)]
+pub struct DecodeError {
+    /// Name of the file
+    pub name: String,
+    /// Why it couldn't be decoded
+    pub error: Error,
+}
+
+/// What `heads()` does with an entry whose name can't be decoded, as decided by a policy set
+/// with `FileHeads::with_decode_policy`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SkipOrAbort {
+    /// Leave the entry out of the listing.
+    Skip,
+    /// Report the error in the listing, as happens when no policy is set.
+    Abort,
+}
+
+type DecodePolicy = Box<FnMut(&Path, &Error) -> SkipOrAbort + Send>;
+
 /// A basic file-based persistent head store.
 ///
-/// Stores heads as empty files in the specified directory. File operations are dispatched to
+/// Stores heads as files in the specified directory. The files are empty unless a value was
+/// stored with the head by `add_with_value`. File operations are dispatched to
 /// a thread pool to avoid blocking the main thread with IO. For simplicity, file accesses
-/// are unsynchronized since each operation performs just a single File IO syscall.
-pub struct FileHeads<T> {
+/// are unsynchronized since each operation changes a head with a single atomic file system
+/// call.
+///
+/// A sharded store places each head file two subdirectory levels down, named after the
+/// leading bytes of a hash of the encoded key (e.g. `ab/cd/head:...`). This keeps directories
+/// small for stores with very many heads.
+///
+/// Head file names start with a prefix (`head:` by default); other files in the directory are
+/// ignored. A prefix ends with its only `:`, so a file name's prefix is everything up to its
+/// first `:`, and stores with different prefixes can share a directory without seeing each
+/// other's heads. The rest of the name is the key, encoded by the codec `C`.
+///
+/// Head files are written under a temporary name starting with `.` and then renamed into
+/// place, so they never appear partially written.
+pub struct FileHeads<T, C = UrlEncodeCodec> {
     base: PathBuf,
-    pool: Arc<CpuPool>,
-    _marker: PhantomData<T>,
+    prefix: String,
+    pool: Spawner,
+    sharded: bool,
+    durability: Durability,
+    decode_policy: Option<Arc<Mutex<DecodePolicy>>>,
+    _marker: PhantomData<(T, C)>,
+}
+
+// Maximum number of adds in flight at once in `add_stream`.
+const ADD_STREAM_CONCURRENCY: usize = 16;
+
+// Implemented by hand since deriving would require `T: Clone`.
+impl<T, C> Clone for FileHeads<T, C> {
+    fn clone(&self) -> Self {
+        FileHeads {
+            base: self.base.clone(),
+            prefix: self.prefix.clone(),
+            pool: self.pool.clone(),
+            sharded: self.sharded,
+            durability: self.durability,
+            decode_policy: self.decode_policy.clone(),
+            _marker: PhantomData,
+        }
+    }
 }
 
-impl<T: Serialize> FileHeads<T> {
+impl<T> FileHeads<T> {
     pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
         Self::open_with_pool(path, Arc::new(CpuPool::new_num_cpus()))
     }
 
     pub fn open_with_pool<P: AsRef<Path>>(path: P, pool: Arc<CpuPool>) -> Result<Self> {
-        let path = path.as_ref();
+        Self::open_inner(path.as_ref(), pool, false)
+    }
+
+    /// Like `open_with_pool`, but run at most `limit` of this store's IO tasks at once. Any
+    /// more wait for a running one to finish before being spawned on the pool. Clones of the
+    /// store share the limit. Panics if `limit` is 0.
+    pub fn open_with_pool_and_limit<P: AsRef<Path>>(
+        path: P,
+        pool: Arc<CpuPool>,
+        limit: usize,
+    ) -> Result<Self> {
+        let mut heads = Self::open_with_pool(path, pool.clone())?;
+        heads.pool = Spawner::new(pool, Some(limit));
+        Ok(heads)
+    }
+
+    pub fn open_with_prefix<P: AsRef<Path>>(path: P, prefix: &str) -> Result<Self> {
+        Self::open(path)?.set_prefix(prefix)
+    }
+
+    pub fn open_sharded<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::open_sharded_with_pool(path, Arc::new(CpuPool::new_num_cpus()))
+    }
+
+    pub fn open_sharded_with_pool<P: AsRef<Path>>(path: P, pool: Arc<CpuPool>) -> Result<Self> {
+        Self::open_inner(path.as_ref(), pool, true)
+    }
+
+    /// Like `open`, but fail if the directory is on a case-insensitive filesystem, where keys
+    /// whose encodings differ only in case would be stored in the same file. This is only a
+    /// concern for key types whose encodings can contain upper case letters.
+    ///
+    /// The check creates and removes a couple of temporary files in the directory, so it isn't
+    /// done by default.
+    pub fn open_case_sensitive<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::open(path)?.check_case_sensitive()
+    }
+
+    fn check_case_sensitive(self) -> Result<Self> {
+        if !is_case_sensitive(&self.base)? {
+            bail!(ErrorKind::CaseInsensitive(self.base.clone()));
+        }
+        Ok(self)
+    }
+
+    fn set_prefix(mut self, prefix: &str) -> Result<Self> {
+        // Names starting with '.' are used for temporary files. Requiring a single ':' at the
+        // end means no prefix can start with another, so no store sees another's heads.
+        if !prefix.ends_with(':') || prefix.find(':') != Some(prefix.len() - 1) ||
+            prefix.starts_with('.') || prefix.contains('/') ||
+            prefix.contains(path::MAIN_SEPARATOR)
+        {
+            bail!(ErrorKind::InvalidPrefix(prefix.to_string()));
+        }
+        self.prefix = prefix.to_string();
+        Ok(self)
+    }
 
+    fn open_inner(path: &Path, pool: Arc<CpuPool>, sharded: bool) -> Result<Self> {
         if !path.is_dir() {
-            bail!("'{}' is not a directory", path.to_string_lossy());
+            bail!(ErrorKind::NotADirectory(path.to_path_buf()));
         }
+        // Resolve symlinks and redundant components, so that stores opened through different
+        // paths to the same directory behave the same.
+        let base = fs::canonicalize(path)
+            .chain_err(|| format!("failed to resolve head directory '{}'", path.display()))?;
 
         Ok(FileHeads {
-            base: path.to_path_buf(),
-            pool: pool,
+            base: base,
+            prefix: PREFIX.to_string(),
+            pool: Spawner::new(pool, None),
+            sharded: sharded,
+            durability: Durability::default(),
+            decode_policy: None,
             _marker: PhantomData,
         })
     }
@@ -103,30 +359,130 @@ impl<T: Serialize> FileHeads<T> {
         Self::open_with_pool(path, pool)
     }
 
-    fn get_path(&self, key: &T) -> Result<PathBuf> {
-        let key_string = to_string(UrlEncodeWrapper::new(key))?;
-        Ok(self.base.join(format!("{}{}", PREFIX, key_string)))
+    pub fn create_with_prefix<P: AsRef<Path>>(path: P, prefix: &str) -> Result<Self> {
+        Self::create(path)?.set_prefix(prefix)
+    }
+
+    pub fn create_case_sensitive<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::create(path)?.check_case_sensitive()
+    }
+
+    pub fn create_sharded<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::create_sharded_with_pool(path, Arc::new(CpuPool::new_num_cpus()))
+    }
+
+    pub fn create_sharded_with_pool<P: AsRef<Path>>(path: P, pool: Arc<CpuPool>) -> Result<Self> {
+        let path = path.as_ref();
+        fs::create_dir_all(path)?;
+        Self::open_sharded_with_pool(path, pool)
+    }
+
+    /// Use the codec `C` to encode keys in file names, rather than urlencoding them. The
+    /// directory must not already contain heads encoded differently.
+    pub fn with_codec<C: HeadKeyCodec<T>>(self) -> FileHeads<T, C> {
+        FileHeads {
+            base: self.base,
+            prefix: self.prefix,
+            pool: self.pool,
+            sharded: self.sharded,
+            durability: self.durability,
+            decode_policy: self.decode_policy,
+            _marker: PhantomData,
+        }
     }
 }
 
-impl<T> Heads for FileHeads<T>
-where
-    T: Serialize + DeserializeOwned + Send + 'static,
-{
-    type Key = T;
-    type Error = Error;
+impl<T, C: HeadKeyCodec<T>> FileHeads<T, C> {
+    /// Set how durable added heads should be. The default is `Durability::None`.
+    ///
+    /// `Durability::Fsync` makes adds considerably slower, since every add waits for two
+    /// fsyncs (the file and its directory) to reach stable storage.
+    pub fn with_durability(mut self, durability: Durability) -> Self {
+        self.durability = durability;
+        self
+    }
 
-    type Unit = BoxFuture<(), Self::Error>;
-    type Bool = BoxFuture<bool, Self::Error>;
-    type Heads = BoxStream<Self::Key, Self::Error>;
+    /// Call `policy` with the path of each entry that `heads()` finds whose name can't be
+    /// decoded, and skip the entry or report the error as it decides. The policy can also
+    /// log or count bad entries, or move them out of the way. By default every such entry is
+    /// reported as an error.
+    ///
+    /// The policy is shared by clones of the store, and is called by one listing at a time.
+    pub fn with_decode_policy<F>(mut self, policy: F) -> Self
+    where
+        F: FnMut(&Path, &Error) -> SkipOrAbort + Send + 'static,
+    {
+        self.decode_policy = Some(Arc::new(Mutex::new(Box::new(policy))));
+        self
+    }
 
-    fn add(&self, key: &Self::Key) -> Self::Unit {
+    /// Add a head, resolving to `true` if it was newly created and `false` if it was already a
+    /// head. Use `add` when the difference doesn't matter. Race-free; see `get_or_add`.
+    pub fn add_checked(&self, key: &T) -> BoxFuture<bool, Error> {
         let pool = self.pool.clone();
-        self.get_path(&key)
+        let sharded = self.sharded;
+        let durability = self.durability;
+        self.get_path(key)
+            .into_future()
+            .and_then(move |path| {
+                let future =
+                    poll_fn(move || Ok(Async::Ready(create_new_head(&path, sharded, durability)?)));
+                pool.spawn(future)
+            })
+            .boxed()
+    }
+
+    /// Make sure `key` is a head, resolving to `true` if this call added it and `false` if it
+    /// was already a head, for publishing a head idempotently while learning whether this
+    /// writer was the first. This is the same as `add_checked`.
+    ///
+    /// The check and the add are a single exclusive file creation (`create_new`), so when
+    /// several writers (in this process or others) add the same head concurrently, exactly one
+    /// of them sees `true`. Calling `is_head` and then `add` can't guarantee that.
+    pub fn get_or_add(&self, key: &T) -> BoxFuture<bool, Error> {
+        self.add_checked(key)
+    }
+
+    /// Remove a head, resolving to `true` if it was removed and `false` if it wasn't a head.
+    /// Other failures are still errors. Use `remove` when the difference doesn't matter.
+    pub fn remove_checked(&self, key: &T) -> BoxFuture<bool, Error> {
+        let pool = self.pool.clone();
+        self.get_path(key)
+            .into_future()
+            .and_then(move |path| {
+                let future = poll_fn(move || Ok(Async::Ready(remove_existing_head(&path)?)));
+                pool.spawn(future)
+            })
+            .boxed()
+    }
+
+    /// Resolve to when a head's file was last modified, or `None` if it isn't a head. Both `add`
+    /// and `add_with_value` rewrite the file, so this is when the head was last added. This is
+    /// specific to `FileHeads`, since other stores don't necessarily record it.
+    pub fn modified(&self, key: &T) -> BoxFuture<Option<SystemTime>, Error> {
+        let pool = self.pool.clone();
+        self.get_path(key)
             .into_future()
             .and_then(move |path| {
+                let future = poll_fn(move || Ok(Async::Ready(head_mtime(&path)?)));
+                pool.spawn(future)
+            })
+            .boxed()
+    }
+
+    /// Add a head with an associated value, which is serialized into the head file. If `key`
+    /// is already a head, its value is replaced atomically. Adding a head with `add` clears its
+    /// value.
+    pub fn add_with_value<V: Serialize>(&self, key: &T, value: &V) -> BoxFuture<(), Error> {
+        let pool = self.pool.clone();
+        let sharded = self.sharded;
+        let durability = self.durability;
+        self.get_path(key)
+            .and_then(|path| Ok((path, serialize(value, Infinite)?)))
+            .into_future()
+            .and_then(move |(path, contents)| {
                 let future = poll_fn(move || {
-                    File::create(&path)?;
+                    write_head(&path, &contents, sharded, durability)?;
                     Ok(Async::Ready(()))
                 });
                 pool.spawn(future)
@@ -134,19 +490,46 @@ where
             .boxed()
     }
 
-    fn remove(&self, key: &Self::Key) -> Self::Unit {
+    /// Resolve to the value stored with a head by `add_with_value`. This is `None` both when
+    /// `key` isn't a head and when it was added without a value; use `is_head` to tell them
+    /// apart.
+    pub fn get_value<V>(&self, key: &T) -> BoxFuture<Option<V>, Error>
+    where
+        V: DeserializeOwned + Send + 'static,
+    {
         let pool = self.pool.clone();
-        self.get_path(&key)
+        self.get_path(key)
             .into_future()
             .and_then(move |path| {
                 let future = poll_fn(move || {
-                    fs::remove_file(&path).or_else(|e| {
-                        // Don't report an error if the file doesn't exist.
-                        match e.kind() {
-                            io::ErrorKind::NotFound => Ok(()),
-                            _ => Err(e),
+                    let value = match read_head(&path)? {
+                        Some(ref contents) if !contents.is_empty() => {
+                            Some(deserialize(contents).chain_err(|| {
+                                format!("invalid value in head '{}'", path.display())
+                            })?)
                         }
-                    })?;
+                        _ => None,
+                    };
+                    Ok(Async::Ready(value))
+                });
+                pool.spawn(future)
+            })
+            .boxed()
+    }
+
+    /// Add several heads at once. All paths are computed up front and the files are then
+    /// created sequentially in a single pool task, stopping at the first failure.
+    pub fn add_many(&self, keys: &[T]) -> BoxFuture<(), Error> {
+        let pool = self.pool.clone();
+        let sharded = self.sharded;
+        let durability = self.durability;
+        self.get_paths(keys)
+            .into_future()
+            .and_then(move |paths| {
+                let future = poll_fn(move || {
+                    for path in &paths {
+                        create_head(path, sharded, durability)?;
+                    }
                     Ok(Async::Ready(()))
                 });
                 pool.spawn(future)
@@ -154,124 +537,2009 @@ where
             .boxed()
     }
 
-    fn is_head(&self, key: &Self::Key) -> Self::Bool {
+    /// Remove several heads at once. Like `add_many`, this is performed in a single pool task.
+    /// Heads that don't exist are ignored.
+    pub fn remove_many(&self, keys: &[T]) -> BoxFuture<(), Error> {
         let pool = self.pool.clone();
-        self.get_path(&key)
+        self.get_paths(keys)
             .into_future()
-            .and_then(move |path| {
-                let future = poll_fn(move || Ok(Async::Ready(path.exists())));
+            .and_then(move |paths| {
+                let future = poll_fn(move || {
+                    for path in &paths {
+                        remove_head(path)?;
+                    }
+                    Ok(Async::Ready(()))
+                });
                 pool.spawn(future)
             })
             .boxed()
     }
 
-    fn heads(&self) -> Self::Heads {
-        let names = fs::read_dir(&self.base).map(|entries| {
-            entries
-                .map(|result| {
-                    result
-                        .map_err(From::from)
-                        .map(|entry| entry.file_name().to_string_lossy().into_owned())
-                })
-                .filter(|result| match result {
-                    &Ok(ref name) => name.starts_with(PREFIX),
-                    &Err(_) => true,
-                })
-                .map(|result| {
-                    result.and_then(|name| {
-                        from_str::<UrlEncodeWrapper<T>>(&name[PREFIX.len()..])
-                            .map(|wrapper| wrapper.key)
-                            .map_err(From::from)
-                    })
-                })
+    /// Replace the head `old` with `new`, but only if `old` is currently a head. Resolves to
+    /// `false` without making any changes if `old` doesn't exist.
+    ///
+    /// `new` is created before `old` is removed, so a crash part way through leaves both heads
+    /// present rather than neither. Swapping a head with itself leaves it alone.
+    pub fn swap(&self, old: &T, new: &T) -> BoxFuture<bool, Error> {
+        let pool = self.pool.clone();
+        let sharded = self.sharded;
+        let durability = self.durability;
+        self.get_path(old)
+            .and_then(|old| self.get_path(new).map(|new| (old, new)))
+            .into_future()
+            .and_then(move |(old, new)| {
+                let future = poll_fn(move || {
+                    if old == new {
+                        return Ok(Async::Ready(old.exists()));
+                    }
+                    if !old.exists() {
+                        return Ok(Async::Ready(false));
+                    }
+                    create_head(&new, sharded, durability)?;
+                    remove_head(&old)?;
+                    Ok(Async::Ready(true))
+                });
+                pool.spawn(future)
+            })
+            .boxed()
+    }
+
+    /// Rename the head `from` to `to` by renaming its file, which is atomic as long as both
+    /// files are on the same filesystem. Resolves to `false` without making any changes if
+    /// `from` doesn't exist.
+    ///
+    /// Unless `overwrite` is set, this fails with `ErrorKind::HeadExists` if `to` is already a
+    /// head. That check is made just before the rename, so it can't detect `to` being added
+    /// concurrently.
+    pub fn rename(&self, from: &T, to: &T, overwrite: bool) -> BoxFuture<bool, Error> {
+        let pool = self.pool.clone();
+        let sharded = self.sharded;
+        self.get_path(from)
+            .and_then(|from| self.get_path(to).map(|to| (from, to)))
+            .into_future()
+            .and_then(move |(from, to)| {
+                let future = poll_fn(move || {
+                    if !from.exists() {
+                        return Ok(Async::Ready(false));
+                    }
+                    if !overwrite && to.exists() {
+                        bail!(ErrorKind::HeadExists(to.clone()));
+                    }
+                    if sharded {
+                        if let Some(parent) = to.parent() {
+                            fs::create_dir_all(parent)?;
+                        }
+                    }
+                    match fs::rename(&from, &to) {
+                        Ok(()) => Ok(Async::Ready(true)),
+                        // `from` was removed since it was checked.
+                        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                            Ok(Async::Ready(false))
+                        }
+                        Err(e) => Err(Error::with_chain(
+                            e,
+                            format!("failed to rename head '{}'", from.display()),
+                        )),
+                    }
+                });
+                pool.spawn(future)
+            })
+            .boxed()
+    }
+
+    /// Remove every head in the store in a single pool task, resolving to the number of heads
+    /// removed. Files in the directory that aren't heads of this store are left alone.
+    pub fn clear(&self) -> BoxFuture<usize, Error> {
+        let base = self.base.clone();
+        let prefix = self.prefix.clone();
+        let depth = self.depth();
+        let future = poll_fn(move || {
+            let mut count = 0;
+            for entry in walk_dir(&base, depth) {
+                let entry = entry?;
+                if !entry.file_name().to_string_lossy().starts_with(&prefix) {
+                    continue;
+                }
+                let path = entry.path();
+                match fs::remove_file(&path) {
+                    Ok(()) => count += 1,
+                    // Someone else removed it first.
+                    Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+                    Err(e) => {
+                        return Err(e).chain_err(
+                            || format!("failed to remove head '{}'", path.display()),
+                        )
+                    }
+                }
+            }
+            Ok(Async::Ready(count))
         });
-        match names {
-            Ok(v) => stream::iter(v).boxed(),
-            Err(e) => stream::once(Err(e.into())).boxed(),
-        }
+        self.pool.spawn(future)
     }
-}
 
+    /// Take an exclusive advisory lock on the store's directory, resolving to a guard which
+    /// holds it until it's dropped. If the lock is held elsewhere, this waits for it on the
+    /// pool, occupying one of its threads.
+    ///
+    /// The lock is on a `.lock` file in the directory, so it is shared by every store for the
+    /// directory, in any process, whatever their prefixes. It only excludes other holders of
+    /// the lock: operations on heads aren't synchronized with it, whether or not the lock is
+    /// held. It's for writers which take the lock around a series of changes to keep each
+    /// other's series from interleaving.
+    pub fn lock(&self) -> BoxFuture<HeadsGuard, Error> {
+        let path = self.base.join(LOCK_FILE);
+        let future = poll_fn(move || {
+            let guard = lock_file(&path, true)?.expect("blocking lock wasn't taken");
+            Ok(Async::Ready(guard))
+        });
+        self.pool.spawn(future)
+    }
 
-#[cfg(test)]
-mod test {
-    use super::*;
-    use std::str::FromStr;
-    use futures::{Future, Stream};
-    use tempdir::TempDir;
-    use mercurial_types::NodeHash;
-    use mercurial_types::hash::Sha1;
+    /// Like `lock`, but resolve to `None` instead of waiting if the lock is already held.
+    pub fn try_lock(&self) -> BoxFuture<Option<HeadsGuard>, Error> {
+        let path = self.base.join(LOCK_FILE);
+        let future = poll_fn(move || Ok(Async::Ready(lock_file(&path, false)?)));
+        self.pool.spawn(future)
+    }
 
-    #[test]
-    fn basic() {
-        let tmp = TempDir::new("filebookmarks_heads_basic").unwrap();
-        let heads = FileHeads::open(tmp.path()).unwrap();
-        let empty: Vec<String> = Vec::new();
-        assert_eq!(heads.heads().collect().wait().unwrap(), empty);
+    /// Resolve to how many head files the store has and how much space they take, gathered in
+    /// a single pool task. Files in the directory that aren't heads of this store aren't
+    /// counted, and nor are shard directories.
+    pub fn disk_usage(&self) -> BoxFuture<DiskUsage, Error> {
+        let base = self.base.clone();
+        let prefix = self.prefix.clone();
+        let depth = self.depth();
+        let future = poll_fn(move || {
+            let mut usage = DiskUsage::default();
+            for entry in walk_dir(&base, depth) {
+                let entry = entry?;
+                if !entry.file_name().to_string_lossy().starts_with(&prefix) {
+                    continue;
+                }
+                let metadata = match entry.metadata() {
+                    Ok(metadata) => metadata,
+                    // Someone else removed it since it was listed.
+                    Err(ref e) if e.kind() == io::ErrorKind::NotFound => continue,
+                    Err(e) => {
+                        return Err(e).chain_err(|| {
+                            format!("failed to read metadata of '{}'", entry.path().display())
+                        })
+                    }
+                };
+                usage.files += 1;
+                usage.bytes += metadata.len();
+                usage.allocated += metadata.blocks() * 512;
+            }
+            Ok(Async::Ready(usage))
+        });
+        self.pool.spawn(future)
+    }
 
-        let foo = "foo".to_string();
-        let bar = "bar".to_string();
-        let baz = "baz".to_string();
+    /// Return the path of the file that represents `key` in this store, whether or not it is
+    /// currently a head. Fails if the key can't be encoded.
+    ///
+    /// The path is in the canonical form of the store's directory, with any symlinks resolved.
+    pub fn path_for(&self, key: &T) -> Result<PathBuf> {
+        self.get_path(key)
+    }
 
-        assert!(!heads.is_head(&foo).wait().unwrap());
-        assert!(!heads.is_head(&bar).wait().unwrap());
-        assert!(!heads.is_head(&baz).wait().unwrap());
+    fn get_path(&self, key: &T) -> Result<PathBuf> {
+        let key_string = C::encode(key)?;
+        check_key_string(&key_string)?;
+        let name = format!("{}{}", self.prefix, key_string);
+        if self.sharded {
+            let hash = Sha1::from(key_string.as_bytes()).to_hex();
+            let hash = hash.as_str();
+            Ok(self.base.join(&hash[0..2]).join(&hash[2..4]).join(name))
+        } else {
+            Ok(self.base.join(name))
+        }
+    }
 
-        heads.add(&foo).wait().unwrap();
-        heads.add(&bar).wait().unwrap();
+    fn get_paths(&self, keys: &[T]) -> Result<Vec<PathBuf>> {
+        keys.iter().map(|key| self.get_path(key)).collect()
+    }
 
-        assert!(heads.is_head(&foo).wait().unwrap());
-        assert!(heads.is_head(&bar).wait().unwrap());
-        assert!(!heads.is_head(&baz).wait().unwrap());
+    /// Number of shard directory levels between the base directory and the head files.
+    fn depth(&self) -> usize {
+        if self.sharded { 2 } else { 0 }
+    }
+}
 
-        let mut result = heads.heads().collect().wait().unwrap();
-        result.sort();
+impl<T, C> FileHeads<T, C>
+where
+    T: Send + 'static,
+    C: HeadKeyCodec<T>,
+{
+    /// Like `heads()`, but entries whose names can't be deserialized are skipped rather than
+    /// reported as errors, so that a stray file in the directory doesn't prevent listing the
+    /// valid heads. Each skipped name is passed to `skipped` along with the reason. IO errors
+    /// are still reported in the stream.
+    pub fn heads_lenient<F>(&self, mut skipped: F) -> BoxStream<T, Error>
+    where
+        F: FnMut(String, Error) + Send + 'static,
+    {
+        let prefix_len = self.prefix.len();
+        let keys = self.head_names().filter_map(move |result| match result {
+            Ok(name) => match decode_name::<T, C>(&name, prefix_len) {
+                Ok(key) => Some(Ok(key)),
+                Err(e) => {
+                    skipped(name, e);
+                    None
+                }
+            },
+            Err(e) => Some(Err(e)),
+        });
+        stream::iter(keys).boxed()
+    }
 
-        assert_eq!(result, vec![bar.clone(), foo.clone()]);
+    /// Like `heads()`, but a name which can't be decoded is reported as a `DecodeError` item,
+    /// with the file name, rather than as an error which ends the stream. Errors in the stream
+    /// itself are then only failures to list the directory, so callers can carry on past bad
+    /// names without a callback, as with `heads_lenient`, and still stop on IO errors.
+    pub fn heads_results(&self) -> BoxStream<result::Result<T, DecodeError>, Error> {
+        let prefix_len = self.prefix.len();
+        let keys = self.head_names().map(move |result| {
+            result.map(|name| {
+                let decoded = decode_name::<T, C>(&name, prefix_len);
+                decoded.map_err(|e| DecodeError { name: name, error: e })
+            })
+        });
+        stream::iter(keys).boxed()
+    }
 
-        heads.remove(&foo).wait().unwrap();
-        heads.remove(&bar).wait().unwrap();
-        heads.remove(&baz).wait().unwrap(); // Removing non-existent head should not panic.
+    /// Like `heads()`, but only the heads whose encoded keys start with `prefix`. Other head
+    /// files are skipped by name, without decoding them, so listing a small part of a large
+    /// store is cheap.
+    ///
+    /// The prefix is matched against the encoded form of the key used in file names, not the
+    /// key itself. With `UrlEncodeCodec`, string keys are urlencoded a character at a time, so
+    /// `encode_key` applied to a prefix of a key gives a prefix of its encoding. For example,
+    /// `encode_key(&"refs/heads/".to_string())` selects the string keys under `refs/heads/`.
+    pub fn heads_starting_with(&self, prefix: &str) -> BoxStream<T, Error> {
+        let prefix_len = self.prefix.len();
+        let name_prefix = format!("{}{}", self.prefix, prefix);
+        let keys = self.head_names()
+            .filter(move |result| match result {
+                &Ok(ref name) => name.starts_with(&name_prefix),
+                &Err(_) => true,
+            })
+            .map(move |result| {
+                result.and_then(|name| decode_name::<T, C>(&name, prefix_len))
+            });
+        stream::iter(keys).boxed()
+    }
 
-        assert_eq!(heads.heads().collect().wait().unwrap(), empty);
+    /// Return the number of heads whose encoded keys, as used in file names, `matches_encoded`
+    /// returns `true` for. The keys aren't decoded, so for predicates which can be checked
+    /// against the encoding, such as keys starting with a prefix as with `heads_starting_with`,
+    /// this is cheaper than `count_matching`. It runs in a single pool task.
+    pub fn count_matching_encoded<F>(&self, matches_encoded: F) -> BoxFuture<usize, Error>
+    where
+        F: Fn(&str) -> bool + Send + 'static,
+    {
+        let base = self.base.clone();
+        let prefix = self.prefix.clone();
+        let depth = self.depth();
+        let future = poll_fn(move || {
+            let mut count = 0;
+            for entry in walk_dir(&base, depth) {
+                let name = entry?.file_name().to_string_lossy().into_owned();
+                if name.starts_with(&prefix) && matches_encoded(&name[prefix.len()..]) {
+                    count += 1;
+                }
+            }
+            Ok(Async::Ready(count))
+        });
+        self.pool.spawn(future)
     }
 
-    #[test]
-    fn persistence() {
-        let tmp = TempDir::new("filebookmarks_heads_persistence").unwrap();
-        let foo = "foo".to_string();
-        let bar = "bar".to_string();
+    /// Like `heads()`, but with when each head was last modified, as returned by `modified`.
+    /// Heads which are removed between being listed and having their time read are left out.
+    pub fn heads_with_mtime(&self) -> BoxStream<(T, SystemTime), Error> {
+        let prefix_len = self.prefix.len();
+        let keys = self.head_files().filter_map(move |result| {
+            let head = result.and_then(|(name, path)| {
+                let key = decode_name::<T, C>(&name, prefix_len)?;
+                Ok(head_mtime(&path)?.map(|mtime| (key, mtime)))
+            });
+            match head {
+                Ok(head) => head.map(Ok),
+                Err(e) => Some(Err(e)),
+            }
+        });
+        stream::iter(keys).boxed()
+    }
 
-        {
-            let heads = FileHeads::open(tmp.path()).unwrap();
-            heads.add(&foo).wait().unwrap();
-            heads.add(&bar).wait().unwrap();
-        }
+    /// Resolve to all the heads in the store. Unlike `heads()`, which reads the directory as
+    /// the stream is consumed, the whole listing is read into memory in a single pool task
+    /// before any names are decoded. This gives a much closer approximation of a point in time
+    /// view when other writers are active, at the cost of holding every name in memory.
+    ///
+    /// Directory listings aren't atomic, so a head added or removed while the listing is
+    /// being read may or may not be included.
+    pub fn snapshot(&self) -> BoxFuture<Vec<T>, Error> {
+        let prefix_len = self.prefix.len();
+        // The directory is read when the listing starts, so that has to happen on the pool.
+        let heads = self.clone();
+        let future = poll_fn(move || {
+            let names: Vec<String> = heads.head_names().collect::<Result<_>>()?;
+            Ok(Async::Ready(names))
+        });
+        self.pool
+            .spawn(future)
+            .and_then(move |names| {
+                names
+                    .iter()
+                    .map(|name| decode_name::<T, C>(name, prefix_len))
+                    .collect()
+            })
+            .boxed()
+    }
 
-        let heads = FileHeads::<String>::open(&tmp.path()).unwrap();
-        let mut result = heads.heads().collect().wait().unwrap();
-        result.sort();
-        assert_eq!(result, vec![bar.clone(), foo.clone()]);
+    /// Add every key produced by `keys`, resolving to the number of keys added. Only a bounded
+    /// number of adds are in flight at any time, so `keys` is consumed no faster than the
+    /// heads can be written.
+    pub fn add_stream<S>(&self, keys: S) -> BoxFuture<usize, Error>
+    where
+        S: Stream<Item = T, Error = Error> + Send + 'static,
+    {
+        let heads = self.clone();
+        keys.map(move |key| heads.add(&key))
+            .buffer_unordered(ADD_STREAM_CONCURRENCY)
+            .fold(0, |count, ()| Ok::<_, Error>(count + 1))
+            .boxed()
     }
 
-    #[test]
-    fn invalid_dir() {
-        let tmp = TempDir::new("filebookmarks_heads_invalid_dir").unwrap();
-        let heads = FileHeads::<String>::open(tmp.path().join("does_not_exist"));
-        assert!(heads.is_err());
+    /// Return a stream of the heads added to and removed from the directory from now on,
+    /// whether by this process or another one. Files without the head prefix are ignored.
+    ///
+    /// Changes are picked up through the platform's file notification mechanism (inotify on
+    /// Linux, FSEvents on macOS), which has some limitations:
+    ///
+    /// - changes made on other hosts to a network filesystem such as NFS usually aren't seen;
+    /// - FSEvents may coalesce several changes to the same file, and deliver them late;
+    /// - in a sharded store, a head added just as its shard directory is created can be missed.
+    ///
+    /// The events are read on a dedicated thread, which exits once the stream has been dropped
+    /// and the next change arrives.
+    pub fn watch(&self) -> BoxStream<HeadEvent<T>, Error> {
+        let (raw_tx, raw_rx) = std_mpsc::channel();
+        let mode = if self.sharded {
+            RecursiveMode::Recursive
+        } else {
+            RecursiveMode::NonRecursive
+        };
+        // Start watching before returning, so that no change made after this call is missed.
+        let watcher = raw_watcher(raw_tx).and_then(|mut watcher| {
+            watcher.watch(&self.base, mode)?;
+            Ok(watcher)
+        });
+        let watcher = match watcher {
+            Ok(watcher) => watcher,
+            Err(err) => return stream::once(Err(err.into())).boxed(),
+        };
+
+        let (mut tx, rx) = mpsc::channel(0);
+        let prefix = self.prefix.clone();
+        thread::spawn(move || {
+            // Dropping the watcher stops the notifications.
+            let _watcher = watcher;
+            let mut renames = HashSet::new();
+            for event in raw_rx {
+                if let Some(event) = head_event::<T, C>(event, &prefix, &mut renames) {
+                    match tx.send(event).wait() {
+                        Ok(sender) => tx = sender,
+                        Err(_) => break,
+                    }
+                }
+            }
+        });
+
+        rx.then(|event| event.expect("receiver can't fail")).boxed()
     }
 
-    #[test]
-    fn savenodehash() {
-        let tmp = TempDir::new("filebookmarks_heads_nod").unwrap();
-        {
-            let h = (0..40).map(|_| "a").collect::<String>();
-            let head = NodeHash::new(Sha1::from_str(h.as_str()).unwrap());
-            let heads = FileHeads::<NodeHash>::open(tmp.path()).unwrap();
-            heads.add(&head).wait().unwrap();
-            let mut result = heads.heads().collect().wait().unwrap();
-            result.sort();
-            assert_eq!(result, vec![head]);
-        }
+    /// Iterate over the names of all head files in the store.
+    fn head_names(&self) -> Box<Iterator<Item = Result<String>> + Send> {
+        Box::new(self.head_files().map(|result| result.map(|(name, _)| name)))
+    }
+
+    /// Iterate over the names and paths of all head files in the store.
+    fn head_files(&self) -> Box<Iterator<Item = Result<(String, PathBuf)>> + Send> {
+        let prefix = self.prefix.clone();
+        let files = walk_dir(&self.base, self.depth())
+            .map(|result| {
+                result.map_err(From::from).map(|entry| {
+                    (entry.file_name().to_string_lossy().into_owned(), entry.path())
+                })
+            })
+            .filter(move |result| match result {
+                &Ok((ref name, _)) => name.starts_with(&prefix),
+                &Err(_) => true,
+            });
+        Box::new(files)
+    }
+}
+
+/// An `EvictionPolicy` for a `BoundedHeads` over a `FileHeads`, which evicts the head whose file
+/// was modified longest ago. Since head files aren't modified once written, that's the head
+/// which was added, or last added again, first. Finding it lists the whole store.
+pub struct OldestByMtime;
+
+impl<T, C> EvictionPolicy<FileHeads<T, C>> for OldestByMtime
+where
+    T: Send + 'static,
+    C: HeadKeyCodec<T>,
+{
+    fn victim(&self, heads: &FileHeads<T, C>) -> BoxFuture<Option<T>, Error> {
+        heads
+            .heads_with_mtime()
+            .fold(None, |oldest, (key, mtime)| {
+                let older = match oldest {
+                    Some((_, oldest_mtime)) => mtime < oldest_mtime,
+                    None => true,
+                };
+                Ok::<_, Error>(if older { Some((key, mtime)) } else { oldest })
+            })
+            .map(|oldest| oldest.map(|(key, _)| key))
+            .boxed()
+    }
+}
+
+/// Turn a file notification into a `HeadEvent`, if it's about a head file being created or
+/// removed.
+///
+/// `renames` holds the cookies of renames whose old name has been seen but not the new one.
+fn head_event<T, C>(
+    event: RawEvent,
+    prefix: &str,
+    renames: &mut HashSet<u32>,
+) -> Option<Result<HeadEvent<T>>>
+where
+    C: HeadKeyCodec<T>,
+{
+    let (path, kind) = match (event.path, event.op) {
+        (_, Err(err)) => return Some(Err(err.into())),
+        (Some(path), Ok(kind)) => (path, kind),
+        (None, Ok(_)) => return None,
+    };
+    // Where the platform reports it, a rename within the directory is a pair of events with
+    // the same cookie, for the old name and then the new one. Heads are written by renaming a
+    // temporary file, so this matters even for files which aren't heads.
+    let renamed_to = match event.cookie {
+        Some(cookie) if kind.contains(op::RENAME) => {
+            let second = renames.remove(&cookie);
+            if !second {
+                renames.insert(cookie);
+            }
+            second
+        }
+        _ => false,
+    };
+    let name = match path.file_name() {
+        Some(name) => name.to_string_lossy().into_owned(),
+        None => return None,
+    };
+    if !name.starts_with(prefix) {
+        return None;
+    }
+
+    let added = if kind.contains(op::CREATE) {
+        true
+    } else if kind.contains(op::REMOVE) {
+        false
+    } else if kind.contains(op::RENAME) {
+        // Renames are reported for both the old and the new name. Unless this is known to be
+        // the new name, go by whether the file is there now.
+        renamed_to || path.exists()
+    } else {
+        return None;
+    };
+
+    let event = decode_name::<T, C>(&name, prefix.len()).map(|key| if added {
+        HeadEvent::Added(key)
+    } else {
+        HeadEvent::Removed(key)
+    });
+    Some(event)
+}
+
+/// Recover the key from the name of a head file, skipping the first `prefix_len` bytes.
+fn decode_name<T, C: HeadKeyCodec<T>>(name: &str, prefix_len: usize) -> Result<T> {
+    C::decode(&name[prefix_len..])
+}
+
+type DirEntries = Box<Iterator<Item = io::Result<DirEntry>> + Send>;
+
+/// Iterate over the entries `depth` subdirectory levels below `dir`. Non-directories found
+/// above that level are skipped.
+fn walk_dir(dir: &Path, depth: usize) -> DirEntries {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) => return Box::new(iter::once(Err(e))),
+    };
+    if depth == 0 {
+        return Box::new(entries);
+    }
+    Box::new(entries.flat_map(move |entry| -> DirEntries {
+        let entry = entry.and_then(|entry| entry.file_type().map(|ty| (entry, ty)));
+        match entry {
+            Ok((entry, ty)) => if ty.is_dir() {
+                walk_dir(&entry.path(), depth - 1)
+            } else {
+                Box::new(iter::empty())
+            },
+            Err(e) => Box::new(iter::once(Err(e))),
+        }
+    }))
+}
+
+/// Probe whether file names in `dir` are case sensitive, by creating a file with a lower case
+/// name and checking whether the upper case version of the name then exists.
+fn is_case_sensitive(dir: &Path) -> Result<bool> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or(Duration::from_secs(0));
+    let name = format!("case-probe-{}-{}", now.as_secs(), now.subsec_nanos());
+    let lower = dir.join(&name);
+    let upper = dir.join(name.to_uppercase());
+
+    fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&lower)
+        .chain_err(|| format!("failed to create '{}'", lower.display()))?;
+    let sensitive = !upper.exists();
+    fs::remove_file(&lower)?;
+    Ok(sensitive)
+}
+
+/// Make sure an encoded key can't refer to a path outside of the store directory. The
+/// urlencoding should already escape separators, but this doesn't rely on it.
+fn check_key_string(key_string: &str) -> Result<()> {
+    if key_string == "." || key_string == ".." || key_string.contains('/') ||
+        key_string.contains(path::MAIN_SEPARATOR)
+    {
+        bail!(ErrorKind::InvalidKey(key_string.to_string()));
+    }
+    Ok(())
+}
+
+fn create_head(path: &Path, sharded: bool, durability: Durability) -> Result<()> {
+    write_head(path, &[], sharded, durability)
+}
+
+/// Like `create_head`, but with `contents` as the contents of the file.
+///
+/// The file is written under a temporary name and then renamed into place, so a reader sees
+/// either the old file or the whole of the new one.
+fn write_head(path: &Path, contents: &[u8], sharded: bool, durability: Durability) -> Result<()> {
+    if sharded {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+    }
+    let (mut file, temp) = create_temp(path)?;
+    let written = file.write_all(contents)
+        .and_then(|()| if durability == Durability::Fsync {
+            // The contents must be persisted before the rename is.
+            file.sync_all()
+        } else {
+            Ok(())
+        })
+        .and_then(|()| fs::rename(&temp, path));
+    if let Err(e) = written {
+        let _ = fs::remove_file(&temp);
+        return Err(Error::with_chain(
+            e,
+            format!("failed to write head '{}'", path.display()),
+        ));
+    }
+    sync_dir(path, durability)
+}
+
+// Number of temporary files created by this process, to give each a different name.
+static TEMP_FILES: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// Create a new file next to `path` to write its contents to before renaming it into place.
+/// The name starts with `.`, which head file prefixes can't, so it isn't mistaken for a head.
+fn create_temp(path: &Path) -> Result<(File, PathBuf)> {
+    let name = match path.file_name() {
+        Some(name) => name.to_string_lossy().into_owned(),
+        None => bail!(ErrorKind::InvalidKey(path.display().to_string())),
+    };
+    loop {
+        // Another process may be using the same name.
+        let count = TEMP_FILES.fetch_add(1, Ordering::Relaxed);
+        let temp = path.with_file_name(format!(".{}.tmp{}", name, count));
+        match fs::OpenOptions::new().write(true).create_new(true).open(&temp) {
+            Ok(file) => return Ok((file, temp)),
+            Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(e) => {
+                return Err(Error::with_chain(
+                    e,
+                    format!("failed to create head '{}'", path.display()),
+                ))
+            }
+        }
+    }
+}
+
+/// Return the contents of a head file, or `None` if it doesn't exist.
+fn read_head(path: &Path) -> Result<Option<Vec<u8>>> {
+    let mut file = match File::open(path) {
+        Ok(file) => file,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => {
+            return Err(Error::with_chain(
+                e,
+                format!("failed to read head '{}'", path.display()),
+            ))
+        }
+    };
+    let mut contents = Vec::new();
+    file.read_to_end(&mut contents)
+        .chain_err(|| format!("failed to read head '{}'", path.display()))?;
+    Ok(Some(contents))
+}
+
+/// The space taken by the heads in a `FileHeads` store, returned by `FileHeads::disk_usage`.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct DiskUsage {
+    /// Number of head files
+    pub files: usize,
+    /// Total length of the head files, which is only non-zero for heads with values
+    pub bytes: u64,
+    /// Total space allocated on disk for the head files. This doesn't include the space taken
+    /// by their inodes and directory entries, which depends on the filesystem.
+    pub allocated: u64,
+}
+
+/// A lock on a `FileHeads` directory, returned by `FileHeads::lock`. The lock is released when
+/// this is dropped.
+pub struct HeadsGuard {
+    file: File,
+}
+
+impl Drop for HeadsGuard {
+    fn drop(&mut self) {
+        // Closing the file would release the lock anyway, so a failure here doesn't matter.
+        let _ = fcntl::flock(self.file.as_raw_fd(), FlockArg::Unlock);
+    }
+}
+
+/// Take an exclusive lock on the file at `path`, creating it if needed. If `wait` is false and
+/// the lock is held elsewhere, return `None` instead of waiting.
+fn lock_file(path: &Path, wait: bool) -> Result<Option<HeadsGuard>> {
+    let file = fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .open(path)
+        .chain_err(|| format!("failed to open lock file '{}'", path.display()))?;
+    let arg = if wait {
+        FlockArg::LockExclusive
+    } else {
+        FlockArg::LockExclusiveNonblock
+    };
+    match fcntl::flock(file.as_raw_fd(), arg) {
+        Ok(()) => Ok(Some(HeadsGuard { file: file })),
+        Err(nix::Error::Sys(nix::Errno::EWOULDBLOCK)) if !wait => Ok(None),
+        Err(e) => Err(e).chain_err(|| format!("failed to lock '{}'", path.display())),
+    }
+}
+
+/// Like `create_head`, but leave an existing head alone, returning whether the head was
+/// created.
+fn create_new_head(path: &Path, sharded: bool, durability: Durability) -> Result<bool> {
+    if sharded {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+    }
+    let file = match fs::OpenOptions::new().write(true).create_new(true).open(path) {
+        Ok(file) => file,
+        Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => return Ok(false),
+        Err(e) => {
+            return Err(Error::with_chain(
+                e,
+                format!("failed to create head '{}'", path.display()),
+            ))
+        }
+    };
+    sync_head(&file, path, durability)?;
+    Ok(true)
+}
+
+fn sync_head(file: &File, path: &Path, durability: Durability) -> Result<()> {
+    if durability == Durability::Fsync {
+        file.sync_all()?;
+    }
+    sync_dir(path, durability)
+}
+
+/// Persist the directory entry for `path`.
+fn sync_dir(path: &Path, durability: Durability) -> Result<()> {
+    if durability == Durability::Fsync {
+        if let Some(parent) = path.parent() {
+            File::open(parent)?.sync_all()?;
+        }
+    }
+    Ok(())
+}
+
+fn remove_head(path: &Path) -> Result<()> {
+    remove_existing_head(path).map(|_| ())
+}
+
+/// Like `remove_head`, but return whether there was a head to remove.
+fn remove_existing_head(path: &Path) -> Result<bool> {
+    fs::remove_file(path)
+        .map(|()| true)
+        .or_else(|e| {
+            // Don't report an error if the file doesn't exist.
+            match e.kind() {
+                io::ErrorKind::NotFound => Ok(false),
+                _ => Err(e),
+            }
+        })
+        .chain_err(|| format!("failed to remove head '{}'", path.display()))
+}
+
+/// Return the modification time of a head file, or `None` if it doesn't exist.
+fn head_mtime(path: &Path) -> Result<Option<SystemTime>> {
+    let metadata = match fs::metadata(path) {
+        Ok(metadata) => metadata,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => {
+            return Err(Error::with_chain(
+                e,
+                format!("failed to read head '{}'", path.display()),
+            ))
+        }
+    };
+    Ok(Some(metadata.modified()?))
+}
+
+/// Apply the head file changes in `ops` in order. Adding a head which already exists leaves
+/// its file, and any value stored in it, alone. If a change fails, the changes made so far are
+/// undone on a best-effort basis, restoring the contents of removed heads, and the original
+/// error is returned.
+fn apply_ops(ops: &[HeadOp<PathBuf>], sharded: bool, durability: Durability) -> Result<()> {
+    // Each path changed so far, with its contents beforehand, if it existed.
+    let mut undo = Vec::new();
+    for op in ops {
+        let applied = match op {
+            &HeadOp::Add(ref path) => read_head(path).and_then(|old| match old {
+                Some(_) => Ok(None),
+                None => create_head(path, sharded, durability).map(|()| Some((path, None))),
+            }),
+            &HeadOp::Remove(ref path) => read_head(path).and_then(|old| {
+                remove_head(path).map(|()| old.map(|old| (path, Some(old))))
+            }),
+        };
+        match applied {
+            Ok(Some((path, old))) => undo.push((path, old)),
+            Ok(None) => (),
+            Err(e) => {
+                for (path, old) in undo.into_iter().rev() {
+                    let _ = match old {
+                        Some(contents) => write_head(path, &contents, sharded, durability),
+                        None => remove_head(path),
+                    };
+                }
+                return Err(e);
+            }
+        }
+    }
+    Ok(())
+}
+
+impl<T, C> Heads for FileHeads<T, C>
+where
+    T: Send + 'static,
+    C: HeadKeyCodec<T>,
+{
+    type Key = T;
+    type Error = Error;
+
+    type Unit = BoxFuture<(), Self::Error>;
+    type Bool = BoxFuture<bool, Self::Error>;
+    type Heads = BoxStream<Self::Key, Self::Error>;
+
+    fn add(&self, key: &Self::Key) -> Self::Unit {
+        let pool = self.pool.clone();
+        let sharded = self.sharded;
+        let durability = self.durability;
+        self.get_path(&key)
+            .into_future()
+            .and_then(move |path| {
+                let future = poll_fn(move || {
+                    create_head(&path, sharded, durability)?;
+                    Ok(Async::Ready(()))
+                });
+                pool.spawn(future)
+            })
+            .boxed()
+    }
+
+    fn remove(&self, key: &Self::Key) -> Self::Unit {
+        let pool = self.pool.clone();
+        self.get_path(&key)
+            .into_future()
+            .and_then(move |path| {
+                let future = poll_fn(move || {
+                    remove_head(&path)?;
+                    Ok(Async::Ready(()))
+                });
+                pool.spawn(future)
+            })
+            .boxed()
+    }
+
+    fn is_head(&self, key: &Self::Key) -> Self::Bool {
+        let pool = self.pool.clone();
+        self.get_path(&key)
+            .into_future()
+            .and_then(move |path| {
+                let future = poll_fn(move || Ok(Async::Ready(path.exists())));
+                pool.spawn(future)
+            })
+            .boxed()
+    }
+
+    fn heads(&self) -> Self::Heads {
+        let prefix_len = self.prefix.len();
+        let policy = match self.decode_policy {
+            None => {
+                let keys = self.head_names().map(move |result| {
+                    result.and_then(|name| decode_name::<T, C>(&name, prefix_len))
+                });
+                return stream::iter(keys).boxed();
+            }
+            Some(ref policy) => policy.clone(),
+        };
+
+        let keys = self.head_files().filter_map(move |result| match result {
+            Ok((name, path)) => match decode_name::<T, C>(&name, prefix_len) {
+                Ok(key) => Some(Ok(key)),
+                Err(e) => {
+                    let mut policy = policy.lock().expect("lock poisoned");
+                    match (&mut **policy)(&path, &e) {
+                        SkipOrAbort::Skip => None,
+                        SkipOrAbort::Abort => Some(Err(e)),
+                    }
+                }
+            },
+            Err(e) => Some(Err(e)),
+        });
+        stream::iter(keys).boxed()
+    }
+
+    /// All the keys are checked in a single pool task.
+    fn contains_all(&self, keys: &[Self::Key]) -> BoxFuture<bool, Self::Error> {
+        let pool = self.pool.clone();
+        self.get_paths(keys)
+            .into_future()
+            .and_then(move |paths| {
+                let future = poll_fn(move || Ok(Async::Ready(paths.iter().all(|p| p.exists()))));
+                pool.spawn(future)
+            })
+            .boxed()
+    }
+
+    /// The listing is read with `snapshot`, in a single pool task. Keys are sorted after
+    /// they're decoded, since their encoded names don't necessarily sort the same way.
+    fn heads_sorted(&self) -> BoxFuture<Vec<Self::Key>, Self::Error>
+    where
+        Self::Key: Ord,
+    {
+        self.snapshot()
+            .map(|mut keys| {
+                keys.sort();
+                keys
+            })
+            .boxed()
+    }
+
+    /// All the keys are checked in a single pool task, and the heads among them are streamed
+    /// once it completes.
+    fn filter_present(&self, keys: Vec<Self::Key>) -> BoxStream<Self::Key, Self::Error> {
+        let pool = self.pool.clone();
+        self.get_paths(&keys)
+            .into_future()
+            .and_then(move |paths| {
+                let mut keys = Some(keys);
+                let future = poll_fn(move || {
+                    let keys = keys.take().expect("polled after completion");
+                    let present: Vec<_> = keys.into_iter()
+                        .zip(paths.iter())
+                        .filter(|&(_, path)| path.exists())
+                        .map(|(key, _)| Ok::<_, Error>(key))
+                        .collect();
+                    Ok(Async::Ready(present))
+                });
+                pool.spawn(future)
+            })
+            .map(stream::iter)
+            .flatten_stream()
+            .boxed()
+    }
+
+    /// Count the head files directly, without deserializing their names.
+    fn count(&self) -> BoxFuture<usize, Self::Error> {
+        let base = self.base.clone();
+        let prefix = self.prefix.clone();
+        let depth = self.depth();
+        let future = poll_fn(move || {
+            let mut count = 0;
+            for entry in walk_dir(&base, depth) {
+                if entry?.file_name().to_string_lossy().starts_with(&prefix) {
+                    count += 1;
+                }
+            }
+            Ok(Async::Ready(count))
+        });
+        self.pool.spawn(future)
+    }
+
+    /// The heads are listed, decoded and checked in a single pool task. A name which can't be
+    /// decoded is an error, as with `heads()`.
+    fn count_matching<F>(&self, pred: F) -> BoxFuture<usize, Self::Error>
+    where
+        F: Fn(&Self::Key) -> bool + Send + 'static,
+    {
+        let base = self.base.clone();
+        let prefix = self.prefix.clone();
+        let depth = self.depth();
+        let future = poll_fn(move || {
+            let mut count = 0;
+            for entry in walk_dir(&base, depth) {
+                let name = entry?.file_name().to_string_lossy().into_owned();
+                if name.starts_with(&prefix) && pred(&decode_name::<T, C>(&name, prefix.len())?) {
+                    count += 1;
+                }
+            }
+            Ok(Async::Ready(count))
+        });
+        self.pool.spawn(future)
+    }
+
+    /// The operations are applied in order in a single pool task. If one fails, those already
+    /// applied are undone before the error is reported.
+    ///
+    /// This is not fully atomic: other users of the directory can observe intermediate states,
+    /// a crash part way through leaves the operations applied so far in place, and undoing a
+    /// change can itself fail.
+    fn apply(&self, ops: Vec<HeadOp<Self::Key>>) -> BoxFuture<(), Self::Error> {
+        let paths: Result<Vec<_>> = ops.iter()
+            .map(|op| match op {
+                &HeadOp::Add(ref key) => self.get_path(key).map(HeadOp::Add),
+                &HeadOp::Remove(ref key) => self.get_path(key).map(HeadOp::Remove),
+            })
+            .collect();
+        let pool = self.pool.clone();
+        let sharded = self.sharded;
+        let durability = self.durability;
+        paths
+            .into_future()
+            .and_then(move |ops| {
+                let future = poll_fn(move || {
+                    apply_ops(&ops, sharded, durability)?;
+                    Ok(Async::Ready(()))
+                });
+                pool.spawn(future)
+            })
+            .boxed()
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::str::FromStr;
+    use futures::{Future, Stream};
+    use tempdir::TempDir;
+    use heads::{Backoff, BoundedHeads, CachedHeads, InsertionOrder, InstrumentedHeads, Primary,
+                RetryingHeads, UnionHeads, migrate_heads};
+    use memheads::MemHeads;
+    use mercurial_types::NodeHash;
+    use mercurial_types::hash::Sha1;
+
+    #[test]
+    fn basic() {
+        let tmp = TempDir::new("filebookmarks_heads_basic").unwrap();
+        let heads = FileHeads::open(tmp.path()).unwrap();
+        let empty: Vec<String> = Vec::new();
+        assert_eq!(heads.heads().collect().wait().unwrap(), empty);
+
+        let foo = "foo".to_string();
+        let bar = "bar".to_string();
+        let baz = "baz".to_string();
+
+        assert!(!heads.is_head(&foo).wait().unwrap());
+        assert!(!heads.is_head(&bar).wait().unwrap());
+        assert!(!heads.is_head(&baz).wait().unwrap());
+
+        heads.add(&foo).wait().unwrap();
+        heads.add(&bar).wait().unwrap();
+
+        assert!(heads.is_head(&foo).wait().unwrap());
+        assert!(heads.is_head(&bar).wait().unwrap());
+        assert!(!heads.is_head(&baz).wait().unwrap());
+
+        let mut result = heads.heads().collect().wait().unwrap();
+        result.sort();
+
+        assert_eq!(result, vec![bar.clone(), foo.clone()]);
+
+        heads.remove(&foo).wait().unwrap();
+        heads.remove(&bar).wait().unwrap();
+        heads.remove(&baz).wait().unwrap(); // Removing non-existent head should not panic.
+
+        assert_eq!(heads.heads().collect().wait().unwrap(), empty);
+    }
+
+    #[test]
+    fn persistence() {
+        let tmp = TempDir::new("filebookmarks_heads_persistence").unwrap();
+        let foo = "foo".to_string();
+        let bar = "bar".to_string();
+
+        {
+            let heads = FileHeads::open(tmp.path()).unwrap();
+            heads.add(&foo).wait().unwrap();
+            heads.add(&bar).wait().unwrap();
+        }
+
+        let heads = FileHeads::<String>::open(&tmp.path()).unwrap();
+        let mut result = heads.heads().collect().wait().unwrap();
+        result.sort();
+        assert_eq!(result, vec![bar.clone(), foo.clone()]);
+    }
+
+    #[test]
+    fn batch() {
+        let tmp = TempDir::new("filebookmarks_heads_batch").unwrap();
+        let heads = FileHeads::open(tmp.path()).unwrap();
+        let keys: Vec<String> = (0..100).map(|i| format!("head{}", i)).collect();
+
+        heads.add_many(&keys).wait().unwrap();
+        let mut result = heads.heads().collect().wait().unwrap();
+        result.sort();
+        let mut expected = keys.clone();
+        expected.sort();
+        assert_eq!(result, expected);
+
+        heads.remove_many(&keys[..50]).wait().unwrap();
+        let mut result = heads.heads().collect().wait().unwrap();
+        result.sort();
+        let mut expected = keys[50..].to_vec();
+        expected.sort();
+        assert_eq!(result, expected);
+
+        // Removing already removed heads should not fail.
+        heads.remove_many(&keys).wait().unwrap();
+        assert_eq!(heads.heads().collect().wait().unwrap(), Vec::<String>::new());
+    }
+
+    #[test]
+    fn count() {
+        let tmp = TempDir::new("filebookmarks_heads_count").unwrap();
+        let heads = FileHeads::open(tmp.path()).unwrap();
+        assert_eq!(heads.count().wait().unwrap(), 0);
+
+        heads.add(&"foo".to_string()).wait().unwrap();
+        heads.add(&"bar".to_string()).wait().unwrap();
+        // Files without the head prefix are not counted.
+        File::create(tmp.path().join("unrelated")).unwrap();
+
+        assert_eq!(heads.count().wait().unwrap(), 2);
+    }
+
+    #[test]
+    fn count_matching() {
+        let tmp = TempDir::new("filebookmarks_heads_count_matching").unwrap();
+        let heads = FileHeads::open(tmp.path()).unwrap();
+        for key in vec!["draft/foo", "draft/bar", "public/baz"] {
+            heads.add(&key.to_string()).wait().unwrap();
+        }
+        File::create(tmp.path().join("unrelated")).unwrap();
+
+        let drafts = heads.count_matching(|key: &String| key.starts_with("draft/"));
+        assert_eq!(drafts.wait().unwrap(), 2);
+        assert_eq!(heads.count_matching(|_: &String| false).wait().unwrap(), 0);
+
+        // The encoded form of a prefix selects the same keys.
+        let prefix = encode_key(&"draft/".to_string()).unwrap();
+        let drafts = heads.count_matching_encoded(move |encoded| encoded.starts_with(&prefix));
+        assert_eq!(drafts.wait().unwrap(), 2);
+        assert_eq!(heads.count_matching_encoded(|_| true).wait().unwrap(), 3);
+    }
+
+    #[test]
+    fn disk_usage() {
+        let tmp = TempDir::new("filebookmarks_heads_disk_usage").unwrap();
+        let heads = FileHeads::open(tmp.path()).unwrap();
+        assert_eq!(heads.disk_usage().wait().unwrap(), DiskUsage::default());
+
+        heads.add(&"foo".to_string()).wait().unwrap();
+        heads.add(&"bar".to_string()).wait().unwrap();
+        heads.add_with_value(&"baz".to_string(), &12345u64).wait().unwrap();
+        // Files without the head prefix are not counted.
+        File::create(tmp.path().join("unrelated"))
+            .unwrap()
+            .write_all(b"unrelated")
+            .unwrap();
+
+        let usage = heads.disk_usage().wait().unwrap();
+        assert_eq!(usage.files, 3);
+        assert_eq!(usage.bytes, serialize(&12345u64, Infinite).unwrap().len() as u64);
+
+        // Sharded stores are counted too.
+        let tmp = TempDir::new("filebookmarks_heads_disk_usage_sharded").unwrap();
+        let heads = FileHeads::open_sharded(tmp.path()).unwrap();
+        heads.add(&"foo".to_string()).wait().unwrap();
+        heads.add(&"bar".to_string()).wait().unwrap();
+        assert_eq!(heads.disk_usage().wait().unwrap().files, 2);
+    }
+
+    #[test]
+    fn contains_all() {
+        let tmp = TempDir::new("filebookmarks_heads_contains_all").unwrap();
+        let heads = FileHeads::open(tmp.path()).unwrap();
+        let foo = "foo".to_string();
+        let bar = "bar".to_string();
+        let baz = "baz".to_string();
+        heads.add_many(&[foo.clone(), bar.clone()]).wait().unwrap();
+
+        assert!(heads.contains_all(&[foo.clone(), bar.clone()]).wait().unwrap());
+        assert!(!heads.contains_all(&[foo.clone(), baz.clone()]).wait().unwrap());
+        assert!(heads.contains_all(&[]).wait().unwrap());
+    }
+
+    #[test]
+    fn filter_present() {
+        let tmp = TempDir::new("filebookmarks_heads_filter_present").unwrap();
+        let heads = FileHeads::open_sharded(tmp.path()).unwrap();
+        let keys: Vec<String> = (0..10).map(|i| format!("head{}", i)).collect();
+        let added = vec![keys[0].clone(), keys[3].clone(), keys[7].clone()];
+        heads.add_many(&added).wait().unwrap();
+
+        let mut present = heads.filter_present(keys).collect().wait().unwrap();
+        present.sort();
+        assert_eq!(present, added);
+        assert!(heads.filter_present(vec![]).collect().wait().unwrap().is_empty());
+    }
+
+    #[test]
+    fn heads_sorted() {
+        let tmp = TempDir::new("filebookmarks_heads_heads_sorted").unwrap();
+        let heads = FileHeads::open_sharded(tmp.path()).unwrap();
+        // Urlencoding doesn't preserve the order of these.
+        let mut keys: Vec<String> = vec!["b", "a/b", "a", "a b", "A", "~"]
+            .into_iter()
+            .map(String::from)
+            .collect();
+        heads.add_many(&keys).wait().unwrap();
+
+        keys.sort();
+        assert_eq!(heads.heads_sorted().wait().unwrap(), keys);
+    }
+
+    #[test]
+    fn heads_starting_with() {
+        let tmp = TempDir::new("filebookmarks_heads_heads_starting_with").unwrap();
+        let heads = FileHeads::open(tmp.path()).unwrap();
+        let keys: Vec<String> = vec![
+            "refs/heads/feature/a",
+            "refs/heads/feature/b",
+            "refs/heads/featured",
+            "refs/heads/master",
+            "refs/tags/v1",
+        ].into_iter()
+            .map(String::from)
+            .collect();
+        heads.add_many(&keys).wait().unwrap();
+
+        let starting_with = |prefix: &str| {
+            let encoded = encode_key(&prefix.to_string()).unwrap();
+            let mut result = heads.heads_starting_with(&encoded).collect().wait().unwrap();
+            result.sort();
+            result
+        };
+        assert_eq!(starting_with("refs/heads/feature/"), &keys[0..2]);
+        assert_eq!(starting_with("refs/heads/"), &keys[0..4]);
+        assert_eq!(starting_with(""), keys);
+        assert!(starting_with("refs/remotes/").is_empty());
+    }
+
+    #[test]
+    fn migrate() {
+        let tmp = TempDir::new("filebookmarks_heads_migrate").unwrap();
+        let src = FileHeads::open(tmp.path()).unwrap();
+        let keys = vec!["foo".to_string(), "bar".to_string(), "a b/c".to_string()];
+        src.add_many(&keys).wait().unwrap();
+
+        let dst = MemHeads::new();
+        dst.add(&"existing".to_string()).wait().unwrap();
+        assert_eq!(migrate_heads(&src, &dst).wait().unwrap(), keys.len());
+
+        for key in &keys {
+            assert!(dst.is_head(key).wait().unwrap());
+        }
+        assert!(dst.is_head(&"existing".to_string()).wait().unwrap());
+
+        // The source is left untouched.
+        let mut remaining = src.heads().collect().wait().unwrap();
+        remaining.sort();
+        let mut expected = keys.clone();
+        expected.sort();
+        assert_eq!(remaining, expected);
+    }
+
+    #[test]
+    fn watch() {
+        let tmp = TempDir::new("filebookmarks_heads_watch").unwrap();
+        let heads = FileHeads::open(tmp.path()).unwrap();
+        let foo = "foo".to_string();
+        let events = heads.watch();
+
+        // Files without the prefix don't produce events.
+        File::create(tmp.path().join("other")).unwrap();
+        heads.add(&foo).wait().unwrap();
+        heads.remove(&foo).wait().unwrap();
+
+        let events = events.take(2).collect().wait().unwrap();
+        assert_eq!(events, vec![HeadEvent::Added(foo.clone()), HeadEvent::Removed(foo)]);
+    }
+
+    #[test]
+    fn path_traversal() {
+        let tmp = TempDir::new("filebookmarks_heads_path_traversal").unwrap();
+        let base = tmp.path().join("heads");
+        let heads = FileHeads::create(&base).unwrap();
+
+        // The separator is urlencoded, so the key is accepted and stored inside the store
+        // directory.
+        let key = "../escape".to_string();
+        heads.add(&key).wait().unwrap();
+        let path = heads.get_path(&key).unwrap();
+        assert_eq!(path.parent(), Some(base.as_path()));
+        assert!(path.is_file());
+        assert_eq!(heads.heads().collect().wait().unwrap(), vec![key.clone()]);
+        assert_eq!(fs::read_dir(tmp.path()).unwrap().count(), 1);
+
+        assert!(check_key_string("..").is_err());
+        assert!(check_key_string(".").is_err());
+        assert!(check_key_string("../escape").is_err());
+        assert!(check_key_string("..%2Fescape").is_ok());
+    }
+
+    #[test]
+    fn sharded() {
+        let tmp = TempDir::new("filebookmarks_heads_sharded").unwrap();
+        let heads = FileHeads::create_sharded(tmp.path()).unwrap();
+        let foo = "foo".to_string();
+        let bar = "bar".to_string();
+
+        heads.add(&foo).wait().unwrap();
+        heads.add_many(&[bar.clone()]).wait().unwrap();
+
+        // Head files live two levels below the base directory.
+        let path = heads.get_path(&foo).unwrap();
+        assert!(path.is_file());
+        let base = fs::canonicalize(tmp.path()).unwrap();
+        assert_eq!(path.parent().unwrap().parent().unwrap().parent(), Some(base.as_path()));
+
+        assert!(heads.is_head(&foo).wait().unwrap());
+        assert!(heads.is_head(&bar).wait().unwrap());
+        assert_eq!(heads.count().wait().unwrap(), 2);
+
+        let mut result = heads.heads().collect().wait().unwrap();
+        result.sort();
+        assert_eq!(result, vec![bar.clone(), foo.clone()]);
+
+        heads.remove(&foo).wait().unwrap();
+        assert!(!heads.is_head(&foo).wait().unwrap());
+
+        let heads = FileHeads::<String>::open_sharded(tmp.path()).unwrap();
+        assert_eq!(heads.heads().collect().wait().unwrap(), vec![bar]);
+    }
+
+    #[test]
+    fn heads_results() {
+        let tmp = TempDir::new("filebookmarks_heads_results").unwrap();
+        let heads = FileHeads::open(tmp.path()).unwrap();
+        let foo = "foo".to_string();
+        heads.add(&foo).wait().unwrap();
+        File::create(tmp.path().join(format!("{}garbage", PREFIX))).unwrap();
+
+        let mut good = Vec::new();
+        let mut bad = Vec::new();
+        for result in heads.heads_results().collect().wait().unwrap() {
+            match result {
+                Ok(key) => good.push(key),
+                Err(err) => bad.push(err.name),
+            }
+        }
+        assert_eq!(good, vec![foo]);
+        assert_eq!(bad, vec![format!("{}garbage", PREFIX)]);
+
+        // Failing to list the directory is still an error in the stream.
+        drop(tmp);
+        assert!(heads.heads_results().collect().wait().is_err());
+    }
+
+    #[test]
+    fn lenient() {
+        let tmp = TempDir::new("filebookmarks_heads_lenient").unwrap();
+        let heads = FileHeads::open(tmp.path()).unwrap();
+        let foo = "foo".to_string();
+        heads.add(&foo).wait().unwrap();
+        File::create(tmp.path().join(format!("{}garbage", PREFIX))).unwrap();
+
+        // The strict listing reports the bad entry as an error.
+        assert!(heads.heads().collect().wait().is_err());
+
+        let skipped = Arc::new(Mutex::new(Vec::new()));
+        let result = {
+            let skipped = skipped.clone();
+            heads
+                .heads_lenient(move |name, _| skipped.lock().unwrap().push(name))
+                .collect()
+                .wait()
+                .unwrap()
+        };
+        assert_eq!(result, vec![foo]);
+        assert_eq!(*skipped.lock().unwrap(), vec![format!("{}garbage", PREFIX)]);
+    }
+
+    #[test]
+    fn decode_policy() {
+        let tmp = TempDir::new("filebookmarks_heads_decode_policy").unwrap();
+        let corrupt = tmp.path().join(".corrupt");
+        fs::create_dir(&corrupt).unwrap();
+        let foo = "foo".to_string();
+        let garbage = format!("{}garbage", PREFIX);
+        File::create(tmp.path().join(&garbage)).unwrap();
+
+        // Move bad entries aside, and carry on listing.
+        let heads = {
+            let corrupt = corrupt.clone();
+            FileHeads::open(tmp.path())
+                .unwrap()
+                .with_decode_policy(move |path, _| {
+                    let name = path.file_name().expect("no file name");
+                    fs::rename(path, corrupt.join(name)).unwrap();
+                    SkipOrAbort::Skip
+                })
+        };
+        heads.add(&foo).wait().unwrap();
+        assert_eq!(heads.heads().collect().wait().unwrap(), vec![foo.clone()]);
+        assert!(!tmp.path().join(&garbage).exists());
+        assert!(corrupt.join(&garbage).exists());
+
+        // A policy can still give up.
+        File::create(tmp.path().join(&garbage)).unwrap();
+        let heads = heads.with_decode_policy(|_, _| SkipOrAbort::Abort);
+        assert!(heads.heads().collect().wait().is_err());
+    }
+
+    #[test]
+    fn fsync() {
+        let tmp = TempDir::new("filebookmarks_heads_fsync").unwrap();
+        let heads = FileHeads::open(tmp.path())
+            .unwrap()
+            .with_durability(Durability::Fsync);
+        let foo = "foo".to_string();
+
+        heads.add(&foo).wait().unwrap();
+        heads.add_many(&["bar".to_string()]).wait().unwrap();
+        assert!(heads.is_head(&foo).wait().unwrap());
+        assert_eq!(heads.count().wait().unwrap(), 2);
+    }
+
+    #[test]
+    fn swap() {
+        let tmp = TempDir::new("filebookmarks_heads_swap").unwrap();
+        let heads = FileHeads::open(tmp.path()).unwrap();
+        let foo = "foo".to_string();
+        let bar = "bar".to_string();
+        let baz = "baz".to_string();
+
+        heads.add(&foo).wait().unwrap();
+        assert!(heads.swap(&foo, &bar).wait().unwrap());
+        assert!(!heads.is_head(&foo).wait().unwrap());
+        assert!(heads.is_head(&bar).wait().unwrap());
+
+        // Swapping out a missing head does nothing.
+        assert!(!heads.swap(&foo, &baz).wait().unwrap());
+        assert!(!heads.is_head(&baz).wait().unwrap());
+        assert_eq!(heads.heads().collect().wait().unwrap(), vec![bar.clone()]);
+
+        // Swapping a head with itself keeps it.
+        assert!(heads.swap(&bar, &bar).wait().unwrap());
+        assert!(heads.is_head(&bar).wait().unwrap());
+        assert!(!heads.swap(&foo, &foo).wait().unwrap());
+        assert_eq!(heads.heads().collect().wait().unwrap(), vec![bar]);
+    }
+
+    #[test]
+    fn rename() {
+        let tmp = TempDir::new("filebookmarks_heads_rename").unwrap();
+        let heads = FileHeads::open(tmp.path()).unwrap();
+        let foo = "foo".to_string();
+        let bar = "bar".to_string();
+        let baz = "baz".to_string();
+
+        heads.add(&foo).wait().unwrap();
+        assert!(heads.rename(&foo, &bar, false).wait().unwrap());
+        assert!(!heads.is_head(&foo).wait().unwrap());
+        assert!(heads.is_head(&bar).wait().unwrap());
+
+        // Renaming a missing head does nothing.
+        assert!(!heads.rename(&foo, &baz, false).wait().unwrap());
+        assert!(!heads.is_head(&baz).wait().unwrap());
+
+        // An existing target is only replaced when asked to.
+        heads.add(&baz).wait().unwrap();
+        match heads.rename(&bar, &baz, false).wait() {
+            Err(Error(ErrorKind::HeadExists(_), _)) => {}
+            res => panic!("unexpected result {:?}", res),
+        }
+        assert!(heads.is_head(&bar).wait().unwrap());
+
+        assert!(heads.rename(&bar, &baz, true).wait().unwrap());
+        assert_eq!(heads.heads().collect().wait().unwrap(), vec![baz]);
+    }
+
+    #[test]
+    fn rename_sharded() {
+        let tmp = TempDir::new("filebookmarks_heads_rename_sharded").unwrap();
+        let heads = FileHeads::open_sharded(tmp.path()).unwrap();
+        let foo = "foo".to_string();
+        let bar = "bar".to_string();
+
+        heads.add(&foo).wait().unwrap();
+        assert!(heads.rename(&foo, &bar, false).wait().unwrap());
+        assert_eq!(heads.heads().collect().wait().unwrap(), vec![bar]);
+    }
+
+    #[test]
+    fn add_checked() {
+        let tmp = TempDir::new("filebookmarks_heads_add_checked").unwrap();
+        let heads = FileHeads::open(tmp.path()).unwrap();
+        let foo = "foo".to_string();
+
+        assert!(heads.add_checked(&foo).wait().unwrap());
+        assert!(heads.is_head(&foo).wait().unwrap());
+        assert!(!heads.add_checked(&foo).wait().unwrap());
+        assert_eq!(heads.heads().collect().wait().unwrap(), vec![foo.clone()]);
+
+        let sharded = TempDir::new("filebookmarks_heads_add_checked_sharded").unwrap();
+        let heads = FileHeads::open_sharded(sharded.path()).unwrap();
+        assert!(heads.add_checked(&foo).wait().unwrap());
+        assert!(!heads.add_checked(&foo).wait().unwrap());
+    }
+
+    #[test]
+    fn no_temp_files() {
+        let tmp = TempDir::new("filebookmarks_heads_no_temp_files").unwrap();
+        let heads = FileHeads::open(tmp.path()).unwrap();
+        let foo = "foo".to_string();
+        let bar = "bar".to_string();
+
+        heads.add(&foo).wait().unwrap();
+        heads.add_with_value(&bar, &42u32).wait().unwrap();
+        heads.add_with_value(&bar, &43u32).wait().unwrap();
+        heads.add_many(&[foo.clone(), bar.clone()]).wait().unwrap();
+
+        // Only the head files themselves are left.
+        let mut names: Vec<_> = fs::read_dir(tmp.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        names.sort();
+        assert_eq!(names, vec![format!("{}key=bar", PREFIX), format!("{}key=foo", PREFIX)]);
+        assert_eq!(heads.get_value::<u32>(&bar).wait().unwrap(), None);
+    }
+
+    #[test]
+    fn get_or_add_race() {
+        let tmp = TempDir::new("filebookmarks_heads_get_or_add_race").unwrap();
+        let pool = Arc::new(CpuPool::new(4));
+        let heads = FileHeads::open_with_pool(tmp.path(), pool).unwrap();
+
+        for i in 0..50 {
+            let key = format!("head{}", i);
+            // The adds all run on the pool at once, so they race each other.
+            let racers: Vec<_> = (0..4).map(|_| heads.get_or_add(&key)).collect();
+            let results = ::futures::future::join_all(racers).wait().unwrap();
+            assert_eq!(results.iter().filter(|added| **added).count(), 1, "{}", key);
+        }
+    }
+
+    #[test]
+    fn lock() {
+        let tmp = TempDir::new("filebookmarks_heads_lock").unwrap();
+        let heads = FileHeads::<String>::open(tmp.path()).unwrap();
+        let other = FileHeads::<String>::open_with_prefix(tmp.path(), "other:").unwrap();
+
+        let guard = heads.lock().wait().unwrap();
+        // The lock is on the directory, so it's held for every store using it.
+        assert!(heads.try_lock().wait().unwrap().is_none());
+        assert!(other.try_lock().wait().unwrap().is_none());
+        // The lock file isn't a head.
+        assert_eq!(heads.count().wait().unwrap(), 0);
+
+        drop(guard);
+        let guard = other.try_lock().wait().unwrap();
+        assert!(guard.is_some());
+        assert!(heads.try_lock().wait().unwrap().is_none());
+        drop(guard);
+
+        // A blocked `lock` waits until the guard is dropped.
+        let guard = heads.lock().wait().unwrap();
+        let (tx, rx) = std_mpsc::channel();
+        let waiter = heads.lock().map(move |guard| {
+            tx.send(()).unwrap();
+            guard
+        });
+        let waiter = thread::spawn(move || waiter.wait().map(|_| ()));
+        thread::sleep(Duration::from_millis(100));
+        assert!(rx.try_recv().is_err());
+        drop(guard);
+        waiter.join().unwrap().unwrap();
+        assert!(rx.try_recv().is_ok());
+    }
+
+    #[test]
+    fn oldest_by_mtime() {
+        let tmp = TempDir::new("filebookmarks_heads_oldest_by_mtime").unwrap();
+        let heads = FileHeads::open(tmp.path()).unwrap();
+        let keys: Vec<String> = (0..3).map(|i| format!("head{}", i)).collect();
+
+        // Give each head a later mtime than the one before, rewriting it until the filesystem's
+        // timestamps tell them apart.
+        for (i, key) in keys.iter().enumerate() {
+            heads.add(key).wait().unwrap();
+            while i > 0 && heads.modified(key).wait().unwrap() <=
+                heads.modified(&keys[i - 1]).wait().unwrap()
+            {
+                thread::sleep(Duration::from_millis(10));
+                heads.add(key).wait().unwrap();
+            }
+        }
+
+        let evicted = Arc::new(Mutex::new(Vec::new()));
+        let bounded = BoundedHeads::new(heads, 3, OldestByMtime).with_callback({
+            let evicted = evicted.clone();
+            move |key: &String| evicted.lock().unwrap().push(key.clone())
+        });
+        let new = "new".to_string();
+        bounded.add(&new).wait().unwrap();
+
+        assert_eq!(*evicted.lock().unwrap(), vec![keys[0].clone()]);
+        let mut present = bounded.heads().collect().wait().unwrap();
+        present.sort();
+        assert_eq!(present, vec![keys[1].clone(), keys[2].clone(), new]);
+    }
+
+    #[test]
+    fn remove_checked() {
+        let tmp = TempDir::new("filebookmarks_heads_remove_checked").unwrap();
+        let heads = FileHeads::open(tmp.path()).unwrap();
+        let foo = "foo".to_string();
+        let bar = "bar".to_string();
+
+        heads.add(&foo).wait().unwrap();
+        assert!(heads.remove_checked(&foo).wait().unwrap());
+        assert!(!heads.is_head(&foo).wait().unwrap());
+        assert!(!heads.remove_checked(&foo).wait().unwrap());
+
+        // Failures other than the head being absent are still reported.
+        fs::create_dir(heads.get_path(&bar).unwrap()).unwrap();
+        assert!(heads.remove_checked(&bar).wait().is_err());
+    }
+
+    #[test]
+    fn modified() {
+        let tmp = TempDir::new("filebookmarks_heads_modified").unwrap();
+        let heads = FileHeads::open(tmp.path()).unwrap();
+        let foo = "foo".to_string();
+        let recent = |mtime: SystemTime| {
+            // The clock may have moved backwards since the file was written.
+            mtime.elapsed().map(|age| age < Duration::from_secs(60)).unwrap_or(true)
+        };
+
+        assert_eq!(heads.modified(&foo).wait().unwrap(), None);
+        heads.add(&foo).wait().unwrap();
+        assert!(recent(heads.modified(&foo).wait().unwrap().expect("no mtime")));
+
+        let listed = heads.heads_with_mtime().collect().wait().unwrap();
+        assert_eq!(listed.len(), 1);
+        assert_eq!(listed[0].0, foo);
+        assert!(recent(listed[0].1));
+    }
+
+    #[test]
+    fn values() {
+        #[derive(Debug, Deserialize, Eq, PartialEq, Serialize)]
+        struct Published {
+            phase: String,
+            time: u64,
+        }
+
+        let tmp = TempDir::new("filebookmarks_heads_values").unwrap();
+        let heads = FileHeads::open(tmp.path()).unwrap();
+        let foo = "foo".to_string();
+        let bar = "bar".to_string();
+        let value = Published {
+            phase: "public".to_string(),
+            time: 1500000000,
+        };
+
+        heads.add_with_value(&foo, &value).wait().unwrap();
+        assert!(heads.is_head(&foo).wait().unwrap());
+        assert_eq!(heads.get_value(&foo).wait().unwrap(), Some(value));
+
+        // Heads without a value, and missing heads, have no value.
+        heads.add(&bar).wait().unwrap();
+        assert_eq!(heads.get_value::<Published>(&bar).wait().unwrap(), None);
+        assert_eq!(heads.get_value::<Published>(&"baz".to_string()).wait().unwrap(), None);
+
+        // Adding the head again clears its value.
+        heads.add(&foo).wait().unwrap();
+        assert_eq!(heads.get_value::<Published>(&foo).wait().unwrap(), None);
+
+        let mut all = heads.heads().collect().wait().unwrap();
+        all.sort();
+        assert_eq!(all, vec![bar, foo]);
+    }
+
+    #[test]
+    fn limited() {
+        let tmp = TempDir::new("filebookmarks_heads_limited").unwrap();
+        let pool = Arc::new(CpuPool::new(8));
+        let heads = FileHeads::open_with_pool_and_limit(tmp.path(), pool, 2).unwrap();
+        let keys: Vec<String> = (0..100).map(|i| format!("head{}", i)).collect();
+
+        // Start every operation at once; they all complete despite the limit.
+        let adds: Vec<_> = keys.iter().map(|key| heads.add(key)).collect();
+        ::futures::future::join_all(adds).wait().unwrap();
+        let checks: Vec<_> = keys.iter().map(|key| heads.is_head(key)).collect();
+        assert!(::futures::future::join_all(checks).wait().unwrap().into_iter().all(|b| b));
+        assert_eq!(heads.count().wait().unwrap(), keys.len());
+
+        // Operations which haven't been polled don't hold up later ones.
+        let removes: Vec<_> = keys.iter().take(3).map(|key| heads.remove(key)).collect();
+        for remove in removes.into_iter().rev() {
+            remove.wait().unwrap();
+        }
+        assert_eq!(heads.count().wait().unwrap(), keys.len() - 3);
+    }
+
+    #[test]
+    fn snapshot() {
+        let tmp = TempDir::new("filebookmarks_heads_snapshot").unwrap();
+        let heads = FileHeads::open(tmp.path()).unwrap();
+        let keys: Vec<String> = (0..100).map(|i| format!("head{}", i)).collect();
+        heads.add_many(&keys).wait().unwrap();
+
+        // Add and remove other heads while the snapshot is taken.
+        let others: Vec<String> = (0..100).map(|i| format!("other{}", i)).collect();
+        let writer = heads.add_many(&others).and_then({
+            let heads = heads.clone();
+            let others = others.clone();
+            move |()| heads.remove_many(&others)
+        });
+        let mut snapshot = heads.snapshot().wait().unwrap();
+        writer.wait().unwrap();
+
+        // Every head which was present throughout is there exactly once.
+        snapshot.sort();
+        let len = snapshot.len();
+        snapshot.dedup();
+        assert_eq!(snapshot.len(), len);
+        for key in &keys {
+            assert!(snapshot.contains(key));
+        }
+        assert!(snapshot.iter().all(|key| keys.contains(key) || others.contains(key)));
+
+        let mut all = heads.snapshot().wait().unwrap();
+        all.sort();
+        let mut expected = keys.clone();
+        expected.sort();
+        assert_eq!(all, expected);
+    }
+
+    #[test]
+    fn path_for() {
+        let tmp = TempDir::new("filebookmarks_heads_path_for").unwrap();
+        let heads = FileHeads::open(tmp.path()).unwrap();
+        let foo = "foo".to_string();
+
+        let path = heads.path_for(&foo).unwrap();
+        let base = fs::canonicalize(tmp.path()).unwrap();
+        assert_eq!(path, base.join(format!("{}key=foo", PREFIX)));
+        assert!(!path.exists());
+        heads.add(&foo).wait().unwrap();
+        assert!(path.exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlinked_dir() {
+        use std::os::unix::fs::symlink;
+
+        let tmp = TempDir::new("filebookmarks_heads_symlinked_dir").unwrap();
+        let dir = tmp.path().join("heads");
+        let link = tmp.path().join("link");
+        fs::create_dir(&dir).unwrap();
+        symlink(&dir, &link).unwrap();
+
+        let direct = FileHeads::open(&dir).unwrap();
+        let linked = FileHeads::open(&link).unwrap();
+        let trailing = FileHeads::open(format!("{}/", link.display())).unwrap();
+        let foo = "foo".to_string();
+        direct.add(&foo).wait().unwrap();
+
+        for heads in &[&linked, &trailing] {
+            assert_eq!(heads.heads().collect().wait().unwrap(), vec![foo.clone()]);
+            assert_eq!(heads.path_for(&foo).unwrap(), direct.path_for(&foo).unwrap());
+        }
+
+        // A dangling symlink isn't a directory.
+        fs::remove_dir(&dir).unwrap();
+        assert!(FileHeads::<String>::open(&link).is_err());
+    }
+
+    #[test]
+    fn prefix() {
+        let tmp = TempDir::new("filebookmarks_heads_prefix").unwrap();
+        let public = FileHeads::open_with_prefix(tmp.path(), "public:").unwrap();
+        let draft = FileHeads::open_with_prefix(tmp.path(), "draft:").unwrap();
+        let foo = "foo".to_string();
+        let bar = "bar".to_string();
+
+        public.add(&foo).wait().unwrap();
+        draft.add(&bar).wait().unwrap();
+
+        assert!(public.is_head(&foo).wait().unwrap());
+        assert!(!public.is_head(&bar).wait().unwrap());
+        assert!(!draft.is_head(&foo).wait().unwrap());
+        assert_eq!(public.heads().collect().wait().unwrap(), vec![foo]);
+        assert_eq!(draft.heads().collect().wait().unwrap(), vec![bar]);
+        assert_eq!(draft.count().wait().unwrap(), 1);
+
+        assert!(FileHeads::<String>::open_with_prefix(tmp.path(), "").is_err());
+        assert!(FileHeads::<String>::open_with_prefix(tmp.path(), "a/b:").is_err());
+        assert!(FileHeads::<String>::open_with_prefix(tmp.path(), ".head:").is_err());
+        assert!(FileHeads::<String>::open_with_prefix(tmp.path(), "head").is_err());
+
+        // A prefix which starts with another one would make its heads visible to the other
+        // store, so it's rejected.
+        assert!(FileHeads::<String>::open_with_prefix(tmp.path(), "public:x").is_err());
+        assert!(FileHeads::<String>::open_with_prefix(tmp.path(), "public:x:").is_err());
+        let publicx = FileHeads::open_with_prefix(tmp.path(), "publicx:").unwrap();
+        publicx.add(&"baz".to_string()).wait().unwrap();
+        assert_eq!(public.count().wait().unwrap(), 1);
+    }
+
+    #[test]
+    fn clear() {
+        let tmp = TempDir::new("filebookmarks_heads_clear").unwrap();
+        let heads = FileHeads::open(tmp.path()).unwrap();
+        heads
+            .add_many(&["foo".to_string(), "bar".to_string()])
+            .wait()
+            .unwrap();
+        File::create(tmp.path().join("unrelated")).unwrap();
+
+        assert_eq!(heads.clear().wait().unwrap(), 2);
+        assert_eq!(heads.count().wait().unwrap(), 0);
+        assert!(tmp.path().join("unrelated").exists());
+        assert_eq!(heads.clear().wait().unwrap(), 0);
+    }
+
+    #[test]
+    fn transaction() {
+        let tmp = TempDir::new("filebookmarks_heads_transaction").unwrap();
+        let heads = FileHeads::open(tmp.path()).unwrap();
+        let foo = "foo".to_string();
+        let bar = "bar".to_string();
+        let baz = "baz".to_string();
+
+        heads.add(&foo).wait().unwrap();
+        heads
+            .transaction()
+            .add(bar.clone())
+            .remove(foo.clone())
+            .commit()
+            .wait()
+            .unwrap();
+        assert_eq!(heads.heads().collect().wait().unwrap(), vec![bar.clone()]);
+
+        // Make removing `baz` fail by putting a directory where its file would be.
+        fs::create_dir(heads.path_for(&baz).unwrap()).unwrap();
+        let res = heads
+            .transaction()
+            .add(foo.clone())
+            .remove(bar.clone())
+            .remove(baz.clone())
+            .commit()
+            .wait();
+        assert!(res.is_err());
+
+        // Nothing from the failed transaction is left behind.
+        assert!(!heads.is_head(&foo).wait().unwrap());
+        assert!(heads.is_head(&bar).wait().unwrap());
+    }
+
+    // Check that a failed transaction through `heads`, which wraps `inner`, is rolled back.
+    fn check_rollback<H: Heads<Key = String>>(heads: &H, inner: &FileHeads<String>) {
+        let foo = "foo".to_string();
+        let bar = "bar".to_string();
+        let baz = "baz".to_string();
+        inner.add(&bar).wait().unwrap();
+        fs::create_dir(inner.path_for(&baz).unwrap()).unwrap();
+
+        let res = heads
+            .transaction()
+            .add(foo.clone())
+            .remove(bar.clone())
+            .remove(baz.clone())
+            .commit()
+            .wait();
+        assert!(res.is_err());
+        assert!(!inner.is_head(&foo).wait().unwrap());
+        assert!(inner.is_head(&bar).wait().unwrap());
+    }
+
+    #[test]
+    fn wrapped_transaction() {
+        let tmp = TempDir::new("filebookmarks_heads_wrapped_transaction").unwrap();
+        let create = |name: &str| FileHeads::<String>::create(tmp.path().join(name)).unwrap();
+
+        let inner = create("cached");
+        check_rollback(&CachedHeads::new(inner.clone(), 10), &inner);
+        let inner = create("instrumented");
+        check_rollback(&InstrumentedHeads::noop(inner.clone()), &inner);
+        let inner = create("union");
+        check_rollback(
+            &UnionHeads::new(create("other"), inner.clone(), Primary::Second),
+            &inner,
+        );
+        let inner = create("retrying");
+        let policy = Backoff::new(3, Duration::from_millis(1), |_: &Error| true);
+        check_rollback(&RetryingHeads::new(inner.clone(), policy), &inner);
+        let inner = create("bounded");
+        check_rollback(&BoundedHeads::new(inner.clone(), 10, InsertionOrder::new()), &inner);
+    }
+
+    #[test]
+    fn transaction_values() {
+        let tmp = TempDir::new("filebookmarks_heads_transaction_values").unwrap();
+        let heads = FileHeads::open(tmp.path()).unwrap();
+        let foo = "foo".to_string();
+        let bar = "bar".to_string();
+        let baz = "baz".to_string();
+        heads.add_with_value(&foo, &1u64).wait().unwrap();
+        heads.add_with_value(&bar, &2u64).wait().unwrap();
+
+        // Adding an existing head keeps its value.
+        heads.transaction().add(foo.clone()).commit().wait().unwrap();
+        assert_eq!(heads.get_value::<u64>(&foo).wait().unwrap(), Some(1));
+
+        // Rolling back a removal restores the value.
+        fs::create_dir(heads.path_for(&baz).unwrap()).unwrap();
+        let res = heads
+            .transaction()
+            .remove(bar.clone())
+            .remove(baz.clone())
+            .commit()
+            .wait();
+        assert!(res.is_err());
+        assert_eq!(heads.get_value::<u64>(&bar).wait().unwrap(), Some(2));
+    }
+
+    #[test]
+    fn add_stream() {
+        let tmp = TempDir::new("filebookmarks_heads_add_stream").unwrap();
+        let heads = FileHeads::open(tmp.path()).unwrap();
+        let keys: Vec<String> = (0..100).map(|i| format!("head{}", i)).collect();
+
+        let count = heads
+            .add_stream(stream::iter(keys.clone().into_iter().map(Ok)))
+            .wait()
+            .unwrap();
+        assert_eq!(count, keys.len());
+        for key in &keys {
+            assert!(heads.is_head(key).wait().unwrap());
+        }
+    }
+
+    #[test]
+    fn case_sensitive() {
+        let tmp = TempDir::new("filebookmarks_heads_case_sensitive").unwrap();
+        // Whatever the answer, probing shouldn't leave anything behind.
+        let sensitive = is_case_sensitive(tmp.path()).unwrap();
+        assert_eq!(fs::read_dir(tmp.path()).unwrap().count(), 0);
+
+        let heads = FileHeads::<String>::open_case_sensitive(tmp.path());
+        assert_eq!(heads.is_ok(), sensitive);
+    }
+
+    #[test]
+    fn invalid_dir() {
+        let tmp = TempDir::new("filebookmarks_heads_invalid_dir").unwrap();
+        let path = tmp.path().join("does_not_exist");
+        match FileHeads::<String>::open(&path) {
+            Err(Error(ErrorKind::NotADirectory(ref bad), _)) => assert_eq!(bad, &path),
+            Err(e) => panic!("unexpected error {:?}", e),
+            Ok(_) => panic!("unexpected success"),
+        }
+    }
+
+    #[test]
+    fn savenodehash() {
+        let tmp = TempDir::new("filebookmarks_heads_nod").unwrap();
+        {
+            let h = (0..40).map(|_| "a").collect::<String>();
+            let head = NodeHash::new(Sha1::from_str(h.as_str()).unwrap());
+            let heads = FileHeads::<NodeHash>::open(tmp.path()).unwrap();
+            heads.add(&head).wait().unwrap();
+            let mut result = heads.heads().collect().wait().unwrap();
+            result.sort();
+            assert_eq!(result, vec![head]);
+        }
+    }
+
+    #[test]
+    fn hex_codec() {
+        let tmp = TempDir::new("filebookmarks_heads_hex_codec").unwrap();
+        let hex = "33d68faa8d39ec00ce3850870c4e3ee6c8493e4b";
+        let head = NodeHash::from_str(hex).unwrap();
+        let heads = FileHeads::<NodeHash>::open(tmp.path())
+            .unwrap()
+            .with_codec::<HexCodec>();
+
+        heads.add(&head).wait().unwrap();
+        assert!(tmp.path().join(format!("{}{}", PREFIX, hex)).exists());
+        assert!(heads.is_head(&head).wait().unwrap());
+        assert_eq!(heads.heads().collect().wait().unwrap(), vec![head]);
+
+        // Names which aren't exactly what the codec produces are rejected.
+        assert!(HexCodec::decode(&hex.to_uppercase()).is_err());
+        assert!(HexCodec::decode(&hex[..39]).is_err());
+        assert!(HexCodec::decode(&format!("key={}", hex)).is_err());
+    }
+
+    #[test]
+    fn nodehash_filename() {
+        let tmp = TempDir::new("filebookmarks_heads_nodehash_filename").unwrap();
+        let hex = "33d68faa8d39ec00ce3850870c4e3ee6c8493e4b";
+        let head = NodeHash::from_str(hex).unwrap();
+        let heads = FileHeads::<NodeHash>::open(tmp.path()).unwrap();
+
+        heads.add(&head).wait().unwrap();
+        assert!(tmp.path().join(format!("{}key={}", PREFIX, hex)).exists());
+
+        // Head files which don't use the canonical lowercase form are skipped.
+        let upper = format!("{}key={}", PREFIX, hex.to_uppercase());
+        File::create(tmp.path().join(&upper)).unwrap();
+
+        let skipped = Arc::new(Mutex::new(Vec::new()));
+        let result = {
+            let skipped = skipped.clone();
+            heads
+                .heads_lenient(move |name, _| skipped.lock().unwrap().push(name))
+                .collect()
+                .wait()
+                .unwrap()
+        };
+        assert_eq!(result, vec![head]);
+        assert_eq!(*skipped.lock().unwrap(), vec![upper]);
     }
 }
@@ -0,0 +1,243 @@
+// Copyright (c) 2004-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Limiting how many head IO tasks run on the thread pool at once.
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+use futures::{Async, Future, Poll};
+use futures::future::{self, BoxFuture};
+use futures::sync::oneshot;
+use futures_cpupool::CpuPool;
+
+/// Spawns tasks on a thread pool, optionally allowing only a limited number of them to be
+/// queued or running at any time.
+#[derive(Clone)]
+pub struct Spawner {
+    pool: Arc<CpuPool>,
+    limit: Option<Arc<Semaphore>>,
+}
+
+impl Spawner {
+    /// Panics if `limit` is `Some(0)`, since no task could ever run.
+    pub fn new(pool: Arc<CpuPool>, limit: Option<usize>) -> Self {
+        assert!(limit != Some(0), "limit must be at least 1");
+        Spawner {
+            pool: pool,
+            limit: limit.map(Semaphore::new),
+        }
+    }
+
+    /// Run `future` on the pool. With a limit, the task isn't spawned until a permit is
+    /// available, so nothing happens until the returned future is polled.
+    pub fn spawn<F>(&self, future: F) -> BoxFuture<F::Item, F::Error>
+    where
+        F: Future + Send + 'static,
+        F::Item: Send + 'static,
+        F::Error: Send + 'static,
+    {
+        match self.limit {
+            None => self.pool.spawn(future).boxed(),
+            Some(ref limit) => {
+                let pool = self.pool.clone();
+                let limit = limit.clone();
+                // Ask for the permit on the first poll rather than now, so that futures which
+                // are never polled don't hold permits the others are waiting for.
+                future::lazy(move || Semaphore::acquire(&limit))
+                    .map_err(|_| unreachable!("semaphore dropped while waiting for a permit"))
+                    .and_then(move |permit| {
+                        // Release the permit as soon as the task is done, whether or not
+                        // anyone is waiting for the result.
+                        pool.spawn(future.then(move |res| {
+                            drop(permit);
+                            res
+                        }))
+                    })
+                    .boxed()
+            }
+        }
+    }
+}
+
+/// A counting semaphore whose permits are handed to waiters in the order they asked.
+pub struct Semaphore {
+    state: Mutex<State>,
+}
+
+struct State {
+    permits: usize,
+    waiters: VecDeque<oneshot::Sender<Permit>>,
+}
+
+impl Semaphore {
+    pub fn new(permits: usize) -> Arc<Self> {
+        Arc::new(Semaphore {
+            state: Mutex::new(State {
+                permits: permits,
+                waiters: VecDeque::new(),
+            }),
+        })
+    }
+
+    /// Wait for a permit, which is returned when it's dropped.
+    pub fn acquire(sem: &Arc<Self>) -> Acquire {
+        let mut state = sem.state.lock().expect("lock poisoned");
+        if state.permits > 0 {
+            state.permits -= 1;
+            Acquire::Ready(Some(Permit(Some(sem.clone()))))
+        } else {
+            let (tx, rx) = oneshot::channel();
+            state.waiters.push_back(tx);
+            Acquire::Waiting(rx)
+        }
+    }
+
+    // Hand a returned permit to the first waiter which is still interested, or put it back.
+    fn release(sem: &Arc<Self>) {
+        loop {
+            let waiter = {
+                let mut state = sem.state.lock().expect("lock poisoned");
+                match state.waiters.pop_front() {
+                    Some(waiter) => waiter,
+                    None => {
+                        state.permits += 1;
+                        return;
+                    }
+                }
+            };
+            match waiter.send(Permit(Some(sem.clone()))) {
+                Ok(()) => return,
+                // The waiter has gone away. Dropping the permit would release it again, so
+                // take the semaphore out of it first and try the next waiter instead.
+                Err(mut permit) => {
+                    permit.0.take();
+                }
+            }
+        }
+    }
+}
+
+/// Permission to run one task, given back to the `Semaphore` on drop.
+pub struct Permit(Option<Arc<Semaphore>>);
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        if let Some(sem) = self.0.take() {
+            Semaphore::release(&sem);
+        }
+    }
+}
+
+/// Future returned by `Semaphore::acquire`.
+#[must_use = "futures do nothing unless polled"]
+pub enum Acquire {
+    Ready(Option<Permit>),
+    Waiting(oneshot::Receiver<Permit>),
+}
+
+impl Future for Acquire {
+    type Item = Permit;
+    type Error = oneshot::Canceled;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        match self {
+            &mut Acquire::Ready(ref mut permit) => {
+                Ok(Async::Ready(permit.take().expect("polled after completion")))
+            }
+            &mut Acquire::Waiting(ref mut rx) => rx.poll(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::cmp;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+    use std::time::Duration;
+
+    use futures::future::{join_all, poll_fn};
+
+    #[test]
+    fn semaphore() {
+        let sem = Semaphore::new(1);
+        let first = Semaphore::acquire(&sem).wait().unwrap();
+        let second = Semaphore::acquire(&sem);
+        let third = Semaphore::acquire(&sem);
+        match (&second, &third) {
+            (&Acquire::Waiting(_), &Acquire::Waiting(_)) => (),
+            _ => panic!("permit handed out twice"),
+        }
+
+        // The permit skips the waiter which gave up.
+        drop(second);
+        drop(first);
+        let third = third.wait().unwrap();
+        match Semaphore::acquire(&sem) {
+            Acquire::Waiting(_) => (),
+            Acquire::Ready(_) => panic!("permit handed out twice"),
+        }
+
+        drop(third);
+        match Semaphore::acquire(&sem) {
+            Acquire::Ready(_) => (),
+            Acquire::Waiting(_) => panic!("permit not returned"),
+        }
+
+        // The permit offered to the waiter which gave up didn't keep the semaphore alive.
+        assert_eq!(Arc::strong_count(&sem), 1);
+    }
+
+    #[test]
+    fn limit() {
+        let pool = Arc::new(CpuPool::new(8));
+        let running = Arc::new(AtomicUsize::new(0));
+        let max = Arc::new(Mutex::new(0));
+
+        let spawner = Spawner::new(pool, Some(2));
+        let tasks: Vec<_> = (0..20)
+            .map(|_| {
+                let running = running.clone();
+                let max = max.clone();
+                spawner.spawn(poll_fn(move || {
+                    let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                    {
+                        let mut max = max.lock().unwrap();
+                        *max = cmp::max(*max, now);
+                    }
+                    thread::sleep(Duration::from_millis(10));
+                    running.fetch_sub(1, Ordering::SeqCst);
+                    Ok::<_, ()>(Async::Ready(()))
+                }))
+            })
+            .collect();
+        join_all(tasks).wait().unwrap();
+
+        assert_eq!(*max.lock().unwrap(), 2);
+    }
+
+    #[test]
+    #[should_panic]
+    fn zero_limit() {
+        Spawner::new(Arc::new(CpuPool::new(1)), Some(0));
+    }
+
+    #[test]
+    fn permit_on_poll() {
+        let pool = Arc::new(CpuPool::new(2));
+        let spawner = Spawner::new(pool, Some(2));
+        let tasks: Vec<_> = (0..3)
+            .map(|i| spawner.spawn(future::ok::<_, ()>(i)))
+            .collect();
+
+        // Creating the first two doesn't use up the permits the last one needs.
+        for (i, task) in tasks.into_iter().enumerate().rev() {
+            assert_eq!(task.wait().unwrap(), i);
+        }
+    }
+}
@@ -0,0 +1,197 @@
+// Copyright (c) 2004-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Keeping several sets of heads, such as bookmarks and branch heads, in one store.
+
+use std::result;
+
+use futures::future::{BoxFuture, Future};
+use futures::stream::{BoxStream, Stream};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde::de::{DeserializeOwned, Error as DeError};
+use serde::ser::Error as SerError;
+
+use heads::Heads;
+
+use {FileHeads, HeadKeyCodec, decode_key, encode_key};
+use errors::*;
+
+// Separates the namespace from the key in a serialized `Namespaced`.
+const NS_SEPARATOR: char = ':';
+
+/// A key tagged with the namespace it belongs to.
+///
+/// It serializes as a string of the namespace followed by the key encoded with `encode_key`,
+/// so it can be stored by any head store which accepts serializable keys.
+#[derive(Clone, Debug, Eq, PartialEq, Hash)]
+pub struct Namespaced<T> {
+    pub ns: String,
+    pub key: T,
+}
+
+impl<T> Namespaced<T> {
+    pub fn new<S: Into<String>>(ns: S, key: T) -> Self {
+        Namespaced {
+            ns: ns.into(),
+            key: key,
+        }
+    }
+}
+
+impl<T: Serialize> Serialize for Namespaced<T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> result::Result<S::Ok, S::Error> {
+        if self.ns.contains(NS_SEPARATOR) {
+            return Err(S::Error::custom(ErrorKind::InvalidNamespace(self.ns.clone())));
+        }
+        let key = encode_key(&self.key).map_err(S::Error::custom)?;
+        serializer.serialize_str(&format!("{}{}{}", self.ns, NS_SEPARATOR, key))
+    }
+}
+
+impl<'de, T: DeserializeOwned> Deserialize<'de> for Namespaced<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> result::Result<Self, D::Error> {
+        let tagged = String::deserialize(deserializer)?;
+        let pos = match tagged.find(NS_SEPARATOR) {
+            Some(pos) => pos,
+            None => {
+                return Err(D::Error::custom(
+                    format!("'{}' is missing a namespace", tagged),
+                ))
+            }
+        };
+        let key = decode_key(&tagged[pos + 1..]).map_err(D::Error::custom)?;
+        Ok(Namespaced::new(&tagged[..pos], key))
+    }
+}
+
+impl<T, C> FileHeads<Namespaced<T>, C>
+where
+    T: Send + 'static,
+    C: HeadKeyCodec<Namespaced<T>>,
+{
+    /// Return a view of the heads in namespace `ns`, whose keys are the plain keys within the
+    /// namespace. Heads in other namespaces can't be seen or changed through it.
+    pub fn namespace(&self, ns: &str) -> Result<NamespacedHeads<T, C>> {
+        if ns.contains(NS_SEPARATOR) {
+            bail!(ErrorKind::InvalidNamespace(ns.to_string()));
+        }
+        Ok(NamespacedHeads {
+            heads: self.clone(),
+            ns: ns.to_string(),
+        })
+    }
+}
+
+/// The heads in a single namespace of a `FileHeads` store, made by `FileHeads::namespace`.
+pub struct NamespacedHeads<T, C> {
+    heads: FileHeads<Namespaced<T>, C>,
+    ns: String,
+}
+
+impl<T, C> NamespacedHeads<T, C> {
+    pub fn ns(&self) -> &str {
+        &self.ns
+    }
+
+    fn tag(&self, key: &T) -> Namespaced<T>
+    where
+        T: Clone,
+    {
+        Namespaced::new(self.ns.as_str(), key.clone())
+    }
+}
+
+// Implemented by hand since deriving would require `T: Clone`.
+impl<T, C> Clone for NamespacedHeads<T, C> {
+    fn clone(&self) -> Self {
+        NamespacedHeads {
+            heads: self.heads.clone(),
+            ns: self.ns.clone(),
+        }
+    }
+}
+
+impl<T, C> Heads for NamespacedHeads<T, C>
+where
+    T: Clone + Send + 'static,
+    C: HeadKeyCodec<Namespaced<T>>,
+{
+    type Key = T;
+    type Error = Error;
+
+    type Unit = BoxFuture<(), Self::Error>;
+    type Bool = BoxFuture<bool, Self::Error>;
+    type Heads = BoxStream<Self::Key, Self::Error>;
+
+    fn add(&self, key: &Self::Key) -> Self::Unit {
+        self.heads.add(&self.tag(key))
+    }
+
+    fn remove(&self, key: &Self::Key) -> Self::Unit {
+        self.heads.remove(&self.tag(key))
+    }
+
+    fn is_head(&self, key: &Self::Key) -> Self::Bool {
+        self.heads.is_head(&self.tag(key))
+    }
+
+    /// The whole store is listed, and heads in other namespaces are skipped.
+    fn heads(&self) -> Self::Heads {
+        let ns = self.ns.clone();
+        self.heads
+            .heads()
+            .filter_map(move |tagged| if tagged.ns == ns {
+                Some(tagged.key)
+            } else {
+                None
+            })
+            .boxed()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tempdir::TempDir;
+
+    #[test]
+    fn namespaces() {
+        let tmp = TempDir::new("filebookmarks_heads_namespaces").unwrap();
+        let heads = FileHeads::<Namespaced<String>>::open(tmp.path()).unwrap();
+        let bookmarks = heads.namespace("bookmarks").unwrap();
+        let branches = heads.namespace("branches").unwrap();
+        let foo = "foo".to_string();
+        let bar = "bar:baz".to_string();
+
+        bookmarks.add(&foo).wait().unwrap();
+        branches.add(&bar).wait().unwrap();
+
+        assert!(bookmarks.is_head(&foo).wait().unwrap());
+        assert!(!bookmarks.is_head(&bar).wait().unwrap());
+        assert!(!branches.is_head(&foo).wait().unwrap());
+        assert_eq!(bookmarks.heads().collect().wait().unwrap(), vec![foo.clone()]);
+        assert_eq!(branches.heads().collect().wait().unwrap(), vec![bar.clone()]);
+
+        // Removing a key from one namespace leaves the same key in another alone.
+        branches.add(&foo).wait().unwrap();
+        bookmarks.remove(&foo).wait().unwrap();
+        assert!(branches.is_head(&foo).wait().unwrap());
+        assert_eq!(bookmarks.heads().collect().wait().unwrap(), Vec::<String>::new());
+
+        // The underlying store sees every namespace.
+        let mut all = heads.heads().collect().wait().unwrap();
+        all.sort_by(|a, b| a.key.cmp(&b.key));
+        assert_eq!(
+            all,
+            vec![
+                Namespaced::new("branches", bar.clone()),
+                Namespaced::new("branches", foo.clone()),
+            ]
+        );
+
+        assert!(heads.namespace("a:b").is_err());
+    }
+}
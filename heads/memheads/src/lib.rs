@@ -12,28 +12,36 @@ extern crate error_chain;
 extern crate futures;
 
 use std::hash::Hash;
-use std::sync::Mutex;
+use std::sync::{Arc, Mutex};
 
-use futures::future::{FutureResult, ok};
+use futures::future::{BoxFuture, Future, FutureResult, ok};
 use futures::stream::{BoxStream, Stream, iter};
 use std::collections::HashSet;
 
-use heads::Heads;
+use heads::{HeadOp, Heads};
 
 mod errors {
     // Create Error, ErrorKind, ResultExt, and Result types.
-    error_chain!{}
+    error_chain!{
+        links {
+            Heads(::heads::Error, ::heads::ErrorKind);
+        }
+    }
 }
-use errors::*;
+pub use errors::*;
 
 /// Generic, in-memory heads store backed by a HashSet, intended to be used in tests.
+///
+/// Clones share the same underlying set. Listing heads takes a snapshot of the set, so
+/// concurrent modifications don't affect a listing in progress.
+#[derive(Clone)]
 pub struct MemHeads<T: Hash + Eq + Clone> {
-    heads: Mutex<HashSet<T>>,
+    heads: Arc<Mutex<HashSet<T>>>,
 }
 
 impl<T: Hash + Eq + Clone + Send> MemHeads<T> {
-    fn new() -> Self {
-        MemHeads { heads: Mutex::new(HashSet::new()) }
+    pub fn new() -> Self {
+        MemHeads { heads: Arc::new(Mutex::new(HashSet::new())) }
     }
 }
 
@@ -55,6 +63,12 @@ impl<T: Hash + Eq + Clone + Send + 'static> Heads for MemHeads<T> {
         ok(())
     }
 
+    /// The key is stored without being cloned.
+    fn add_owned(&self, head: Self::Key) -> Self::Unit {
+        self.heads.lock().unwrap().insert(head);
+        ok(())
+    }
+
     fn is_head(&self, head: &Self::Key) -> Self::Bool {
         ok(self.heads.lock().unwrap().contains(head))
     }
@@ -64,6 +78,18 @@ impl<T: Hash + Eq + Clone + Send + 'static> Heads for MemHeads<T> {
         let heads = (*guard).clone();
         iter(heads.into_iter().map(|head| Ok(head))).boxed()
     }
+
+    /// All operations are applied while holding the lock, so they're atomic.
+    fn apply(&self, ops: Vec<HeadOp<Self::Key>>) -> BoxFuture<(), Self::Error> {
+        let mut heads = self.heads.lock().unwrap();
+        for op in ops {
+            match op {
+                HeadOp::Add(head) => heads.insert(head),
+                HeadOp::Remove(head) => heads.remove(&head),
+            };
+        }
+        ok(()).boxed()
+    }
 }
 
 #[cfg(test)]
@@ -71,6 +97,7 @@ mod test {
     use super::*;
     use futures::Future;
     use futures::Stream;
+    use heads::diff_heads;
 
     #[test]
     fn test_heads() {
@@ -93,6 +120,7 @@ mod test {
         result.sort();
 
         assert_eq!(result, vec!["bar", "foo"]);
+        assert_eq!(heads.count().wait().unwrap(), 2);
 
         heads.remove(&"foo").wait().unwrap();
         heads.remove(&"bar").wait().unwrap();
@@ -100,4 +128,62 @@ mod test {
 
         assert_eq!(heads.heads().collect().wait().unwrap(), empty);
     }
+
+    #[test]
+    fn test_owned() {
+        let heads = MemHeads::new();
+        heads.add_owned("foo".to_string()).wait().unwrap();
+        heads.add_owned("bar".to_string()).wait().unwrap();
+        assert!(heads.is_head(&"foo".to_string()).wait().unwrap());
+
+        heads.remove_owned("foo".to_string()).wait().unwrap();
+        assert_eq!(heads.heads().collect().wait().unwrap(), vec!["bar".to_string()]);
+    }
+
+    #[test]
+    fn test_transaction() {
+        let heads = MemHeads::new();
+        heads.add(&"foo").wait().unwrap();
+        heads
+            .transaction()
+            .add("bar")
+            .remove("foo")
+            .commit()
+            .wait()
+            .unwrap();
+        assert_eq!(heads.heads().collect().wait().unwrap(), vec!["bar"]);
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let heads = MemHeads::new();
+        heads.add(&"foo").wait().unwrap();
+
+        let listing = heads.heads();
+        // Clones share state, and changes after listing starts aren't observed by it.
+        heads.clone().add(&"bar").wait().unwrap();
+        assert_eq!(listing.collect().wait().unwrap(), vec!["foo"]);
+        assert!(heads.is_head(&"bar").wait().unwrap());
+    }
+
+    #[test]
+    fn test_diff() {
+        let primary = MemHeads::new();
+        let replica = MemHeads::new();
+        for head in &["foo", "bar", "baz"] {
+            primary.add(head).wait().unwrap();
+        }
+        for head in &["bar", "baz", "qux"] {
+            replica.add(head).wait().unwrap();
+        }
+
+        let (only_primary, only_replica) = diff_heads(&primary, &replica).wait().unwrap();
+        assert_eq!(only_primary, vec!["foo"]);
+        assert_eq!(only_replica, vec!["qux"]);
+
+        replica.add(&"foo").wait().unwrap();
+        replica.remove(&"qux").wait().unwrap();
+        let empty: Vec<&str> = Vec::new();
+        assert_eq!(diff_heads(&primary, &replica).wait().unwrap(), (empty.clone(), empty));
+    }
 }
@@ -0,0 +1,273 @@
+// Copyright (c) 2004-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+#![deny(warnings)]
+
+extern crate fileheads;
+extern crate heads;
+
+#[macro_use]
+extern crate error_chain;
+extern crate futures;
+extern crate futures_cpupool;
+extern crate rocksdb;
+extern crate serde;
+#[cfg(test)]
+extern crate tempdir;
+
+use std::marker::PhantomData;
+use std::path::Path;
+use std::str;
+use std::sync::Arc;
+
+use futures::Async;
+use futures::future::{BoxFuture, Future, IntoFuture, poll_fn};
+use futures::stream::{self, BoxStream, Stream};
+use futures_cpupool::CpuPool;
+use rocksdb::{ColumnFamily, Db, ReadOptions, WriteOptions};
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+
+use fileheads::{decode_key, encode_key};
+use heads::Heads;
+
+mod errors {
+    error_chain!{
+        errors {
+            BadKey(key: Vec<u8>) {
+                description("stored key is not valid")
+                display("stored key is not valid: {:?}", key)
+            }
+            NoColumnFamily(name: &'static str) {
+                description("database has no heads column family")
+                display("database has no column family {:?}", name)
+            }
+        }
+
+        links {
+            FileHeads(::fileheads::Error, ::fileheads::ErrorKind);
+            Heads(::heads::Error, ::heads::ErrorKind);
+            RocksDB(::rocksdb::Error, ::rocksdb::ErrorKind);
+        }
+    }
+}
+pub use errors::*;
+
+// All heads are stored in this column family, keeping them apart from anything else in the
+// database.
+const COLUMN_FAMILY: &str = "heads";
+
+// Number of keys read from the database at a time when listing heads.
+const PAGE_SIZE: usize = 1000;
+
+/// A head store backed by a RocksDB database.
+///
+/// Each head is a single empty value in the `heads` column family, keyed by the head encoded
+/// the same way as `FileHeads` encodes them into file names. Reads and writes are dispatched
+/// to a thread pool to avoid blocking the main thread.
+pub struct RocksHeads<T> {
+    db: Db,
+    pool: Arc<CpuPool>,
+    _marker: PhantomData<T>,
+}
+
+impl<T: Serialize> RocksHeads<T> {
+    /// Open an existing database, which must have a `heads` column family.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::open_with_pool(path, Arc::new(CpuPool::new_num_cpus()))
+    }
+
+    pub fn open_with_pool<P: AsRef<Path>>(path: P, pool: Arc<CpuPool>) -> Result<Self> {
+        Self::open_with_options(path, rocksdb::Options::new(), pool)
+    }
+
+    /// Open a database, creating it and its `heads` column family if they don't already exist.
+    pub fn create<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::create_with_pool(path, Arc::new(CpuPool::new_num_cpus()))
+    }
+
+    pub fn create_with_pool<P: AsRef<Path>>(path: P, pool: Arc<CpuPool>) -> Result<Self> {
+        let opts = rocksdb::Options::new()
+            .create_if_missing(true)
+            .create_missing_column_families(true);
+        Self::open_with_options(path, opts, pool)
+    }
+
+    pub fn open_with_options<P: AsRef<Path>>(
+        path: P,
+        opts: rocksdb::Options,
+        pool: Arc<CpuPool>,
+    ) -> Result<Self> {
+        let db = Db::open_cf(path, opts, &["default", COLUMN_FAMILY])?;
+        heads_cf(&db)?;
+        Ok(RocksHeads {
+            db: db,
+            pool: pool,
+            _marker: PhantomData,
+        })
+    }
+
+    // Run `f` with the database, the heads column family and the database key for `key` on the
+    // thread pool.
+    fn with_key<F, R>(&self, key: &T, mut f: F) -> BoxFuture<R, Error>
+    where
+        F: FnMut(&Db, ColumnFamily, &[u8]) -> Result<R> + Send + 'static,
+        R: Send + 'static,
+    {
+        let pool = self.pool.clone();
+        let db = self.db.clone();
+        encode_key(key)
+            .map_err(Error::from)
+            .into_future()
+            .and_then(move |key| {
+                let future = poll_fn(move || {
+                    let cf = heads_cf(&db)?;
+                    Ok(Async::Ready(f(&db, cf, key.as_bytes())?))
+                });
+                pool.spawn(future)
+            })
+            .boxed()
+    }
+}
+
+// Return the handle of the column family the heads are stored in.
+fn heads_cf(db: &Db) -> Result<ColumnFamily> {
+    match db.cf_handle(COLUMN_FAMILY) {
+        Some(cf) => Ok(cf),
+        None => bail!(ErrorKind::NoColumnFamily(COLUMN_FAMILY)),
+    }
+}
+
+// Return up to `PAGE_SIZE` database keys which sort after `after`.
+fn read_page(db: &Db, after: &[u8]) -> Result<Vec<Vec<u8>>> {
+    let mut iter = db.iterator_cf(heads_cf(db)?, &ReadOptions::new());
+    iter.seek(after);
+
+    let mut page = Vec::new();
+    while iter.valid() && page.len() < PAGE_SIZE {
+        let key = iter.key();
+        if key != after {
+            page.push(key.to_vec());
+        }
+        iter.next();
+    }
+    iter.status()?;
+    Ok(page)
+}
+
+// Decode a database key back into a head.
+fn decode_dbkey<T: DeserializeOwned>(dbkey: &[u8]) -> Result<T> {
+    match str::from_utf8(dbkey) {
+        Ok(encoded) => Ok(decode_key(encoded)?),
+        Err(_) => Err(ErrorKind::BadKey(dbkey.to_vec()).into()),
+    }
+}
+
+impl<T> Heads for RocksHeads<T>
+where
+    T: Serialize + DeserializeOwned + Send + 'static,
+{
+    type Key = T;
+    type Error = Error;
+
+    type Unit = BoxFuture<(), Self::Error>;
+    type Bool = BoxFuture<bool, Self::Error>;
+    type Heads = BoxStream<Self::Key, Self::Error>;
+
+    fn add(&self, key: &Self::Key) -> Self::Unit {
+        self.with_key(key, |db, cf, key| {
+            db.put_cf(cf, key, b"", &WriteOptions::new())?;
+            Ok(())
+        })
+    }
+
+    fn remove(&self, key: &Self::Key) -> Self::Unit {
+        self.with_key(key, |db, cf, key| {
+            db.delete_cf(cf, key, &WriteOptions::new())?;
+            Ok(())
+        })
+    }
+
+    fn is_head(&self, key: &Self::Key) -> Self::Bool {
+        self.with_key(key, |db, cf, key| {
+            Ok(db.get_cf(cf, key, &ReadOptions::new())?.is_some())
+        })
+    }
+
+    /// Heads are read from the column family a page at a time, in key order.
+    fn heads(&self) -> Self::Heads {
+        let pool = self.pool.clone();
+        let db = self.db.clone();
+        stream::unfold(Some(Vec::new()), move |after| {
+            after.map(|after| {
+                let db = db.clone();
+                let future = poll_fn(move || {
+                    let page = read_page(&db, &after)?;
+                    let next = if page.len() < PAGE_SIZE {
+                        None
+                    } else {
+                        page.last().cloned()
+                    };
+                    Ok(Async::Ready((page, next)))
+                });
+                pool.spawn(future)
+            })
+        }).map(|page| stream::iter(page.into_iter().map(|key| decode_dbkey(&key))))
+            .flatten()
+            .boxed()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tempdir::TempDir;
+
+    #[test]
+    fn basic() {
+        let tmp = TempDir::new("rocksheads_basic").unwrap();
+        let path = tmp.path().join("heads");
+        let foo = "foo".to_string();
+        let bar = "bar".to_string();
+        let baz = "baz".to_string();
+
+        {
+            let heads = RocksHeads::create(&path).unwrap();
+            assert_eq!(heads.heads().collect().wait().unwrap(), Vec::<String>::new());
+
+            heads.add(&foo).wait().unwrap();
+            heads.add(&bar).wait().unwrap();
+            heads.add(&bar).wait().unwrap(); // Adding an existing head is fine.
+
+            assert!(heads.is_head(&foo).wait().unwrap());
+            assert!(heads.is_head(&bar).wait().unwrap());
+            assert!(!heads.is_head(&baz).wait().unwrap());
+
+            heads.remove(&foo).wait().unwrap();
+            heads.remove(&baz).wait().unwrap();
+            assert!(!heads.is_head(&foo).wait().unwrap());
+        }
+
+        let heads = RocksHeads::<String>::open(&path).unwrap();
+        assert_eq!(heads.heads().collect().wait().unwrap(), vec![bar]);
+    }
+
+    #[test]
+    fn encoding() {
+        let tmp = TempDir::new("rocksheads_encoding").unwrap();
+        let heads = RocksHeads::create(tmp.path()).unwrap();
+        let key = "a b/c".to_string();
+        heads.add(&key).wait().unwrap();
+
+        // Keys are stored in the heads column family with the same encoding FileHeads uses.
+        let dbkey = encode_key(&key).unwrap();
+        let cf = heads_cf(&heads.db).unwrap();
+        let stored = heads.db.get_cf(cf, dbkey.as_bytes(), &ReadOptions::new()).unwrap();
+        assert!(stored.is_some());
+        assert!(heads.db.get(dbkey.as_bytes(), &ReadOptions::new()).unwrap().is_none());
+        assert_eq!(heads.heads().collect().wait().unwrap(), vec![key]);
+    }
+}
@@ -0,0 +1,235 @@
+// Copyright (c) 2004-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+#![deny(warnings)]
+
+extern crate fileheads;
+extern crate heads;
+
+#[macro_use]
+extern crate error_chain;
+extern crate futures;
+extern crate futures_cpupool;
+extern crate rusqlite;
+extern crate serde;
+#[cfg(test)]
+extern crate tempdir;
+
+use std::marker::PhantomData;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use futures::Async;
+use futures::future::{BoxFuture, Future, IntoFuture, poll_fn};
+use futures::stream::{self, BoxStream, Stream};
+use futures_cpupool::CpuPool;
+use rusqlite::Connection;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+
+use fileheads::{decode_key, encode_key};
+use heads::Heads;
+
+mod errors {
+    error_chain!{
+        links {
+            FileHeads(::fileheads::Error, ::fileheads::ErrorKind);
+            Heads(::heads::Error, ::heads::ErrorKind);
+        }
+
+        foreign_links {
+            Sqlite(::rusqlite::Error);
+        }
+    }
+}
+pub use errors::*;
+
+// Number of keys fetched from the database at a time when listing heads.
+const PAGE_SIZE: i64 = 1000;
+
+/// A head store backed by a SQLite database.
+///
+/// Keys are stored in a single table, encoded the same way as `FileHeads` encodes them into
+/// file names. Queries are dispatched to a thread pool to avoid blocking the main thread.
+pub struct SqliteHeads<T> {
+    conn: Arc<Mutex<Connection>>,
+    pool: Arc<CpuPool>,
+    _marker: PhantomData<T>,
+}
+
+impl<T: Serialize> SqliteHeads<T> {
+    /// Open an existing database, which must have been set up by `create`.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::open_with_pool(path, Arc::new(CpuPool::new_num_cpus()))
+    }
+
+    pub fn open_with_pool<P: AsRef<Path>>(path: P, pool: Arc<CpuPool>) -> Result<Self> {
+        let conn = Connection::open(path)?;
+        Ok(SqliteHeads {
+            conn: Arc::new(Mutex::new(conn)),
+            pool: pool,
+            _marker: PhantomData,
+        })
+    }
+
+    /// Open a database, creating it and the heads table if they don't already exist.
+    pub fn create<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::create_with_pool(path, Arc::new(CpuPool::new_num_cpus()))
+    }
+
+    pub fn create_with_pool<P: AsRef<Path>>(path: P, pool: Arc<CpuPool>) -> Result<Self> {
+        let heads = Self::open_with_pool(path, pool)?;
+        heads.conn.lock().expect("lock poisoned").execute_batch(
+            "CREATE TABLE IF NOT EXISTS heads (key TEXT NOT NULL);
+             CREATE UNIQUE INDEX IF NOT EXISTS heads_key ON heads (key);",
+        )?;
+        Ok(heads)
+    }
+
+    // Run `f` with the encoded key and the connection on the thread pool.
+    fn with_key<F, R>(&self, key: &T, mut f: F) -> BoxFuture<R, Error>
+    where
+        F: FnMut(&Connection, &str) -> Result<R> + Send + 'static,
+        R: Send + 'static,
+    {
+        let pool = self.pool.clone();
+        let conn = self.conn.clone();
+        encode_key(key)
+            .map_err(Error::from)
+            .into_future()
+            .and_then(move |key| {
+                let future = poll_fn(move || {
+                    let conn = conn.lock().expect("lock poisoned");
+                    Ok(Async::Ready(f(&conn, &key)?))
+                });
+                pool.spawn(future)
+            })
+            .boxed()
+    }
+}
+
+// Return up to `PAGE_SIZE` encoded keys which sort after `after`.
+fn read_page(conn: &Connection, after: &str) -> Result<Vec<String>> {
+    let mut stmt = conn.prepare("SELECT key FROM heads WHERE key > ?1 ORDER BY key LIMIT ?2")?;
+    let rows = stmt.query_map(&[&after, &PAGE_SIZE], |row| row.get(0))?;
+    let mut page = Vec::new();
+    for row in rows {
+        page.push(row?);
+    }
+    Ok(page)
+}
+
+impl<T> Heads for SqliteHeads<T>
+where
+    T: Serialize + DeserializeOwned + Send + 'static,
+{
+    type Key = T;
+    type Error = Error;
+
+    type Unit = BoxFuture<(), Self::Error>;
+    type Bool = BoxFuture<bool, Self::Error>;
+    type Heads = BoxStream<Self::Key, Self::Error>;
+
+    fn add(&self, key: &Self::Key) -> Self::Unit {
+        self.with_key(key, |conn, key| {
+            conn.execute("INSERT OR IGNORE INTO heads (key) VALUES (?1)", &[&key])?;
+            Ok(())
+        })
+    }
+
+    fn remove(&self, key: &Self::Key) -> Self::Unit {
+        self.with_key(key, |conn, key| {
+            conn.execute("DELETE FROM heads WHERE key = ?1", &[&key])?;
+            Ok(())
+        })
+    }
+
+    fn is_head(&self, key: &Self::Key) -> Self::Bool {
+        self.with_key(key, |conn, key| {
+            let exists: i64 = conn.query_row(
+                "SELECT EXISTS (SELECT 1 FROM heads WHERE key = ?1)",
+                &[&key],
+                |row| row.get(0),
+            )?;
+            Ok(exists != 0)
+        })
+    }
+
+    /// Heads are fetched from the database a page at a time, in key order.
+    fn heads(&self) -> Self::Heads {
+        let pool = self.pool.clone();
+        let conn = self.conn.clone();
+        stream::unfold(Some(String::new()), move |after| {
+            after.map(|after| {
+                let conn = conn.clone();
+                let future = poll_fn(move || {
+                    let page = read_page(&conn.lock().expect("lock poisoned"), &after)?;
+                    let next = if page.len() < PAGE_SIZE as usize {
+                        None
+                    } else {
+                        page.last().cloned()
+                    };
+                    Ok(Async::Ready((page, next)))
+                });
+                pool.spawn(future)
+            })
+        }).map(|page| {
+                let keys = page.into_iter().map(|key| decode_key(&key).map_err(Error::from));
+                stream::iter(keys)
+            })
+            .flatten()
+            .boxed()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tempdir::TempDir;
+
+    #[test]
+    fn basic() {
+        let tmp = TempDir::new("sqliteheads_basic").unwrap();
+        let path = tmp.path().join("heads.db");
+        let foo = "foo".to_string();
+        let bar = "bar".to_string();
+        let baz = "baz".to_string();
+
+        {
+            let heads = SqliteHeads::create(&path).unwrap();
+            assert_eq!(heads.heads().collect().wait().unwrap(), Vec::<String>::new());
+
+            heads.add(&foo).wait().unwrap();
+            heads.add(&bar).wait().unwrap();
+            heads.add(&bar).wait().unwrap(); // Adding an existing head is fine.
+
+            assert!(heads.is_head(&foo).wait().unwrap());
+            assert!(heads.is_head(&bar).wait().unwrap());
+            assert!(!heads.is_head(&baz).wait().unwrap());
+
+            heads.remove(&foo).wait().unwrap();
+            heads.remove(&baz).wait().unwrap();
+            assert!(!heads.is_head(&foo).wait().unwrap());
+        }
+
+        let heads = SqliteHeads::<String>::open(&path).unwrap();
+        assert_eq!(heads.heads().collect().wait().unwrap(), vec![bar]);
+    }
+
+    #[test]
+    fn encoding() {
+        let tmp = TempDir::new("sqliteheads_encoding").unwrap();
+        let path = tmp.path().join("heads.db");
+        let heads = SqliteHeads::create(&path).unwrap();
+        heads.add(&"a b/c".to_string()).wait().unwrap();
+
+        // Keys are stored with the same encoding FileHeads uses.
+        let conn = heads.conn.lock().unwrap();
+        let stored: String = conn.query_row("SELECT key FROM heads", &[], |row| row.get(0))
+            .unwrap();
+        assert_eq!(stored, encode_key(&"a b/c".to_string()).unwrap());
+    }
+}
@@ -0,0 +1,320 @@
+// Copyright (c) 2004-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+use std::hash::Hash;
+use std::sync::{Arc, Mutex};
+
+use futures::{Future, Stream};
+use futures::future::{self, BoxFuture};
+use futures::stream::BoxStream;
+use linked_hash_map::LinkedHashMap;
+
+use {HeadOp, Heads};
+
+/// Decides which head `BoundedHeads` removes to make room for a new one.
+pub trait EvictionPolicy<H: Heads>: Send + Sync + 'static {
+    /// Resolve to the head to remove from `heads`, or `None` if there's nothing to remove.
+    fn victim(&self, heads: &H) -> BoxFuture<Option<H::Key>, H::Error>;
+
+    /// Called after `key` has been added through the `BoundedHeads`.
+    fn added(&self, _key: &H::Key) {}
+
+    /// Called after `key` has been removed through the `BoundedHeads`, including by eviction.
+    fn removed(&self, _key: &H::Key) {}
+}
+
+/// Evict heads in the order they were added through the `BoundedHeads`. Heads which were
+/// already in the store, or which were added some other way, are evicted first, in the order
+/// the store lists them.
+pub struct InsertionOrder<K: Eq + Hash> {
+    order: Arc<Mutex<LinkedHashMap<K, ()>>>,
+}
+
+impl<K: Eq + Hash> InsertionOrder<K> {
+    pub fn new() -> Self {
+        InsertionOrder {
+            order: Arc::new(Mutex::new(LinkedHashMap::new())),
+        }
+    }
+}
+
+impl<H> EvictionPolicy<H> for InsertionOrder<H::Key>
+where
+    H: Heads,
+    H::Key: Clone + Eq + Hash,
+{
+    fn victim(&self, heads: &H) -> BoxFuture<Option<H::Key>, H::Error> {
+        let order = self.order.clone();
+        let untracked = heads.heads().filter({
+            let order = self.order.clone();
+            move |key| !order.lock().expect("lock poisoned").contains_key(key)
+        });
+        untracked
+            .into_future()
+            .map(move |(key, _)| {
+                key.or_else(|| {
+                    let order = order.lock().expect("lock poisoned");
+                    order.front().map(|(key, _)| key.clone())
+                })
+            })
+            .map_err(|(err, _)| err)
+            .boxed()
+    }
+
+    fn added(&self, key: &H::Key) {
+        let mut order = self.order.lock().expect("lock poisoned");
+        // Adding an existing head again makes it the newest.
+        order.remove(key);
+        order.insert(key.clone(), ());
+    }
+
+    fn removed(&self, key: &H::Key) {
+        self.order.lock().expect("lock poisoned").remove(key);
+    }
+}
+
+type Callback<K> = Option<Arc<Fn(&K) + Send + Sync>>;
+
+/// A heads store wrapper which keeps the number of heads to at most `max`, for stores of
+/// short-lived heads which would otherwise grow without limit. Adding a new head to a full
+/// store first removes one chosen by an `EvictionPolicy`, which is passed to the callback set
+/// with `with_callback`.
+///
+/// The count and the eviction aren't atomic with the add, so concurrent adds may take the
+/// store over the limit, and at most one head is evicted for each add, so a store which is
+/// already over the limit stays over it until heads are removed. Transactions are applied by
+/// the wrapped store and tracked by the policy, but nothing is evicted to make room for them.
+/// Changes made other than through this wrapper aren't limited.
+pub struct BoundedHeads<H: Heads, P> {
+    heads: Arc<H>,
+    policy: Arc<P>,
+    max: usize,
+    callback: Callback<H::Key>,
+}
+
+impl<H, P> BoundedHeads<H, P>
+where
+    H: Heads + Sync,
+    H::Key: Clone,
+    P: EvictionPolicy<H>,
+{
+    pub fn new(heads: H, max: usize, policy: P) -> Self {
+        assert!(max > 0, "max must be at least 1");
+        BoundedHeads {
+            heads: Arc::new(heads),
+            policy: Arc::new(policy),
+            max: max,
+            callback: None,
+        }
+    }
+
+    /// Call `callback` with each head that's evicted, once it has been removed.
+    pub fn with_callback<F>(mut self, callback: F) -> Self
+    where
+        F: Fn(&H::Key) + Send + Sync + 'static,
+    {
+        self.callback = Some(Arc::new(callback));
+        self
+    }
+
+    /// Return a reference to the wrapped store.
+    pub fn inner(&self) -> &H {
+        &self.heads
+    }
+}
+
+// Remove the head chosen by `policy`, if there is one.
+fn evict<H, P>(heads: Arc<H>, policy: Arc<P>, callback: Callback<H::Key>) -> BoxFuture<(), H::Error>
+where
+    H: Heads + Sync,
+    P: EvictionPolicy<H>,
+{
+    policy
+        .victim(&heads)
+        .and_then(move |victim| match victim {
+            None => future::ok(()).boxed(),
+            Some(victim) => heads
+                .remove(&victim)
+                .map(move |()| {
+                    policy.removed(&victim);
+                    if let Some(callback) = callback {
+                        (*callback)(&victim);
+                    }
+                })
+                .boxed(),
+        })
+        .boxed()
+}
+
+impl<H, P> Heads for BoundedHeads<H, P>
+where
+    H: Heads + Sync,
+    H::Key: Clone,
+    P: EvictionPolicy<H>,
+{
+    type Key = H::Key;
+    type Error = H::Error;
+
+    type Unit = BoxFuture<(), Self::Error>;
+    type Bool = H::Bool;
+    type Heads = H::Heads;
+
+    fn add(&self, key: &Self::Key) -> Self::Unit {
+        let max = self.max;
+        let heads = self.heads.clone();
+        let policy = self.policy.clone();
+        let callback = self.callback.clone();
+        let key = key.clone();
+
+        self.heads
+            .is_head(&key)
+            .join(self.heads.count())
+            .and_then({
+                let heads = heads.clone();
+                let policy = policy.clone();
+                move |(is_head, count)| if is_head || count < max {
+                    future::ok(()).boxed()
+                } else {
+                    evict(heads, policy, callback)
+                }
+            })
+            .and_then(move |()| heads.add(&key).map(move |()| policy.added(&key)))
+            .boxed()
+    }
+
+    fn remove(&self, key: &Self::Key) -> Self::Unit {
+        let policy = self.policy.clone();
+        let key = key.clone();
+        self.heads
+            .remove(&key)
+            .map(move |()| policy.removed(&key))
+            .boxed()
+    }
+
+    fn is_head(&self, key: &Self::Key) -> Self::Bool {
+        self.heads.is_head(key)
+    }
+
+    fn heads(&self) -> Self::Heads {
+        self.heads.heads()
+    }
+
+    fn count(&self) -> BoxFuture<usize, Self::Error> {
+        self.heads.count()
+    }
+
+    fn count_matching<F>(&self, pred: F) -> BoxFuture<usize, Self::Error>
+    where
+        F: Fn(&Self::Key) -> bool + Send + 'static,
+    {
+        self.heads.count_matching(pred)
+    }
+
+    fn contains_all(&self, keys: &[Self::Key]) -> BoxFuture<bool, Self::Error> {
+        self.heads.contains_all(keys)
+    }
+
+    fn heads_sorted(&self) -> BoxFuture<Vec<Self::Key>, Self::Error>
+    where
+        Self::Key: Ord,
+    {
+        self.heads.heads_sorted()
+    }
+
+    fn filter_present(&self, keys: Vec<Self::Key>) -> BoxStream<Self::Key, Self::Error> {
+        self.heads.filter_present(keys)
+    }
+
+    fn apply(&self, ops: Vec<HeadOp<Self::Key>>) -> BoxFuture<(), Self::Error> {
+        let policy = self.policy.clone();
+        let tracked = ops.clone();
+        self.heads
+            .apply(ops)
+            .map(move |()| for op in tracked {
+                match op {
+                    HeadOp::Add(key) => policy.added(&key),
+                    HeadOp::Remove(key) => policy.removed(&key),
+                }
+            })
+            .boxed()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use testutil::TestHeads;
+
+    #[test]
+    fn evict_oldest() {
+        let evicted = Arc::new(Mutex::new(Vec::new()));
+        let heads = BoundedHeads::new(TestHeads::new(), 2, InsertionOrder::new())
+            .with_callback({
+                let evicted = evicted.clone();
+                move |key: &String| evicted.lock().unwrap().push(key.clone())
+            });
+        let keys: Vec<String> = vec!["a", "b", "c", "d"]
+            .into_iter()
+            .map(String::from)
+            .collect();
+
+        heads.add(&keys[0]).wait().unwrap();
+        heads.add(&keys[1]).wait().unwrap();
+        assert!(evicted.lock().unwrap().is_empty());
+
+        // Adding an existing head doesn't evict anything, but makes it the newest.
+        heads.add(&keys[0]).wait().unwrap();
+        assert!(evicted.lock().unwrap().is_empty());
+
+        heads.add(&keys[2]).wait().unwrap();
+        assert_eq!(*evicted.lock().unwrap(), vec![keys[1].clone()]);
+        heads.add(&keys[3]).wait().unwrap();
+        assert_eq!(*evicted.lock().unwrap(), vec![keys[1].clone(), keys[0].clone()]);
+
+        let mut present = heads.heads().collect().wait().unwrap();
+        present.sort();
+        assert_eq!(present, vec![keys[2].clone(), keys[3].clone()]);
+    }
+
+    #[test]
+    fn transaction() {
+        let heads = BoundedHeads::new(TestHeads::new(), 2, InsertionOrder::new());
+        let keys: Vec<String> = vec!["a", "b", "c"]
+            .into_iter()
+            .map(String::from)
+            .collect();
+
+        heads.add(&keys[0]).wait().unwrap();
+        heads
+            .transaction()
+            .add(keys[1].clone())
+            .commit()
+            .wait()
+            .unwrap();
+        assert_eq!(heads.inner().calls("add"), 2);
+
+        // The head added by the transaction is tracked, so the older one is evicted first.
+        heads.add(&keys[2]).wait().unwrap();
+        let mut present = heads.heads().collect().wait().unwrap();
+        present.sort();
+        assert_eq!(present, vec![keys[1].clone(), keys[2].clone()]);
+    }
+
+    #[test]
+    fn evict_untracked_first() {
+        let store = TestHeads::new();
+        let old = "old".to_string();
+        store.add(&old).wait().unwrap();
+        let heads = BoundedHeads::new(store, 2, InsertionOrder::new());
+        let foo = "foo".to_string();
+        let bar = "bar".to_string();
+
+        heads.add(&foo).wait().unwrap();
+        heads.add(&bar).wait().unwrap();
+        assert!(!heads.is_head(&old).wait().unwrap());
+        assert!(heads.contains_all(&[foo, bar]).wait().unwrap());
+    }
+}
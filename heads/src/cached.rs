@@ -0,0 +1,240 @@
+// Copyright (c) 2004-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+use std::hash::Hash;
+use std::sync::{Arc, Mutex};
+
+use futures::Future;
+use futures::future::{self, BoxFuture};
+use linked_hash_map::LinkedHashMap;
+
+use {HeadOp, Heads};
+
+/// A heads store wrapper which remembers the answers to recent `is_head` queries.
+///
+/// Up to `capacity` answers are cached, evicting the least recently used. Adding or removing
+/// a head through the wrapper, including in a transaction, invalidates its cached answer.
+/// Changes made to the underlying
+/// store by other means are not seen until the cached answer is evicted.
+pub struct CachedHeads<H>
+where
+    H: Heads,
+    H::Key: Eq + Hash + Clone,
+{
+    heads: H,
+    cache: Arc<Mutex<Cache<H::Key>>>,
+}
+
+struct Cache<K: Eq + Hash> {
+    answers: LinkedHashMap<K, bool>,
+    capacity: usize,
+    // Bumped on every invalidation, so that queries which were in flight while a key was
+    // modified don't cache a stale answer.
+    generation: u64,
+}
+
+impl<K: Eq + Hash> Cache<K> {
+    fn insert(&mut self, key: K, answer: bool) {
+        if self.capacity == 0 {
+            return;
+        }
+        self.answers.insert(key, answer);
+        while self.answers.len() > self.capacity {
+            self.answers.pop_front();
+        }
+    }
+
+    fn invalidate(&mut self, key: &K) {
+        self.answers.remove(key);
+        self.generation += 1;
+    }
+}
+
+impl<H> CachedHeads<H>
+where
+    H: Heads,
+    H::Key: Eq + Hash + Clone,
+{
+    pub fn new(heads: H, capacity: usize) -> Self {
+        let cache = Cache {
+            answers: LinkedHashMap::new(),
+            capacity: capacity,
+            generation: 0,
+        };
+        CachedHeads {
+            heads: heads,
+            cache: Arc::new(Mutex::new(cache)),
+        }
+    }
+
+    /// Return a reference to the wrapped store.
+    pub fn inner(&self) -> &H {
+        &self.heads
+    }
+
+    /// Forget all cached answers.
+    pub fn clear_cache(&self) {
+        let mut cache = self.cache.lock().expect("lock poisoned");
+        cache.answers.clear();
+        cache.generation += 1;
+    }
+
+    // Invalidate `key` once `future` completes, in case a query which raced with the change
+    // cached the old answer.
+    fn invalidate_after<F>(&self, key: &H::Key, future: F) -> BoxFuture<(), H::Error>
+    where
+        F: Future<Item = (), Error = H::Error> + Send + 'static,
+    {
+        let cache = self.cache.clone();
+        let key = key.clone();
+        future
+            .then(move |res| {
+                cache.lock().expect("lock poisoned").invalidate(&key);
+                res
+            })
+            .boxed()
+    }
+}
+
+impl<H> Heads for CachedHeads<H>
+where
+    H: Heads,
+    H::Key: Eq + Hash + Clone,
+{
+    type Key = H::Key;
+    type Error = H::Error;
+
+    type Unit = BoxFuture<(), Self::Error>;
+    type Bool = BoxFuture<bool, Self::Error>;
+    type Heads = H::Heads;
+
+    fn add(&self, key: &Self::Key) -> Self::Unit {
+        // Invalidate straight away as well, since the store may make the change even if the
+        // future is dropped without being polled.
+        self.cache.lock().expect("lock poisoned").invalidate(key);
+        let future = self.heads.add(key);
+        self.invalidate_after(key, future)
+    }
+
+    fn remove(&self, key: &Self::Key) -> Self::Unit {
+        self.cache.lock().expect("lock poisoned").invalidate(key);
+        let future = self.heads.remove(key);
+        self.invalidate_after(key, future)
+    }
+
+    fn is_head(&self, key: &Self::Key) -> Self::Bool {
+        let generation = {
+            let mut cache = self.cache.lock().expect("lock poisoned");
+            if let Some(answer) = cache.answers.get_refresh(key) {
+                return future::ok(*answer).boxed();
+            }
+            cache.generation
+        };
+
+        let cache = self.cache.clone();
+        let key = key.clone();
+        self.heads
+            .is_head(&key)
+            .map(move |answer| {
+                let mut cache = cache.lock().expect("lock poisoned");
+                if cache.generation == generation {
+                    cache.insert(key, answer);
+                }
+                answer
+            })
+            .boxed()
+    }
+
+    fn heads(&self) -> Self::Heads {
+        self.heads.heads()
+    }
+
+    fn count(&self) -> BoxFuture<usize, Self::Error> {
+        self.heads.count()
+    }
+
+    /// The operations are applied by the wrapped store, and the answers for their keys are
+    /// invalidated like those for `add` and `remove`.
+    fn apply(&self, ops: Vec<HeadOp<Self::Key>>) -> BoxFuture<(), Self::Error> {
+        let keys: Vec<_> = ops.iter()
+            .map(|op| match op {
+                &HeadOp::Add(ref key) | &HeadOp::Remove(ref key) => key.clone(),
+            })
+            .collect();
+        {
+            let mut cache = self.cache.lock().expect("lock poisoned");
+            for key in &keys {
+                cache.invalidate(key);
+            }
+        }
+
+        let cache = self.cache.clone();
+        self.heads
+            .apply(ops)
+            .then(move |res| {
+                let mut cache = cache.lock().expect("lock poisoned");
+                for key in &keys {
+                    cache.invalidate(key);
+                }
+                res
+            })
+            .boxed()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use testutil::TestHeads;
+
+    #[test]
+    fn cache_hit() {
+        let heads = CachedHeads::new(TestHeads::new(), 10);
+        let foo = "foo".to_string();
+
+        assert!(!heads.is_head(&foo).wait().unwrap());
+        assert!(!heads.is_head(&foo).wait().unwrap());
+        assert_eq!(heads.inner().calls("is_head"), 1);
+
+        // Adding invalidates the cached answer.
+        heads.add(&foo).wait().unwrap();
+        assert!(heads.is_head(&foo).wait().unwrap());
+        assert!(heads.is_head(&foo).wait().unwrap());
+        assert_eq!(heads.inner().calls("is_head"), 2);
+
+        heads.remove(&foo).wait().unwrap();
+        assert!(!heads.is_head(&foo).wait().unwrap());
+        assert_eq!(heads.inner().calls("is_head"), 3);
+    }
+
+    #[test]
+    fn unpolled_change() {
+        let heads = CachedHeads::new(TestHeads::new(), 10);
+        let foo = "foo".to_string();
+        assert!(!heads.is_head(&foo).wait().unwrap());
+
+        // The wrapped store makes the change straight away, so dropping the future mustn't
+        // leave the old answer cached.
+        drop(heads.add(&foo));
+        assert!(heads.is_head(&foo).wait().unwrap());
+        drop(heads.remove(&foo));
+        assert!(!heads.is_head(&foo).wait().unwrap());
+        assert_eq!(heads.inner().calls("is_head"), 3);
+    }
+
+    #[test]
+    fn eviction() {
+        let heads = CachedHeads::new(TestHeads::new(), 1);
+        let foo = "foo".to_string();
+        let bar = "bar".to_string();
+
+        heads.is_head(&foo).wait().unwrap();
+        heads.is_head(&bar).wait().unwrap();
+        // `foo` was evicted to make room for `bar`.
+        heads.is_head(&foo).wait().unwrap();
+        assert_eq!(heads.inner().calls("is_head"), 3);
+    }
+}
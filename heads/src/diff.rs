@@ -0,0 +1,43 @@
+// Copyright (c) 2004-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+use std::collections::HashSet;
+use std::hash::Hash;
+
+use futures::{Future, Stream};
+use futures::future::BoxFuture;
+
+use Heads;
+use errors::*;
+
+/// Compare the heads in two stores, for instance a replica against its primary, resolving to
+/// the heads which are only in `a` and those which are only in `b`, in no particular order.
+///
+/// Both stores are listed in full at the same time, so a head which is added or removed while
+/// the listings are in progress may be reported as a difference.
+pub fn diff_heads<A, B>(a: &A, b: &B) -> BoxFuture<(Vec<A::Key>, Vec<A::Key>), Error>
+where
+    A: Heads,
+    A::Key: Eq + Hash,
+    B: Heads<Key = A::Key>,
+{
+    let a = a.heads()
+        .map_err(|err| Error::with_chain(err, "failed to read heads from first store"))
+        .collect();
+    let b = b.heads()
+        .map_err(|err| Error::with_chain(err, "failed to read heads from second store"))
+        .collect();
+
+    a.join(b)
+        .map(|(a, b)| {
+            // Heads may be listed more than once, so deduplicate both sides.
+            let mut only_a: HashSet<_> = a.into_iter().collect();
+            let b: HashSet<_> = b.into_iter().collect();
+            let only_b = b.into_iter().filter(|key| !only_a.remove(key)).collect();
+            (only_a.into_iter().collect(), only_b)
+        })
+        .boxed()
+}
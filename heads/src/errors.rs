@@ -0,0 +1,26 @@
+// Copyright (c) 2004-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+use std::io;
+
+error_chain! {
+    errors {
+        ReadOnly {
+            description("heads store is read-only")
+            display("heads store is read-only")
+        }
+        MigrateFailed(key: String) {
+            description("failed to copy head")
+            display("failed to copy head {}", key)
+        }
+    }
+}
+
+impl From<Error> for io::Error {
+    fn from(err: Error) -> io::Error {
+        io::Error::new(io::ErrorKind::Other, format!("{}", err))
+    }
+}
@@ -0,0 +1,164 @@
+// Copyright (c) 2004-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use futures::Future;
+use futures::future::BoxFuture;
+
+use {HeadOp, Heads};
+
+/// Destination for the metrics recorded by `InstrumentedHeads`. Metric names are the names of
+/// the `Heads` operations (`add`, `remove`, `is_head`, `heads`, `apply`).
+pub trait HeadsMetrics: Send + Sync + 'static {
+    /// Increment the counter `name`.
+    fn incr(&self, name: &'static str);
+    /// Record a sample for the timing histogram `name`.
+    fn observe(&self, name: &'static str, elapsed: Duration);
+}
+
+/// Metrics sink which discards everything.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct NoopMetrics;
+
+impl HeadsMetrics for NoopMetrics {
+    fn incr(&self, _name: &'static str) {}
+    fn observe(&self, _name: &'static str, _elapsed: Duration) {}
+}
+
+/// A heads store wrapper which counts each operation and records how long it takes to
+/// complete, whether it succeeds or fails.
+///
+/// Listing heads is counted but not timed, since the time to consume the stream is under the
+/// caller's control.
+pub struct InstrumentedHeads<H, M> {
+    heads: H,
+    metrics: Arc<M>,
+}
+
+impl<H, M> InstrumentedHeads<H, M>
+where
+    H: Heads,
+    M: HeadsMetrics,
+{
+    pub fn new(heads: H, metrics: Arc<M>) -> Self {
+        InstrumentedHeads {
+            heads: heads,
+            metrics: metrics,
+        }
+    }
+
+    /// Return a reference to the wrapped store.
+    pub fn inner(&self) -> &H {
+        &self.heads
+    }
+
+    fn timed<F>(&self, name: &'static str, future: F) -> BoxFuture<F::Item, F::Error>
+    where
+        F: Future + Send + 'static,
+        F::Item: Send + 'static,
+        F::Error: Send + 'static,
+    {
+        let metrics = self.metrics.clone();
+        let start = Instant::now();
+        metrics.incr(name);
+        future
+            .then(move |res| {
+                metrics.observe(name, start.elapsed());
+                res
+            })
+            .boxed()
+    }
+}
+
+impl<H> InstrumentedHeads<H, NoopMetrics>
+where
+    H: Heads,
+{
+    /// Wrap `heads` without recording anything.
+    pub fn noop(heads: H) -> Self {
+        Self::new(heads, Arc::new(NoopMetrics))
+    }
+}
+
+impl<H, M> Heads for InstrumentedHeads<H, M>
+where
+    H: Heads,
+    M: HeadsMetrics,
+{
+    type Key = H::Key;
+    type Error = H::Error;
+
+    type Unit = BoxFuture<(), Self::Error>;
+    type Bool = BoxFuture<bool, Self::Error>;
+    type Heads = H::Heads;
+
+    fn add(&self, key: &Self::Key) -> Self::Unit {
+        self.timed("add", self.heads.add(key))
+    }
+
+    fn remove(&self, key: &Self::Key) -> Self::Unit {
+        self.timed("remove", self.heads.remove(key))
+    }
+
+    fn is_head(&self, key: &Self::Key) -> Self::Bool {
+        self.timed("is_head", self.heads.is_head(key))
+    }
+
+    fn heads(&self) -> Self::Heads {
+        self.metrics.incr("heads");
+        self.heads.heads()
+    }
+
+    fn apply(&self, ops: Vec<HeadOp<Self::Key>>) -> BoxFuture<(), Self::Error> {
+        self.timed("apply", self.heads.apply(ops))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::collections::HashMap;
+    use std::sync::Mutex;
+    use futures::Stream;
+    use testutil::TestHeads;
+
+    #[derive(Default)]
+    struct RecordingMetrics {
+        counters: Mutex<HashMap<&'static str, usize>>,
+        samples: Mutex<HashMap<&'static str, usize>>,
+    }
+
+    impl HeadsMetrics for RecordingMetrics {
+        fn incr(&self, name: &'static str) {
+            *self.counters.lock().unwrap().entry(name).or_insert(0) += 1;
+        }
+
+        fn observe(&self, name: &'static str, _elapsed: Duration) {
+            *self.samples.lock().unwrap().entry(name).or_insert(0) += 1;
+        }
+    }
+
+    #[test]
+    fn records() {
+        let metrics = Arc::new(RecordingMetrics::default());
+        let heads = InstrumentedHeads::new(TestHeads::new(), metrics.clone());
+        let foo = "foo".to_string();
+
+        heads.add(&foo).wait().unwrap();
+        assert!(heads.is_head(&foo).wait().unwrap());
+        assert_eq!(heads.heads().collect().wait().unwrap(), vec![foo]);
+
+        let counters = metrics.counters.lock().unwrap();
+        assert_eq!(counters.get("add"), Some(&1));
+        assert_eq!(counters.get("is_head"), Some(&1));
+        assert_eq!(counters.get("heads"), Some(&1));
+        assert_eq!(counters.get("remove"), None);
+        assert_eq!(metrics.samples.lock().unwrap().get("add"), Some(&1));
+        assert_eq!(heads.inner().calls("add"), 1);
+    }
+}
@@ -4,11 +4,42 @@
 // This software may be used and distributed according to the terms of the
 // GNU General Public License version 2 or any later version.
 
+#[macro_use]
+extern crate error_chain;
 extern crate futures;
+extern crate linked_hash_map;
 
 use futures::{Future, Stream};
+use futures::future::{self, BoxFuture};
+use futures::stream::{self, BoxStream};
 use std::error;
 
+mod bounded;
+mod cached;
+mod diff;
+pub mod errors;
+mod instrumented;
+mod migrate;
+mod notifying;
+mod readonly;
+mod retrying;
+#[cfg(test)]
+mod testutil;
+mod transaction;
+mod union;
+
+pub use bounded::{BoundedHeads, EvictionPolicy, InsertionOrder};
+pub use cached::CachedHeads;
+pub use diff::diff_heads;
+pub use errors::{Error, ErrorKind};
+pub use instrumented::{HeadsMetrics, InstrumentedHeads, NoopMetrics};
+pub use migrate::migrate_heads;
+pub use notifying::{HeadEvent, NotifyingHeads, Subscription};
+pub use readonly::ReadOnlyHeads;
+pub use retrying::{Backoff, RetryPolicy, RetryingHeads, is_transient_io_error};
+pub use transaction::{HeadOp, Transaction};
+pub use union::{Primary, UnionHeads};
+
 /// Trait representing the interface to a heads store, which more generally is just
 /// a set of commit identifiers.
 pub trait Heads: Send + 'static {
@@ -25,4 +56,183 @@ pub trait Heads: Send + 'static {
     fn remove(&self, &Self::Key) -> Self::Unit;
     fn is_head(&self, &Self::Key) -> Self::Bool;
     fn heads(&self) -> Self::Heads;
+
+    /// Like `add`, but taking ownership of the key, for callers which have no further use for
+    /// it, such as when it came from a stream. The default implementation borrows it for
+    /// `add`; stores which keep the key can override this to avoid a copy.
+    fn add_owned(&self, key: Self::Key) -> Self::Unit {
+        self.add(&key)
+    }
+
+    /// Like `remove`, but taking ownership of the key. See `add_owned`.
+    fn remove_owned(&self, key: Self::Key) -> Self::Unit {
+        self.remove(&key)
+    }
+
+    /// Return the number of heads. The default implementation counts the keys returned by
+    /// `heads()`; stores that can answer more cheaply should override it.
+    fn count(&self) -> BoxFuture<usize, Self::Error> {
+        self.heads()
+            .fold(0, |count, _| Ok::<_, Self::Error>(count + 1))
+            .boxed()
+    }
+
+    /// Return the number of heads for which `pred` returns `true`. The default implementation
+    /// checks the keys returned by `heads()` as they're listed, so they aren't all held in
+    /// memory at once.
+    fn count_matching<F>(&self, pred: F) -> BoxFuture<usize, Self::Error>
+    where
+        F: Fn(&Self::Key) -> bool + Send + 'static,
+    {
+        self.heads()
+            .filter(pred)
+            .fold(0, |count, _| Ok::<_, Self::Error>(count + 1))
+            .boxed()
+    }
+
+    /// Return `true` if every one of `keys` is a head. The default implementation checks each
+    /// key with `is_head`, and resolves as soon as one of them is found not to be a head.
+    fn contains_all(&self, keys: &[Self::Key]) -> BoxFuture<bool, Self::Error> {
+        let checks: Vec<_> = keys.iter()
+            .map(|key| Ok::<_, Self::Error>(self.is_head(key)))
+            .collect();
+        stream::iter(checks)
+            .and_then(|check| check)
+            .filter(|is_head| !*is_head)
+            .into_future()
+            .map(|(missing, _)| missing.is_none())
+            .map_err(|(err, _)| err)
+            .boxed()
+    }
+
+    /// Return all the heads, sorted. The default implementation collects and sorts the keys
+    /// returned by `heads()`.
+    fn heads_sorted(&self) -> BoxFuture<Vec<Self::Key>, Self::Error>
+    where
+        Self::Key: Ord,
+    {
+        self.heads()
+            .collect()
+            .map(|mut keys| {
+                keys.sort();
+                keys
+            })
+            .boxed()
+    }
+
+    /// Return the subset of `keys` which are heads, in no particular order. The default
+    /// implementation checks each key with `is_head`.
+    fn filter_present(&self, keys: Vec<Self::Key>) -> BoxStream<Self::Key, Self::Error> {
+        let checks: Vec<_> = keys.into_iter()
+            .map(|key| {
+                Ok::<_, Self::Error>(self.is_head(&key).map(move |is_head| (key, is_head)))
+            })
+            .collect();
+        stream::iter(checks)
+            .and_then(|check| check)
+            .filter_map(|(key, is_head)| if is_head { Some(key) } else { None })
+            .boxed()
+    }
+
+    /// Start building a group of changes to be applied together.
+    fn transaction(&self) -> Transaction<Self>
+    where
+        Self: Sized,
+    {
+        Transaction::new(self)
+    }
+
+    /// Apply a group of changes. Stores which can apply them atomically should override this.
+    ///
+    /// The default implementation is not atomic: all the operations are started at once, so
+    /// they may complete in any order, and if one fails the others may still take effect.
+    fn apply(&self, ops: Vec<HeadOp<Self::Key>>) -> BoxFuture<(), Self::Error> {
+        let futures: Vec<_> = ops.iter()
+            .map(|op| match op {
+                &HeadOp::Add(ref key) => self.add(key),
+                &HeadOp::Remove(ref key) => self.remove(key),
+            })
+            .collect();
+        future::join_all(futures).map(|_| ()).boxed()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use testutil::TestHeads;
+
+    #[test]
+    fn contains_all() {
+        let heads = TestHeads::new();
+        let foo = "foo".to_string();
+        let bar = "bar".to_string();
+        let baz = "baz".to_string();
+        heads.add(&foo).wait().unwrap();
+        heads.add(&bar).wait().unwrap();
+
+        assert!(heads.contains_all(&[]).wait().unwrap());
+        assert!(heads.contains_all(&[foo.clone(), bar.clone()]).wait().unwrap());
+        assert!(!heads.contains_all(&[foo.clone(), baz.clone(), bar.clone()]).wait().unwrap());
+    }
+
+    #[test]
+    fn owned() {
+        let heads = TestHeads::new();
+        let keys = stream::iter(vec!["foo", "bar"].into_iter().map(|key| Ok(key.to_string())));
+        keys.for_each(|key| heads.add_owned(key)).wait().unwrap();
+        assert!(heads.contains_all(&["foo".to_string(), "bar".to_string()]).wait().unwrap());
+
+        heads.remove_owned("foo".to_string()).wait().unwrap();
+        assert_eq!(heads.heads().collect().wait().unwrap(), vec!["bar".to_string()]);
+        assert_eq!(heads.calls("add"), 2);
+        assert_eq!(heads.calls("remove"), 1);
+    }
+
+    #[test]
+    fn count_matching() {
+        let heads = TestHeads::new();
+        for key in vec!["draft/foo", "draft/bar", "public/baz"] {
+            heads.add(&key.to_string()).wait().unwrap();
+        }
+
+        let drafts = heads.count_matching(|key| key.starts_with("draft/"));
+        assert_eq!(drafts.wait().unwrap(), 2);
+        assert_eq!(heads.count_matching(|_| true).wait().unwrap(), 3);
+        assert_eq!(heads.count_matching(|_| false).wait().unwrap(), 0);
+    }
+
+    #[test]
+    fn heads_sorted() {
+        let heads = TestHeads::new();
+        let mut keys: Vec<String> = vec!["foo", "bar", "baz", "quux"]
+            .into_iter()
+            .map(String::from)
+            .collect();
+        for key in &keys {
+            heads.add(key).wait().unwrap();
+        }
+
+        keys.sort();
+        assert_eq!(heads.heads_sorted().wait().unwrap(), keys);
+    }
+
+    #[test]
+    fn filter_present() {
+        let heads = TestHeads::new();
+        let foo = "foo".to_string();
+        let bar = "bar".to_string();
+        let baz = "baz".to_string();
+        heads.add(&foo).wait().unwrap();
+        heads.add(&bar).wait().unwrap();
+
+        let mut present = heads
+            .filter_present(vec![baz.clone(), bar.clone(), foo.clone()])
+            .collect()
+            .wait()
+            .unwrap();
+        present.sort();
+        assert_eq!(present, vec![bar, foo]);
+        assert!(heads.filter_present(vec![baz]).collect().wait().unwrap().is_empty());
+    }
 }
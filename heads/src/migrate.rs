@@ -0,0 +1,112 @@
+// Copyright (c) 2004-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+use std::fmt::Debug;
+
+use futures::{Future, Stream};
+use futures::future::BoxFuture;
+
+use Heads;
+use errors::*;
+
+/// Copy every head in `src` into `dst`, for instance when switching to a different backend,
+/// and return the number of heads copied. Nothing is removed from `src`.
+///
+/// Nothing happens until the returned future is polled. Heads are then copied one at a time,
+/// and copying stops at the first error. Keys are `Debug` so that the error can say which
+/// head failed to copy.
+///
+/// `dst` is cloned so that the returned future doesn't borrow it, so its clones must share
+/// their contents, as `FileHeads` and `MemHeads` clones do.
+pub fn migrate_heads<A, B>(src: &A, dst: &B) -> BoxFuture<usize, Error>
+where
+    A: Heads,
+    A::Key: Debug,
+    B: Heads<Key = A::Key> + Clone,
+{
+    let dst = dst.clone();
+    src.heads()
+        .map_err(|err| Error::with_chain(err, "failed to read heads from source"))
+        .and_then(move |key| {
+            dst.add(&key).map_err(move |err| {
+                Error::with_chain(err, ErrorKind::MigrateFailed(format!("{:?}", key)))
+            })
+        })
+        .fold(0, |count, ()| Ok::<_, Error>(count + 1))
+        .boxed()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use futures::future::{self, FutureResult};
+    use futures::stream::BoxStream;
+
+    use testutil::TestHeads;
+
+    // Shares a `TestHeads` between clones, and fails the `fail_at`th add.
+    #[derive(Clone)]
+    struct FailingHeads {
+        heads: Arc<TestHeads>,
+        adds: Arc<AtomicUsize>,
+        fail_at: usize,
+    }
+
+    impl Heads for FailingHeads {
+        type Key = String;
+        type Error = io::Error;
+
+        type Unit = FutureResult<(), Self::Error>;
+        type Bool = FutureResult<bool, Self::Error>;
+        type Heads = BoxStream<Self::Key, Self::Error>;
+
+        fn add(&self, key: &Self::Key) -> Self::Unit {
+            if self.adds.fetch_add(1, Ordering::SeqCst) + 1 == self.fail_at {
+                future::err(io::Error::new(io::ErrorKind::Other, "add failed"))
+            } else {
+                self.heads.add(key)
+            }
+        }
+
+        fn remove(&self, key: &Self::Key) -> Self::Unit {
+            self.heads.remove(key)
+        }
+
+        fn is_head(&self, key: &Self::Key) -> Self::Bool {
+            self.heads.is_head(key)
+        }
+
+        fn heads(&self) -> Self::Heads {
+            self.heads.heads()
+        }
+    }
+
+    #[test]
+    fn stops_at_error() {
+        let src = TestHeads::new();
+        for key in &["a", "b", "c", "d"] {
+            src.add(&key.to_string()).wait().unwrap();
+        }
+        let dst = FailingHeads {
+            heads: Arc::new(TestHeads::new()),
+            adds: Arc::new(AtomicUsize::new(0)),
+            fail_at: 2,
+        };
+
+        let migration = migrate_heads(&src, &dst);
+        assert_eq!(dst.adds.load(Ordering::SeqCst), 0);
+        assert!(migration.wait().is_err());
+
+        // Only the head copied before the failure was written.
+        assert_eq!(dst.adds.load(Ordering::SeqCst), 2);
+        assert_eq!(dst.heads().collect().wait().unwrap().len(), 1);
+        assert_eq!(src.count().wait().unwrap(), 4);
+    }
+}
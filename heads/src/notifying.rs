@@ -0,0 +1,302 @@
+// Copyright (c) 2004-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex, Weak};
+
+use futures::{Async, Future, Poll, Stream};
+use futures::future::BoxFuture;
+use futures::stream::BoxStream;
+use futures::task::{self, Task};
+
+use {HeadOp, Heads};
+
+/// A change to the heads in a store.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum HeadEvent<K> {
+    Added(K),
+    Removed(K),
+}
+
+/// A heads store wrapper which reports every successful change made through it to
+/// subscribers. Unlike `FileHeads::watch`, this works with any store, but changes made to the
+/// underlying store other than through this wrapper aren't seen.
+///
+/// An event is sent for each successful `add` or `remove`, whether or not it changed the set
+/// of heads, so adding a key which is already a head still sends `HeadEvent::Added`.
+pub struct NotifyingHeads<H: Heads> {
+    heads: H,
+    subscribers: Arc<Subscribers<H::Key>>,
+}
+
+struct Subscribers<K> {
+    queues: Mutex<Vec<Weak<Mutex<Queue<K>>>>>,
+    capacity: usize,
+}
+
+impl<K: Clone> Subscribers<K> {
+    fn send(&self, event: HeadEvent<K>) {
+        let mut queues = self.queues.lock().expect("lock poisoned");
+        // Subscriptions which have been dropped are forgotten.
+        queues.retain(|queue| match queue.upgrade() {
+            Some(queue) => {
+                queue
+                    .lock()
+                    .expect("lock poisoned")
+                    .push(event.clone(), self.capacity);
+                true
+            }
+            None => false,
+        });
+    }
+}
+
+// Events waiting to be read by a subscriber.
+struct Queue<K> {
+    events: VecDeque<HeadEvent<K>>,
+    lagged: u64, // number of events dropped because the queue was full
+    task: Option<Task>, // subscriber waiting for an event
+    closed: bool, // whether the store has been dropped
+}
+
+impl<K> Queue<K> {
+    fn push(&mut self, event: HeadEvent<K>, capacity: usize) {
+        if self.events.len() >= capacity {
+            self.events.pop_front();
+            self.lagged += 1;
+        }
+        self.events.push_back(event);
+        self.wake();
+    }
+
+    fn wake(&mut self) {
+        if let Some(task) = self.task.take() {
+            task.notify();
+        }
+    }
+}
+
+impl<H> NotifyingHeads<H>
+where
+    H: Heads,
+    H::Key: Clone,
+{
+    /// Wrap `heads`, holding up to `capacity` events for each subscriber which haven't been
+    /// read yet. Once a subscriber's queue is full, its oldest event is dropped for each new
+    /// one, so a slow subscriber can't use more memory than that.
+    pub fn new(heads: H, capacity: usize) -> Self {
+        assert!(capacity > 0, "capacity must be at least 1");
+        NotifyingHeads {
+            heads: heads,
+            subscribers: Arc::new(Subscribers {
+                queues: Mutex::new(Vec::new()),
+                capacity: capacity,
+            }),
+        }
+    }
+
+    /// Return a reference to the wrapped store.
+    pub fn inner(&self) -> &H {
+        &self.heads
+    }
+
+    /// Return a stream of the changes made from now on. The stream ends when the store is
+    /// dropped.
+    pub fn subscribe(&self) -> Subscription<H::Key> {
+        let queue = Arc::new(Mutex::new(Queue {
+            events: VecDeque::new(),
+            lagged: 0,
+            task: None,
+            closed: false,
+        }));
+        self.subscribers
+            .queues
+            .lock()
+            .expect("lock poisoned")
+            .push(Arc::downgrade(&queue));
+        Subscription { queue: queue }
+    }
+
+    // Send `event` once `future` succeeds.
+    fn notify<F>(&self, future: F, event: HeadEvent<H::Key>) -> BoxFuture<(), H::Error>
+    where
+        F: Future<Item = (), Error = H::Error> + Send + 'static,
+    {
+        let subscribers = self.subscribers.clone();
+        future.map(move |()| subscribers.send(event)).boxed()
+    }
+}
+
+impl<H: Heads> Drop for NotifyingHeads<H> {
+    fn drop(&mut self) {
+        let queues = self.subscribers.queues.lock().expect("lock poisoned");
+        for queue in queues.iter().filter_map(Weak::upgrade) {
+            let mut queue = queue.lock().expect("lock poisoned");
+            queue.closed = true;
+            queue.wake();
+        }
+    }
+}
+
+impl<H> Heads for NotifyingHeads<H>
+where
+    H: Heads,
+    H::Key: Clone,
+{
+    type Key = H::Key;
+    type Error = H::Error;
+
+    type Unit = BoxFuture<(), Self::Error>;
+    type Bool = H::Bool;
+    type Heads = H::Heads;
+
+    fn add(&self, key: &Self::Key) -> Self::Unit {
+        self.notify(self.heads.add(key), HeadEvent::Added(key.clone()))
+    }
+
+    fn remove(&self, key: &Self::Key) -> Self::Unit {
+        self.notify(self.heads.remove(key), HeadEvent::Removed(key.clone()))
+    }
+
+    fn is_head(&self, key: &Self::Key) -> Self::Bool {
+        self.heads.is_head(key)
+    }
+
+    fn heads(&self) -> Self::Heads {
+        self.heads.heads()
+    }
+
+    fn count(&self) -> BoxFuture<usize, Self::Error> {
+        self.heads.count()
+    }
+
+    fn count_matching<F>(&self, pred: F) -> BoxFuture<usize, Self::Error>
+    where
+        F: Fn(&Self::Key) -> bool + Send + 'static,
+    {
+        self.heads.count_matching(pred)
+    }
+
+    fn contains_all(&self, keys: &[Self::Key]) -> BoxFuture<bool, Self::Error> {
+        self.heads.contains_all(keys)
+    }
+
+    fn heads_sorted(&self) -> BoxFuture<Vec<Self::Key>, Self::Error>
+    where
+        Self::Key: Ord,
+    {
+        self.heads.heads_sorted()
+    }
+
+    fn filter_present(&self, keys: Vec<Self::Key>) -> BoxStream<Self::Key, Self::Error> {
+        self.heads.filter_present(keys)
+    }
+
+    /// The operations are applied by the wrapped store, and their events are sent in order once
+    /// they have all succeeded.
+    fn apply(&self, ops: Vec<HeadOp<Self::Key>>) -> BoxFuture<(), Self::Error> {
+        let subscribers = self.subscribers.clone();
+        let events: Vec<_> = ops.iter()
+            .map(|op| match op {
+                &HeadOp::Add(ref key) => HeadEvent::Added(key.clone()),
+                &HeadOp::Remove(ref key) => HeadEvent::Removed(key.clone()),
+            })
+            .collect();
+        self.heads
+            .apply(ops)
+            .map(move |()| for event in events {
+                subscribers.send(event);
+            })
+            .boxed()
+    }
+}
+
+/// A stream of the changes made through a `NotifyingHeads`, returned by
+/// `NotifyingHeads::subscribe`.
+pub struct Subscription<K> {
+    queue: Arc<Mutex<Queue<K>>>,
+}
+
+impl<K> Subscription<K> {
+    /// Return the number of events which have been dropped so far because this subscriber
+    /// didn't keep up.
+    pub fn lagged(&self) -> u64 {
+        self.queue.lock().expect("lock poisoned").lagged
+    }
+}
+
+impl<K> Stream for Subscription<K> {
+    type Item = HeadEvent<K>;
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        let mut queue = self.queue.lock().expect("lock poisoned");
+        let event = queue.events.pop_front();
+        match event {
+            Some(event) => Ok(Async::Ready(Some(event))),
+            None if queue.closed => Ok(Async::Ready(None)),
+            None => {
+                queue.task = Some(task::current());
+                Ok(Async::NotReady)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use testutil::TestHeads;
+
+    #[test]
+    fn subscribe() {
+        let heads = NotifyingHeads::new(TestHeads::new(), 16);
+        let foo = "foo".to_string();
+        let bar = "bar".to_string();
+        let events = heads.subscribe();
+
+        heads.add(&foo).wait().unwrap();
+        assert!(heads.inner().is_head(&foo).wait().unwrap());
+        heads.remove(&foo).wait().unwrap();
+        heads.transaction().add(bar.clone()).remove(foo.clone()).commit().wait().unwrap();
+
+        // Events from before subscribing aren't seen.
+        let late = heads.subscribe();
+        drop(heads);
+
+        assert_eq!(
+            events.collect().wait().unwrap(),
+            vec![
+                HeadEvent::Added(foo.clone()),
+                HeadEvent::Removed(foo.clone()),
+                HeadEvent::Added(bar),
+                HeadEvent::Removed(foo),
+            ]
+        );
+        assert!(late.collect().wait().unwrap().is_empty());
+    }
+
+    #[test]
+    fn lagged() {
+        let heads = NotifyingHeads::new(TestHeads::new(), 2);
+        let mut events = heads.subscribe();
+        let keys: Vec<String> = (0..5).map(|i| format!("head{}", i)).collect();
+        for key in &keys {
+            heads.add(key).wait().unwrap();
+        }
+        assert_eq!(events.lagged(), 3);
+
+        // Only the newest events are kept.
+        let first = events.by_ref().take(2).collect().wait().unwrap();
+        assert_eq!(
+            first,
+            vec![
+                HeadEvent::Added(keys[3].clone()),
+                HeadEvent::Added(keys[4].clone()),
+            ]
+        );
+    }
+}
@@ -0,0 +1,109 @@
+// Copyright (c) 2004-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+use futures::Future;
+use futures::future::{self, BoxFuture};
+use futures::stream::BoxStream;
+
+use Heads;
+use errors::*;
+
+/// A heads store wrapper which rejects all changes. Adding or removing a head fails with
+/// `ErrorKind::ReadOnly` without the wrapped store being touched; queries are passed through.
+pub struct ReadOnlyHeads<H> {
+    heads: H,
+}
+
+impl<H> ReadOnlyHeads<H>
+where
+    H: Heads,
+    H::Error: From<Error>,
+{
+    pub fn new(heads: H) -> Self {
+        ReadOnlyHeads { heads: heads }
+    }
+
+    /// Return a reference to the wrapped store.
+    pub fn inner(&self) -> &H {
+        &self.heads
+    }
+}
+
+impl<H> Heads for ReadOnlyHeads<H>
+where
+    H: Heads,
+    H::Error: From<Error>,
+{
+    type Key = H::Key;
+    type Error = H::Error;
+
+    type Unit = BoxFuture<(), Self::Error>;
+    type Bool = H::Bool;
+    type Heads = H::Heads;
+
+    fn add(&self, _key: &Self::Key) -> Self::Unit {
+        future::err(Error::from(ErrorKind::ReadOnly).into()).boxed()
+    }
+
+    fn remove(&self, _key: &Self::Key) -> Self::Unit {
+        future::err(Error::from(ErrorKind::ReadOnly).into()).boxed()
+    }
+
+    fn is_head(&self, key: &Self::Key) -> Self::Bool {
+        self.heads.is_head(key)
+    }
+
+    fn heads(&self) -> Self::Heads {
+        self.heads.heads()
+    }
+
+    fn count(&self) -> BoxFuture<usize, Self::Error> {
+        self.heads.count()
+    }
+
+    fn count_matching<F>(&self, pred: F) -> BoxFuture<usize, Self::Error>
+    where
+        F: Fn(&Self::Key) -> bool + Send + 'static,
+    {
+        self.heads.count_matching(pred)
+    }
+
+    fn contains_all(&self, keys: &[Self::Key]) -> BoxFuture<bool, Self::Error> {
+        self.heads.contains_all(keys)
+    }
+
+    fn heads_sorted(&self) -> BoxFuture<Vec<Self::Key>, Self::Error>
+    where
+        Self::Key: Ord,
+    {
+        self.heads.heads_sorted()
+    }
+
+    fn filter_present(&self, keys: Vec<Self::Key>) -> BoxStream<Self::Key, Self::Error> {
+        self.heads.filter_present(keys)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use testutil::TestHeads;
+
+    #[test]
+    fn rejects_changes() {
+        let heads = ReadOnlyHeads::new(TestHeads::new());
+        let foo = "foo".to_string();
+
+        assert!(heads.add(&foo).wait().is_err());
+        assert!(heads.remove(&foo).wait().is_err());
+        assert!(heads.transaction().add(foo.clone()).commit().wait().is_err());
+        assert_eq!(heads.inner().calls("add"), 0);
+        assert_eq!(heads.inner().calls("remove"), 0);
+
+        assert!(!heads.is_head(&foo).wait().unwrap());
+        assert_eq!(heads.inner().calls("is_head"), 1);
+    }
+}
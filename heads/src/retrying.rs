@@ -0,0 +1,286 @@
+// Copyright (c) 2004-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+use std::cmp;
+use std::io;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use futures::Future;
+use futures::future::{self, BoxFuture};
+use futures::sync::oneshot;
+
+use {HeadOp, Heads};
+
+/// Decides which failed operations `RetryingHeads` tries again, and how long it waits first.
+pub trait RetryPolicy<E>: Send + Sync + 'static {
+    /// Return how long to wait before trying again after attempt number `attempt` (counting
+    /// from 1) failed with `err`, or `None` to give up and return the error.
+    fn retry(&self, attempt: usize, err: &E) -> Option<Duration>;
+}
+
+/// Retry errors for which `retryable` returns `true`, making at most `max_attempts` attempts
+/// in total. The first retry waits for `initial`, and each one after that waits twice as long
+/// as the one before.
+pub struct Backoff<F> {
+    max_attempts: usize,
+    initial: Duration,
+    retryable: F,
+}
+
+impl<F> Backoff<F> {
+    pub fn new(max_attempts: usize, initial: Duration, retryable: F) -> Self {
+        Backoff {
+            max_attempts: max_attempts,
+            initial: initial,
+            retryable: retryable,
+        }
+    }
+}
+
+impl<E, F> RetryPolicy<E> for Backoff<F>
+where
+    F: Fn(&E) -> bool + Send + Sync + 'static,
+{
+    fn retry(&self, attempt: usize, err: &E) -> Option<Duration> {
+        if attempt >= self.max_attempts || !(self.retryable)(err) {
+            return None;
+        }
+        let factor = 1u32 << cmp::min(attempt - 1, 31);
+        Some(
+            self.initial
+                .checked_mul(factor)
+                .unwrap_or(Duration::new(u64::max_value(), 0)),
+        )
+    }
+}
+
+/// Return `true` for IO errors which may well not happen again if the operation is retried,
+/// such as an interrupted system call or, on network filesystems, a stale file handle.
+pub fn is_transient_io_error(err: &io::Error) -> bool {
+    match err.kind() {
+        io::ErrorKind::Interrupted | io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => true,
+        _ => is_stale_handle(err),
+    }
+}
+
+// `io::ErrorKind` has no kind for ESTALE, so check the OS error code.
+#[cfg(target_os = "linux")]
+fn is_stale_handle(err: &io::Error) -> bool {
+    err.raw_os_error() == Some(116)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn is_stale_handle(_err: &io::Error) -> bool {
+    false
+}
+
+/// A heads store wrapper which retries failed changes and lookups as directed by a
+/// `RetryPolicy`, for stores whose failures are often transient. Listing heads is passed
+/// through, since a partly consumed listing can't be retried.
+pub struct RetryingHeads<H, P> {
+    heads: Arc<H>,
+    policy: Arc<P>,
+}
+
+impl<H, P> RetryingHeads<H, P>
+where
+    H: Heads + Sync,
+    P: RetryPolicy<H::Error>,
+{
+    pub fn new(heads: H, policy: P) -> Self {
+        RetryingHeads {
+            heads: Arc::new(heads),
+            policy: Arc::new(policy),
+        }
+    }
+
+    /// Return a reference to the wrapped store.
+    pub fn inner(&self) -> &H {
+        &self.heads
+    }
+
+    fn retry<F, T>(&self, op: F) -> BoxFuture<T, H::Error>
+    where
+        F: Fn(&H) -> BoxFuture<T, H::Error> + Send + Sync + 'static,
+        T: Send + 'static,
+    {
+        attempt(self.heads.clone(), self.policy.clone(), Arc::new(op), 1)
+    }
+}
+
+// Make attempt number `n` at `op`, followed by any further attempts the policy allows.
+fn attempt<H, P, F, T>(
+    heads: Arc<H>,
+    policy: Arc<P>,
+    op: Arc<F>,
+    n: usize,
+) -> BoxFuture<T, H::Error>
+where
+    H: Heads + Sync,
+    P: RetryPolicy<H::Error>,
+    F: Fn(&H) -> BoxFuture<T, H::Error> + Send + Sync + 'static,
+    T: Send + 'static,
+{
+    let future = op(&*heads);
+    future
+        .or_else(move |err| match policy.retry(n, &err) {
+            None => future::err(err).boxed(),
+            Some(wait) => delay(wait)
+                .and_then(move |()| attempt(heads, policy, op, n + 1))
+                .boxed(),
+        })
+        .boxed()
+}
+
+// Resolve after `wait`. There's no timer available here, so a thread does the waiting.
+fn delay<E: Send + 'static>(wait: Duration) -> BoxFuture<(), E> {
+    let (tx, rx) = oneshot::channel();
+    thread::spawn(move || {
+        thread::sleep(wait);
+        let _ = tx.send(());
+    });
+    rx.map_err(|_| unreachable!("delay thread exited early")).boxed()
+}
+
+impl<H, P> Heads for RetryingHeads<H, P>
+where
+    H: Heads + Sync,
+    H::Key: Clone + Sync,
+    P: RetryPolicy<H::Error>,
+{
+    type Key = H::Key;
+    type Error = H::Error;
+
+    type Unit = BoxFuture<(), Self::Error>;
+    type Bool = BoxFuture<bool, Self::Error>;
+    type Heads = H::Heads;
+
+    fn add(&self, key: &Self::Key) -> Self::Unit {
+        let key = key.clone();
+        self.retry(move |heads| heads.add(&key).boxed())
+    }
+
+    fn remove(&self, key: &Self::Key) -> Self::Unit {
+        let key = key.clone();
+        self.retry(move |heads| heads.remove(&key).boxed())
+    }
+
+    fn is_head(&self, key: &Self::Key) -> Self::Bool {
+        let key = key.clone();
+        self.retry(move |heads| heads.is_head(&key).boxed())
+    }
+
+    fn heads(&self) -> Self::Heads {
+        self.heads.heads()
+    }
+
+    /// The operations are applied by the wrapped store, and retried together as a unit.
+    fn apply(&self, ops: Vec<HeadOp<Self::Key>>) -> BoxFuture<(), Self::Error> {
+        self.retry(move |heads| heads.apply(ops.clone()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use futures::future::FutureResult;
+    use futures::stream::{self, BoxStream, Stream};
+
+    /// Store whose operations fail with `kind` until they've been called `failures` times.
+    struct FlakyHeads {
+        calls: AtomicUsize,
+        failures: usize,
+        kind: io::ErrorKind,
+    }
+
+    impl FlakyHeads {
+        fn new(failures: usize, kind: io::ErrorKind) -> Self {
+            FlakyHeads {
+                calls: AtomicUsize::new(0),
+                failures: failures,
+                kind: kind,
+            }
+        }
+
+        fn call<T>(&self, value: T) -> FutureResult<T, io::Error> {
+            if self.calls.fetch_add(1, Ordering::SeqCst) < self.failures {
+                future::err(io::Error::new(self.kind, "flaky"))
+            } else {
+                future::ok(value)
+            }
+        }
+    }
+
+    impl Heads for FlakyHeads {
+        type Key = String;
+        type Error = io::Error;
+
+        type Unit = FutureResult<(), Self::Error>;
+        type Bool = FutureResult<bool, Self::Error>;
+        type Heads = BoxStream<Self::Key, Self::Error>;
+
+        fn add(&self, _key: &Self::Key) -> Self::Unit {
+            self.call(())
+        }
+
+        fn remove(&self, _key: &Self::Key) -> Self::Unit {
+            self.call(())
+        }
+
+        fn is_head(&self, _key: &Self::Key) -> Self::Bool {
+            self.call(true)
+        }
+
+        fn heads(&self) -> Self::Heads {
+            stream::empty().boxed()
+        }
+    }
+
+    fn retrying(
+        failures: usize,
+        kind: io::ErrorKind,
+    ) -> RetryingHeads<FlakyHeads, Backoff<fn(&io::Error) -> bool>> {
+        let policy = Backoff::new(
+            3,
+            Duration::from_millis(1),
+            is_transient_io_error as fn(&io::Error) -> bool,
+        );
+        RetryingHeads::new(FlakyHeads::new(failures, kind), policy)
+    }
+
+    #[test]
+    fn retries() {
+        let foo = "foo".to_string();
+
+        let heads = retrying(2, io::ErrorKind::WouldBlock);
+        heads.add(&foo).wait().unwrap();
+        assert_eq!(heads.inner().calls.load(Ordering::SeqCst), 3);
+        assert!(heads.is_head(&foo).wait().unwrap());
+
+        // It gives up after the last attempt.
+        let heads = retrying(3, io::ErrorKind::Interrupted);
+        assert!(heads.remove(&foo).wait().is_err());
+        assert_eq!(heads.inner().calls.load(Ordering::SeqCst), 3);
+
+        // Errors which aren't transient aren't retried.
+        let heads = retrying(1, io::ErrorKind::PermissionDenied);
+        assert!(heads.add(&foo).wait().is_err());
+        assert_eq!(heads.inner().calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn backoff() {
+        let policy = Backoff::new(4, Duration::from_millis(10), |_: &()| true);
+        assert_eq!(policy.retry(1, &()), Some(Duration::from_millis(10)));
+        assert_eq!(policy.retry(2, &()), Some(Duration::from_millis(20)));
+        assert_eq!(policy.retry(3, &()), Some(Duration::from_millis(40)));
+        assert_eq!(policy.retry(4, &()), None);
+    }
+}
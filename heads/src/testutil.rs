@@ -0,0 +1,72 @@
+// Copyright (c) 2004-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Helpers for testing `Heads` wrappers.
+
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::sync::Mutex;
+
+use futures::future::{FutureResult, ok};
+use futures::stream::{self, BoxStream, Stream};
+
+use Heads;
+
+/// In-memory heads store which records how many times each operation was called.
+pub struct TestHeads {
+    heads: Mutex<HashSet<String>>,
+    calls: Mutex<HashMap<&'static str, usize>>,
+}
+
+impl TestHeads {
+    pub fn new() -> Self {
+        TestHeads {
+            heads: Mutex::new(HashSet::new()),
+            calls: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Return the number of times the operation `op` has been called.
+    pub fn calls(&self, op: &str) -> usize {
+        self.calls.lock().unwrap().get(op).cloned().unwrap_or(0)
+    }
+
+    fn record(&self, op: &'static str) {
+        *self.calls.lock().unwrap().entry(op).or_insert(0) += 1;
+    }
+}
+
+impl Heads for TestHeads {
+    type Key = String;
+    type Error = io::Error;
+
+    type Unit = FutureResult<(), Self::Error>;
+    type Bool = FutureResult<bool, Self::Error>;
+    type Heads = BoxStream<Self::Key, Self::Error>;
+
+    fn add(&self, key: &Self::Key) -> Self::Unit {
+        self.record("add");
+        self.heads.lock().unwrap().insert(key.clone());
+        ok(())
+    }
+
+    fn remove(&self, key: &Self::Key) -> Self::Unit {
+        self.record("remove");
+        self.heads.lock().unwrap().remove(key);
+        ok(())
+    }
+
+    fn is_head(&self, key: &Self::Key) -> Self::Bool {
+        self.record("is_head");
+        ok(self.heads.lock().unwrap().contains(key))
+    }
+
+    fn heads(&self) -> Self::Heads {
+        self.record("heads");
+        let heads = self.heads.lock().unwrap().clone();
+        stream::iter(heads.into_iter().map(Ok)).boxed()
+    }
+}
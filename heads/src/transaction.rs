@@ -0,0 +1,50 @@
+// Copyright (c) 2004-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+use futures::future::BoxFuture;
+
+use Heads;
+
+/// A single change to a heads store.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum HeadOp<K> {
+    Add(K),
+    Remove(K),
+}
+
+/// Builder for a group of changes to be applied to a heads store together. Nothing happens
+/// until `commit` is called; see `Heads::apply` for the guarantees a store provides.
+pub struct Transaction<'a, H: Heads + 'a> {
+    heads: &'a H,
+    ops: Vec<HeadOp<H::Key>>,
+}
+
+impl<'a, H: Heads + 'a> Transaction<'a, H> {
+    pub fn new(heads: &'a H) -> Self {
+        Transaction {
+            heads: heads,
+            ops: Vec::new(),
+        }
+    }
+
+    pub fn add(mut self, key: H::Key) -> Self {
+        self.ops.push(HeadOp::Add(key));
+        self
+    }
+
+    pub fn remove(mut self, key: H::Key) -> Self {
+        self.ops.push(HeadOp::Remove(key));
+        self
+    }
+
+    pub fn ops(&self) -> &[HeadOp<H::Key>] {
+        &self.ops
+    }
+
+    pub fn commit(self) -> BoxFuture<(), H::Error> {
+        self.heads.apply(self.ops)
+    }
+}
@@ -0,0 +1,136 @@
+// Copyright (c) 2004-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+use std::sync::Arc;
+
+use futures::{Future, Stream};
+use futures::future::BoxFuture;
+use futures::stream::BoxStream;
+
+use {HeadOp, Heads};
+
+/// Which store of a `UnionHeads` receives writes.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Primary {
+    First,
+    Second,
+}
+
+/// A heads store which reads from two stores and writes to one of them, for instance while
+/// migrating from one backend to another.
+///
+/// A key is a head if it is a head in either store. Adds, removes and transactions only go to
+/// the primary store, so removing a head which is also present in the other store doesn't
+/// stop it being a head of the union.
+pub struct UnionHeads<A, B> {
+    first: Arc<A>,
+    second: Arc<B>,
+    primary: Primary,
+}
+
+impl<A, B> UnionHeads<A, B>
+where
+    A: Heads + Sync,
+    B: Heads<Key = A::Key> + Sync,
+    A::Error: From<B::Error>,
+{
+    pub fn new(first: A, second: B, primary: Primary) -> Self {
+        UnionHeads {
+            first: Arc::new(first),
+            second: Arc::new(second),
+            primary: primary,
+        }
+    }
+}
+
+impl<A, B> Heads for UnionHeads<A, B>
+where
+    A: Heads + Sync,
+    B: Heads<Key = A::Key> + Sync,
+    A::Error: From<B::Error>,
+{
+    type Key = A::Key;
+    type Error = A::Error;
+
+    type Unit = BoxFuture<(), Self::Error>;
+    type Bool = BoxFuture<bool, Self::Error>;
+    type Heads = BoxStream<Self::Key, Self::Error>;
+
+    fn add(&self, key: &Self::Key) -> Self::Unit {
+        match self.primary {
+            Primary::First => self.first.add(key).boxed(),
+            Primary::Second => self.second.add(key).from_err().boxed(),
+        }
+    }
+
+    fn remove(&self, key: &Self::Key) -> Self::Unit {
+        match self.primary {
+            Primary::First => self.first.remove(key).boxed(),
+            Primary::Second => self.second.remove(key).from_err().boxed(),
+        }
+    }
+
+    fn is_head(&self, key: &Self::Key) -> Self::Bool {
+        self.first
+            .is_head(key)
+            .join(self.second.is_head(key).from_err())
+            .map(|(first, second)| first || second)
+            .boxed()
+    }
+
+    /// Return the heads of the first store followed by those of the second. Rather than
+    /// remembering everything returned from the first store, heads of the second are checked
+    /// against the first store individually and skipped if present there.
+    fn heads(&self) -> Self::Heads {
+        let first = self.first.clone();
+        let second = self.second
+            .heads()
+            .from_err()
+            .and_then(move |key| first.is_head(&key).map(move |dup| (key, dup)))
+            .filter_map(|(key, dup)| if dup { None } else { Some(key) });
+        self.first.heads().chain(second).boxed()
+    }
+
+    fn apply(&self, ops: Vec<HeadOp<Self::Key>>) -> BoxFuture<(), Self::Error> {
+        match self.primary {
+            Primary::First => self.first.apply(ops),
+            Primary::Second => self.second.apply(ops).from_err().boxed(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use testutil::TestHeads;
+
+    #[test]
+    fn union() {
+        let heads = UnionHeads::new(TestHeads::new(), TestHeads::new(), Primary::First);
+        let foo = "foo".to_string();
+        let bar = "bar".to_string();
+        let baz = "baz".to_string();
+
+        heads.first.add(&foo).wait().unwrap();
+        heads.second.add(&foo).wait().unwrap();
+        heads.second.add(&bar).wait().unwrap();
+
+        // `bar` is only in the secondary store.
+        assert!(heads.is_head(&bar).wait().unwrap());
+        assert!(!heads.is_head(&baz).wait().unwrap());
+
+        let mut result = heads.heads().collect().wait().unwrap();
+        result.sort();
+        assert_eq!(result, vec![bar.clone(), foo.clone()]);
+
+        // Writes only go to the primary.
+        heads.add(&baz).wait().unwrap();
+        assert!(heads.first.is_head(&baz).wait().unwrap());
+        assert!(!heads.second.is_head(&baz).wait().unwrap());
+        heads.remove(&bar).wait().unwrap();
+        assert!(heads.is_head(&bar).wait().unwrap());
+    }
+}
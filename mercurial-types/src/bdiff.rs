@@ -4,6 +4,8 @@
 // This software may be used and distributed according to the terms of the
 // GNU General Public License version 2 or any later version.
 
+use std::cmp;
+
 /// A single delta in a revlog or bundle.
 ///
 /// The range from `start`-`end` is replaced with the `content`.
@@ -46,9 +48,110 @@ pub fn apply(text: &[u8], deltas: &[Delta]) -> Vec<u8> {
     ret
 }
 
+// Comparing larger middle sections line by line takes too much memory, so they're replaced in
+// a single `Delta`.
+const MAX_DIFF_CELLS: usize = 1 << 22;
+
+// Offsets of the start of each line in `text`, followed by the length of `text`. Line `i` is
+// `text[offsets[i]..offsets[i + 1]]`, including its newline.
+fn line_offsets(text: &[u8]) -> Vec<usize> {
+    let mut offsets = vec![0];
+    offsets.extend(
+        text.iter()
+            .enumerate()
+            .filter(|&(_, &c)| c == b'\n')
+            .map(|(i, _)| i + 1),
+    );
+    if offsets[offsets.len() - 1] != text.len() {
+        offsets.push(text.len());
+    }
+    offsets
+}
+
+/// Compute a set of `Delta`s which turn `old` into `new`, comparing them line by line like
+/// Mercurial's bdiff. Applying the result to `old` with `apply` gives `new`.
+pub fn diff(old: &[u8], new: &[u8]) -> Vec<Delta> {
+    let aoff = line_offsets(old);
+    let boff = line_offsets(new);
+    let a: Vec<_> = aoff.windows(2).map(|w| &old[w[0]..w[1]]).collect();
+    let b: Vec<_> = boff.windows(2).map(|w| &new[w[0]..w[1]]).collect();
+
+    // Lines at the start and end which haven't changed don't need comparing.
+    let mut prefix = 0;
+    while prefix < a.len() && prefix < b.len() && a[prefix] == b[prefix] {
+        prefix += 1;
+    }
+    let mut suffix = 0;
+    while suffix < a.len() - prefix && suffix < b.len() - prefix &&
+        a[a.len() - 1 - suffix] == b[b.len() - 1 - suffix]
+    {
+        suffix += 1;
+    }
+    let a = &a[prefix..a.len() - suffix];
+    let b = &b[prefix..b.len() - suffix];
+    let (n, m) = (a.len(), b.len());
+
+    let mut deltas = Vec::new();
+    {
+        let mut push = |i0: usize, i1: usize, j0: usize, j1: usize| {
+            deltas.push(Delta {
+                start: aoff[prefix + i0],
+                end: aoff[prefix + i1],
+                content: new[boff[prefix + j0]..boff[prefix + j1]].to_vec(),
+            })
+        };
+
+        if n == 0 && m == 0 {
+            // Nothing changed.
+        } else if n == 0 || m == 0 || (n + 1) * (m + 1) > MAX_DIFF_CELLS {
+            push(0, n, 0, m);
+        } else {
+            // lcs[i * (m + 1) + j] is the length of the longest common subsequence of a[i..]
+            // and b[j..].
+            let mut lcs = vec![0u32; (n + 1) * (m + 1)];
+            for i in (0..n).rev() {
+                for j in (0..m).rev() {
+                    lcs[i * (m + 1) + j] = if a[i] == b[j] {
+                        lcs[(i + 1) * (m + 1) + j + 1] + 1
+                    } else {
+                        cmp::max(lcs[(i + 1) * (m + 1) + j], lcs[i * (m + 1) + j + 1])
+                    };
+                }
+            }
+
+            // Walk the table, turning each run of unmatched lines into a `Delta`.
+            let (mut i, mut j) = (0, 0);
+            let mut start = None;
+            while i < n && j < m {
+                if a[i] == b[j] {
+                    if let Some((i0, j0)) = start.take() {
+                        push(i0, i, j0, j);
+                    }
+                    i += 1;
+                    j += 1;
+                } else {
+                    if start.is_none() {
+                        start = Some((i, j));
+                    }
+                    if lcs[(i + 1) * (m + 1) + j] >= lcs[i * (m + 1) + j + 1] {
+                        i += 1;
+                    } else {
+                        j += 1;
+                    }
+                }
+            }
+            if i < n || j < m || start.is_some() {
+                let (i0, j0) = start.unwrap_or((i, j));
+                push(i0, n, j0, m);
+            }
+        }
+    }
+    deltas
+}
+
 #[cfg(test)]
 mod test {
-    use super::{Delta, apply};
+    use super::{Delta, apply, diff};
 
     #[test]
     fn test_1() {
@@ -154,4 +257,51 @@ mod test {
         assert_eq!(&res[..], b"aaaa\ncccc\n");
     }
 
+    #[test]
+    fn test_diff() {
+        let texts: [&[u8]; 8] = [
+            b"",
+            b"aaaa\nbbbb\ncccc\n",
+            b"aaaa\nxxxx\ncccc\n",
+            b"aaaa\ncccc\n",
+            b"zzzz\naaaa\nbbbb\ncccc\ndddd",
+            b"bbbb\naaaa\ncccc\nbbbb\n",
+            b"no newline",
+            b"\n\n\n",
+        ];
+        for old in texts.iter() {
+            for new in texts.iter() {
+                assert_eq!(&apply(old, &diff(old, new))[..], *new);
+            }
+        }
+
+        // Unchanged lines are left alone.
+        assert_eq!(diff(texts[1], texts[1]), vec![]);
+        assert_eq!(
+            diff(texts[1], texts[2]),
+            vec![
+                Delta {
+                    start: 5,
+                    end: 10,
+                    content: (&b"xxxx\n"[..]).into(),
+                },
+            ]
+        );
+        assert_eq!(
+            diff(texts[1], texts[4]),
+            vec![
+                Delta {
+                    start: 0,
+                    end: 0,
+                    content: (&b"zzzz\n"[..]).into(),
+                },
+                Delta {
+                    start: 15,
+                    end: 15,
+                    content: (&b"dddd"[..]).into(),
+                },
+            ]
+        );
+    }
+
 }
@@ -4,7 +4,6 @@
 // This software may be used and distributed according to the terms of the
 // GNU General Public License version 2 or any later version.
 
-use hash::{self, Context};
 use nodehash::NodeHash;
 
 use blob::Blob;
@@ -106,24 +105,17 @@ where
     // sha1(p1 || p2 || sha1(content)), so we can't compute a filenode for
     // a blob we don't have
     pub fn nodeid(&self) -> Option<NodeHash> {
-        let null = hash::NULL;
+        let null = NodeHash::null();
 
-        let (h1, h2) = match &self.parents {
+        let (p1, p2) = match &self.parents {
             &Parents::None => (&null, &null),
-            &Parents::One(ref p1) => (&null, p1.sha1()),
-            &Parents::Two(ref p1, ref p2) if p1 > p2 => (p2.sha1(), p1.sha1()),
-            &Parents::Two(ref p1, ref p2) => (p1.sha1(), p2.sha1()),
+            &Parents::One(ref p1) => (p1, &null),
+            &Parents::Two(ref p1, ref p2) => (p1, p2),
         };
 
-        self.as_blob().as_slice().map(|data| {
-            let mut ctxt = Context::new();
-
-            ctxt.update(h1);
-            ctxt.update(h2);
-            ctxt.update(data);
-
-            NodeHash::new(ctxt.finish())
-        })
+        self.as_blob()
+            .as_slice()
+            .map(|data| NodeHash::from_parents_and_data(p1, p2, data))
     }
 }
 
@@ -46,7 +46,7 @@ pub mod changeset;
 mod node;
 
 pub use delta::Delta;
-pub use nodehash::{NULL_HASH, NodeHash};
+pub use nodehash::{NULL_HASH, NodeHash, NodeHashPrefix};
 pub use path::Path;
 pub use utils::percent_encode;
 pub use repo::Repo;
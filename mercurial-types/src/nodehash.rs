@@ -14,7 +14,7 @@ use ascii::{AsciiStr, AsciiString};
 use quickcheck::{Arbitrary, Gen, single_shrinker};
 
 use errors::*;
-use hash::{self, Sha1};
+use hash::{self, Context, Sha1};
 use serde;
 
 pub const NULL_HASH: NodeHash = NodeHash(hash::NULL);
@@ -28,6 +28,17 @@ impl NodeHash {
         NodeHash(sha1)
     }
 
+    /// Return the null hash, which Mercurial uses for missing parents and empty manifests.
+    #[inline]
+    pub fn null() -> NodeHash {
+        NULL_HASH
+    }
+
+    #[inline]
+    pub fn is_null(&self) -> bool {
+        *self == NULL_HASH
+    }
+
     pub fn from_bytes(bytes: &[u8]) -> Result<NodeHash> {
         Sha1::from_bytes(bytes).map(NodeHash)
     }
@@ -37,6 +48,20 @@ impl NodeHash {
         Sha1::from_ascii_str(s).map(NodeHash)
     }
 
+    /// Compute the nodeid of a revision from its parents and content, which Mercurial defines
+    /// as the sha1 of the two parent hashes, smallest first, followed by the content. Missing
+    /// parents are given as the null hash.
+    pub fn from_parents_and_data(p1: &NodeHash, p2: &NodeHash, data: &[u8]) -> NodeHash {
+        let (h1, h2) = if p1 > p2 { (p2, p1) } else { (p1, p2) };
+
+        let mut ctxt = Context::new();
+        ctxt.update(h1.sha1());
+        ctxt.update(h2.sha1());
+        ctxt.update(data);
+
+        NodeHash::new(ctxt.finish())
+    }
+
     pub fn sha1(&self) -> &Sha1 {
         &self.0
     }
@@ -45,6 +70,53 @@ impl NodeHash {
     pub fn to_hex(&self) -> AsciiString {
         self.0.to_hex()
     }
+
+    /// Parse an abbreviated hash, of between 1 and 40 hex digits, into a `NodeHashPrefix`
+    /// which can be used to find the nodes it could refer to.
+    pub fn from_prefix(prefix: &str) -> Result<NodeHashPrefix> {
+        if prefix.is_empty() || prefix.len() > 40 {
+            bail!(ErrorKind::InvalidSha1Input(
+                format!("prefix must be 1 to 40 hex digits, got {}", prefix.len()),
+            ));
+        }
+        if !prefix.chars().all(|c| c.is_digit(16)) {
+            bail!(ErrorKind::InvalidSha1Input(
+                format!("prefix {} isn't hex", prefix),
+            ));
+        }
+
+        Ok(NodeHashPrefix(prefix.to_lowercase()))
+    }
+}
+
+/// An abbreviated `NodeHash`, made by `NodeHash::from_prefix`.
+#[derive(Clone, Debug, Eq, PartialEq, Hash)]
+pub struct NodeHashPrefix(String);
+
+impl NodeHashPrefix {
+    /// Return `true` if `hash` starts with this prefix.
+    pub fn matches(&self, hash: &NodeHash) -> bool {
+        hash.to_hex().as_str().starts_with(&self.0)
+    }
+
+    /// Return the full hash if the prefix is all 40 digits of one.
+    pub fn to_full(&self) -> Option<NodeHash> {
+        if self.0.len() == 40 {
+            NodeHash::from_str(&self.0).ok()
+        } else {
+            None
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl Display for NodeHashPrefix {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.write_str(&self.0)
+    }
 }
 
 
@@ -87,6 +159,16 @@ impl<'de> serde::de::Deserialize<'de> for NodeHash {
         D: serde::de::Deserializer<'de>,
     {
         let hex = deserializer.deserialize_string(StringVisitor)?;
+
+        // Only accept exactly what `serialize` produces, so each hash has a single serialized
+        // form.
+        if hex.len() != 40 || !hex.chars().all(|c| c.is_digit(16) && !c.is_uppercase()) {
+            return Err(serde::de::Error::invalid_value(
+                serde::de::Unexpected::Str(&hex),
+                &"40 lowercase hex digits",
+            ));
+        }
+
         match Sha1::from_str(hex.as_str()) {
             Ok(sha1) => Ok(NodeHash::new(sha1)),
             Err(error) => Err(serde::de::Error::custom(error)),
@@ -135,3 +217,75 @@ impl Arbitrary for NodeHash {
         single_shrinker(NULL_HASH)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const HASH: &str = "33d68faa8d39ec00ce3850870c4e3ee6c8493e4b";
+
+    #[test]
+    fn null() {
+        assert!(NodeHash::null().is_null());
+        assert_eq!(
+            NodeHash::from_str("0000000000000000000000000000000000000000").unwrap(),
+            NodeHash::null()
+        );
+        assert!(!NodeHash::from_str(HASH).unwrap().is_null());
+    }
+
+    #[test]
+    fn from_parents_and_data() {
+        let text0 = b"alpha\nbeta\ngamma\n";
+        let text1 = b"alpha\nBETA\ngamma\n";
+        let node0 = NodeHash::from_str("1aa8663bd94a3cf6065c24e16463707c2cfa7610").unwrap();
+        let node1 = NodeHash::from_str("a383dc3b93c51c7012f03c8360fdf58479030266").unwrap();
+        let null = NodeHash::null();
+
+        assert_eq!(NodeHash::from_parents_and_data(&null, &null, text0), node0);
+        // The order the parents are given in doesn't matter.
+        assert_eq!(NodeHash::from_parents_and_data(&node0, &null, text1), node1);
+        assert_eq!(NodeHash::from_parents_and_data(&null, &node0, text1), node1);
+    }
+
+    #[test]
+    fn deserialize_strict() {
+        use serde::de::{Deserialize, IntoDeserializer};
+        use serde::de::value::Error as ValueError;
+
+        let deserialize = |s: &str| {
+            let deserializer: ::serde::de::value::StrDeserializer<ValueError> =
+                s.into_deserializer();
+            NodeHash::deserialize(deserializer)
+        };
+
+        assert_eq!(deserialize(HASH).unwrap(), NodeHash::from_str(HASH).unwrap());
+        assert!(deserialize(&HASH.to_uppercase()).is_err());
+        assert!(deserialize(&format!("{}0", HASH)).is_err());
+        assert!(deserialize(&HASH[..39]).is_err());
+    }
+
+    #[test]
+    fn prefix() {
+        let hash = NodeHash::from_str(HASH).unwrap();
+        let other = NodeHash::from_str("9ae37abdde3ae6c95ec70eac859e3b589372f3d9").unwrap();
+
+        let exact = NodeHash::from_prefix(HASH).unwrap();
+        assert!(exact.matches(&hash));
+        assert!(!exact.matches(&other));
+        assert_eq!(exact.to_full(), Some(hash));
+
+        let prefix = NodeHash::from_prefix("33D68f").unwrap();
+        assert!(prefix.matches(&hash));
+        assert!(!prefix.matches(&other));
+        assert_eq!(prefix.to_full(), None);
+        assert_eq!(prefix.as_str(), "33d68f");
+    }
+
+    #[test]
+    fn bad_prefix() {
+        assert!(NodeHash::from_prefix("").is_err());
+        assert!(NodeHash::from_prefix("33d6g").is_err());
+        assert!(NodeHash::from_prefix(&format!("{}0", HASH)).is_err());
+    }
+}
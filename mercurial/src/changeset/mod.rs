@@ -10,7 +10,7 @@ use std::collections::BTreeMap;
 use std::result;
 use std::error;
 
-use mercurial_types::{BlobNode, NULL_HASH, NodeHash, Parents, Path};
+use mercurial_types::{BlobNode, NodeHash, Parents, Path};
 use mercurial_types::changeset::{Changeset, Time};
 use errors::*;
 
@@ -195,7 +195,7 @@ impl RevlogChangeset {
         // partially initialized RevlogChangeset then fill it in as we go.
         let mut ret = Self {
             parents: *node.parents(),
-            manifestid: NULL_HASH,
+            manifestid: NodeHash::null(),
             user: Vec::new(),
             time: Time { time: 0, tz: 0 },
             extra: Extra(BTreeMap::new()),
@@ -20,6 +20,56 @@ error_chain! {
             description("revlog error")
             display("{}", msg)
         }
+        UnsupportedRevlogVersion(version: u16) {
+            description("unsupported revlog version")
+            display("unsupported revlog version {:#x}", version)
+        }
+        UnknownCompression(marker: u8) {
+            description("unknown revlog compression")
+            display("unknown revlog compression marker {:#x}", marker)
+        }
+        Censored(idx: ::revlog::RevIdx) {
+            description("revision is censored")
+            display("revision {:?} is censored", idx)
+        }
+        UnsupportedFlag(idx: ::revlog::RevIdx, flag: u16) {
+            description("revision has an unsupported flag")
+            display("revision {} has unsupported flag {:#x}", idx, flag)
+        }
+        RevTooLarge(idx: ::revlog::RevIdx, size: usize, limit: usize) {
+            description("revision is too large to reconstruct")
+            display("revision {} would be at least {} bytes, over the limit of {}",
+                    idx, size, limit)
+        }
+        TruncatedIndex(valid_entries: usize, extra_bytes: usize) {
+            description("revlog index ends with a partial entry")
+            display("revlog index has {} complete entries followed by {} extra bytes",
+                    valid_entries, extra_bytes)
+        }
+        DataFileTooShort(expected: u64, actual: u64) {
+            description("revlog data is too short for its index")
+            display("revlog index needs {} bytes of data, but the data file only has {}; \
+                     is it the data file of another revlog?",
+                    expected, actual)
+        }
+        NoMatchingNode(prefix: ::mercurial_types::NodeHashPrefix) {
+            description("no node matches prefix")
+            display("no nodeid starts with {}", prefix)
+        }
+        AmbiguousPrefix(prefix: ::mercurial_types::NodeHashPrefix,
+                        candidates: Vec<::mercurial_types::NodeHash>) {
+            description("prefix matches more than one node")
+            display("{} is ambiguous: it could be any of {}", prefix,
+                    candidates.iter().map(ToString::to_string).collect::<Vec<_>>().join(", "))
+        }
+        NoSuchRevision(idx: ::revlog::RevIdx) {
+            description("no such revision in revlog")
+            display("revision {} not found", idx)
+        }
+        NoSuchNode(nodeid: ::mercurial_types::NodeHash) {
+            description("no such node in revlog")
+            display("nodeid {} not found", nodeid)
+        }
         Repo(msg: String) {
             description("repo error")
             display("{}", msg)
@@ -9,6 +9,7 @@
 // External dependencies
 extern crate flate2;
 extern crate futures;
+extern crate futures_cpupool;
 
 #[macro_use]
 extern crate error_chain;
@@ -26,10 +27,14 @@ extern crate memmap;
 extern crate lz4;
 extern crate time;
 extern crate itertools;
+extern crate linked_hash_map;
+extern crate zstd;
 
 #[cfg(test)]
 #[macro_use]
 extern crate quickcheck;
+#[cfg(test)]
+extern crate tempdir;
 
 extern crate asyncmemo;
 extern crate mercurial_types;
@@ -4,18 +4,26 @@
 // This software may be used and distributed according to the terms of the
 // GNU General Public License version 2 or any later version.
 
-use std::io;
+use std::cmp;
+use std::io::{self, Read, Write};
 use std::path::Path;
-use std::collections::{BTreeMap, HashMap, HashSet};
+use std::collections::{BTreeMap, BinaryHeap, HashMap, HashSet};
 use std::result;
+use std::vec;
 use std::sync::{Arc, Mutex};
 use std::fmt::Debug;
+use std::ops::Range;
 
 use errors::*;
+use flate2::Compression;
+use flate2::write::ZlibEncoder;
+use futures::future::{self, BoxFuture, Future};
+use futures_cpupool::CpuPool;
+use linked_hash_map::LinkedHashMap;
 use nom::IResult;
 use memmap::{self, Mmap};
 
-use mercurial_types::{Blob, BlobNode, NodeHash};
+use mercurial_types::{Blob, BlobNode, NodeHash, NodeHashPrefix};
 pub use mercurial_types::bdiff::{self, Delta};
 pub use mercurial_types::delta;
 
@@ -23,6 +31,7 @@ pub use mercurial_types::delta;
 mod parser;
 mod revidx;
 mod lz4;
+mod zstd;
 
 #[cfg(test)]
 mod test;
@@ -30,21 +39,46 @@ mod test;
 use self::parser::{Entry, Header, Version};
 pub use self::revidx::RevIdx;
 
-#[derive(Debug)]
+// Clones share the underlying data. Loaded data is copied the first time a shared clone is
+// changed.
+#[derive(Clone, Debug)]
 enum Datafile {
-    Loaded(Vec<u8>),
-    Mmap(Mmap),
+    Loaded(Arc<Vec<u8>>),
+    // The length is how much of the mapping is used, which may be less than the whole file.
+    Mmap(Arc<Mmap>, usize),
 }
 
 impl Datafile {
+    fn loaded(data: Vec<u8>) -> Datafile {
+        Datafile::Loaded(Arc::new(data))
+    }
+
     fn map<P: AsRef<Path>>(path: P) -> io::Result<Datafile> {
-        Mmap::open_path(path, memmap::Protection::Read).map(Datafile::Mmap)
+        let mmap = Mmap::open_path(path, memmap::Protection::Read)?;
+        let len = mmap.len();
+        Ok(Datafile::Mmap(Arc::new(mmap), len))
     }
 
     fn as_slice(&self) -> &[u8] {
         match self {
-            &Datafile::Loaded(ref data) => data.as_ref(),
-            &Datafile::Mmap(ref mmap) => unsafe { mmap.as_slice() },
+            &Datafile::Loaded(ref data) => &data[..],
+            &Datafile::Mmap(ref mmap, len) => unsafe { &mmap.as_slice()[..len] },
+        }
+    }
+
+    // Mapped files are read-only, so only loaded data can be changed.
+    fn as_mut_vec(&mut self) -> Option<&mut Vec<u8>> {
+        match self {
+            &mut Datafile::Loaded(ref mut data) => Some(Arc::make_mut(data)),
+            &mut Datafile::Mmap(..) => None,
+        }
+    }
+
+    // Ignore everything after the first `len` bytes.
+    fn truncate(&mut self, len: usize) {
+        match self {
+            &mut Datafile::Loaded(ref mut data) => Arc::make_mut(data).truncate(len),
+            &mut Datafile::Mmap(_, ref mut maplen) => *maplen = cmp::min(*maplen, len),
         }
     }
 }
@@ -65,6 +99,101 @@ where
     }
 }
 
+// Compress the text of a revision the way Mercurial does: with zlib if that makes it smaller,
+// otherwise stored as is, with a 'u' marker unless it starts with a NUL.
+fn compress_chunk(text: &[u8]) -> Result<Vec<u8>> {
+    let mut enc = ZlibEncoder::new(Vec::new(), Compression::Default);
+    enc.write_all(text)?;
+    let compressed = enc.finish()?;
+
+    if compressed.len() < text.len() {
+        Ok(compressed)
+    } else if text.first().map_or(true, |b| *b == 0) {
+        Ok(text.to_vec())
+    } else {
+        let mut chunk = Vec::with_capacity(text.len() + 1);
+        chunk.push(b'u');
+        chunk.extend_from_slice(text);
+        Ok(chunk)
+    }
+}
+
+// Encode an entry in the version 1 ("NG") index format.
+fn encode_entry_ng(entry: &Entry) -> Vec<u8> {
+    fn rev(idx: Option<RevIdx>) -> u32 {
+        idx.map_or(!0, u32::from)
+    }
+
+    let mut buf = Vec::with_capacity(parser::indexng_size());
+    let offset_flags = (entry.offset << 16) | entry.flags.bits() as u64;
+    push_u32(&mut buf, (offset_flags >> 32) as u32);
+    push_u32(&mut buf, offset_flags as u32);
+    push_u32(&mut buf, entry.compressed_len);
+    push_u32(&mut buf, entry.len.unwrap_or(0));
+    push_u32(&mut buf, rev(entry.baserev));
+    push_u32(&mut buf, rev(entry.linkrev));
+    push_u32(&mut buf, rev(entry.p1));
+    push_u32(&mut buf, rev(entry.p2));
+    buf.extend_from_slice(entry.nodeid.as_ref());
+    buf.resize(parser::indexng_size(), 0);
+    buf
+}
+
+// Start of the output of `Revlog::export_range`, identifying the format.
+const EXPORT_MAGIC: &'static [u8] = b"HGREVEXPORT1";
+
+// Size of the fixed fields at the start of an exported frame: the nodeid, the parents' nodeids
+// and the linkrev.
+const EXPORT_FRAME_HEADER_SIZE: usize = 3 * 20 + 4;
+
+fn push_u32(buf: &mut Vec<u8>, v: u32) {
+    buf.extend_from_slice(&[(v >> 24) as u8, (v >> 16) as u8, (v >> 8) as u8, v as u8]);
+}
+
+fn read_u32(buf: &[u8]) -> u32 {
+    (buf[0] as u32) << 24 | (buf[1] as u32) << 16 | (buf[2] as u32) << 8 | buf[3] as u32
+}
+
+// Read the length at the start of an exported frame, or return `None` at the end of the input.
+fn read_frame_len<R: Read>(input: &mut R) -> Result<Option<usize>> {
+    let mut buf = [0u8; 4];
+    let mut got = 0;
+    while got < buf.len() {
+        match input.read(&mut buf[got..]) {
+            Ok(0) if got == 0 => return Ok(None),
+            Ok(0) => bail!(ErrorKind::Revlog("export ends part way through a frame".into())),
+            Ok(n) => got += n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(Some(read_u32(&buf) as usize))
+}
+
+// Size of an index entry, not counting any inline data.
+fn fixed_entry_size(version: Version) -> usize {
+    match version {
+        Version::Revlog0 => parser::index0_size(),
+        Version::RevlogNG => parser::indexng_size(),
+        Version::RevlogV2 => parser::indexv2_size(),
+    }
+}
+
+// Make sure the content of a revision can be returned as is. Censored revisions have been
+// replaced by a tombstone, and revisions with other flags need resolving in ways which aren't
+// supported, so they would otherwise be returned as something other than their content.
+fn check_flags(idx: RevIdx, entry: &Entry) -> Result<()> {
+    if entry.is_censored() {
+        bail!(ErrorKind::Censored(idx));
+    }
+    for flag in &[parser::ELLIPSIS, parser::EXTSTORED] {
+        if entry.flags().contains(*flag) {
+            bail!(ErrorKind::UnsupportedFlag(idx, flag.bits()));
+        }
+    }
+    Ok(())
+}
+
 /// `Revlog` represents a Mercurial revlog structure
 ///
 /// A Mercurial revlog logicically consists of two parts: an index containing metadata about each
@@ -85,6 +214,11 @@ struct RevlogInner {
     data: Option<Datafile>,
     idxoff: BTreeMap<RevIdx, usize>, // cache of index -> offset
     nodeidx: HashMap<NodeHash, RevIdx>, // cache of nodeid -> index
+    nodeidx_complete: bool, // whether nodeidx has every entry
+    revcache: LinkedHashMap<RevIdx, Vec<u8>>, // LRU cache of reconstructed revisions
+    revcache_capacity: usize,
+    deltas_applied: usize, // number of deltas applied to reconstruct revisions
+    max_rev_size: Option<usize>, // largest revision that may be reconstructed
 }
 
 impl PartialEq<Self> for Revlog {
@@ -95,7 +229,15 @@ impl PartialEq<Self> for Revlog {
 impl Eq for Revlog {}
 
 impl Revlog {
-    fn init(idx: Datafile, data: Option<Datafile>) -> Result<Self> {
+    fn init(idx: Datafile, data: Option<Datafile>, tolerate_truncation: bool) -> Result<Self> {
+        // Check the version separately so that an unknown one can be reported clearly.
+        if let Some(version) = idx.as_slice().get(2..4) {
+            let version = ((version[0] as u16) << 8) | version[1] as u16;
+            if Version::from_u16(version).is_none() {
+                bail!(ErrorKind::UnsupportedRevlogVersion(version));
+            }
+        }
+
         let hdr = match parser::header(idx.as_slice()) {
             IResult::Done(_, hdr) => hdr,
             err => {
@@ -106,10 +248,25 @@ impl Revlog {
         };
 
         let mut data = data;
-        if hdr.features.contains(parser::INLINE) {
+        if hdr.is_inline() {
             data = None
         }
 
+        // Without inline data every entry is the same size, so an index whose last entry was
+        // only partially written can be spotted from its length.
+        let mut idx = idx;
+        if !hdr.is_inline() {
+            let entry_size = fixed_entry_size(hdr.version());
+            let idxlen = idx.as_slice().len();
+            let extra = idxlen % entry_size;
+            if extra != 0 {
+                if !tolerate_truncation {
+                    bail!(ErrorKind::TruncatedIndex(idxlen / entry_size, extra));
+                }
+                idx.truncate(idxlen - extra);
+            }
+        }
+
         let mut idxoff = BTreeMap::new();
         idxoff.insert(RevIdx::zero(), 0); // prime cache - idx 0 = offset 0
 
@@ -119,6 +276,11 @@ impl Revlog {
             data: data,
             idxoff: idxoff,
             nodeidx: HashMap::new(),
+            nodeidx_complete: false,
+            revcache: LinkedHashMap::new(),
+            revcache_capacity: 0,
+            deltas_applied: 0,
+            max_rev_size: None,
         };
 
         Ok(Revlog { inner: Arc::new(Mutex::new(inner)) })
@@ -127,19 +289,40 @@ impl Revlog {
     /// Construct a `Revlog` using in-memory data. The index is required; the data
     /// may not be if either its inlined into the data, or not required for operations.
     pub fn new(idx: Vec<u8>, data: Option<Vec<u8>>) -> Result<Self> {
-        Self::init(Datafile::Loaded(idx), data.map(Datafile::Loaded))
+        Self::init(Datafile::loaded(idx), data.map(Datafile::loaded), false)
+    }
+
+    /// Like `Revlog::new`, but if the index ends with a partial entry, as left by an
+    /// interrupted write, ignore it rather than failing with `ErrorKind::TruncatedIndex`. This
+    /// allows recovery tools to read everything up to the damage.
+    ///
+    /// Partial entries can only be detected in indexes without inline data. In inline indexes
+    /// they're reported as parse errors when they're reached.
+    pub fn new_truncated(idx: Vec<u8>, data: Option<Vec<u8>>) -> Result<Self> {
+        Self::init(Datafile::loaded(idx), data.map(Datafile::loaded), true)
     }
 
     /// Construct a `Revlog` from an index file at the given path. Data may be inlined
     /// not not required.
+    ///
+    /// The index is memory-mapped rather than read, and entries are only parsed when they're
+    /// accessed, so opening a large index is cheap. Use `Revlog::new` to construct a `Revlog`
+    /// from data which has already been read into memory.
     pub fn from_idx<IP>(idxpath: IP) -> Result<Revlog>
+    where
+        IP: AsRef<Path>,
+    {
+        Self::from_idx_inner(idxpath, false)
+    }
+
+    fn from_idx_inner<IP>(idxpath: IP, tolerate_truncation: bool) -> Result<Revlog>
     where
         IP: AsRef<Path>,
     {
         let idx = Datafile::map(idxpath)
             .chain_err(|| format!("Can't map idxpath"))?;
 
-        let revlog = Revlog::init(idx, None)?;
+        let revlog = Revlog::init(idx, None, tolerate_truncation)?;
 
         Ok(revlog)
     }
@@ -148,12 +331,40 @@ impl Revlog {
     /// (`None`), and the index file is not inlined, then it will replace the index file's
     /// extension with `.d` and attempt to open that. The operation will fail if that file can't
     /// be opened.
+    ///
+    /// Whether the index is inlined is determined from its header. An inlined index contains
+    /// all the data, so it's an error to provide a `datapath` for one.
+    ///
+    /// Fails with `ErrorKind::DataFileTooShort` if the data file is too short to hold the last
+    /// revision in the index, as when it belongs to a different revlog.
     pub fn from_idx_data<IP, DP>(idxpath: IP, datapath: Option<DP>) -> Result<Revlog>
     where
         IP: AsRef<Path> + Debug,
         DP: AsRef<Path> + Debug,
     {
-        let revlog = Self::from_idx(&idxpath)
+        Self::from_idx_data_inner(idxpath, datapath, false)
+    }
+
+    /// Like `Revlog::from_idx_data`, but ignore a partial entry at the end of the index. See
+    /// `Revlog::new_truncated`.
+    pub fn from_idx_data_truncated<IP, DP>(idxpath: IP, datapath: Option<DP>) -> Result<Revlog>
+    where
+        IP: AsRef<Path> + Debug,
+        DP: AsRef<Path> + Debug,
+    {
+        Self::from_idx_data_inner(idxpath, datapath, true)
+    }
+
+    fn from_idx_data_inner<IP, DP>(
+        idxpath: IP,
+        datapath: Option<DP>,
+        tolerate_truncation: bool,
+    ) -> Result<Revlog>
+    where
+        IP: AsRef<Path> + Debug,
+        DP: AsRef<Path> + Debug,
+    {
+        let revlog = Self::from_idx_inner(&idxpath, tolerate_truncation)
             .chain_err(|| format!("Can't open index {:?}", idxpath))?;
         let datapath = datapath.as_ref().map(DP::as_ref);
         let idxpath = idxpath.as_ref();
@@ -161,10 +372,27 @@ impl Revlog {
         {
             let mut inner = revlog.inner.lock().expect("lock poisoned");
 
+            if inner.header.is_inline() {
+                if let Some(path) = datapath {
+                    bail!(ErrorKind::Revlog(format!(
+                        "index {:?} has inline data, so data file {:?} is not used",
+                        idxpath,
+                        path
+                    )));
+                }
+            }
+
             if !inner.have_data() {
                 let datafile = match datapath {
                     None => {
                         let path = idxpath.with_extension("d");
+                        if !path.exists() {
+                            bail!(ErrorKind::Revlog(format!(
+                                "index {:?} has no inline data, and no data file was found at {:?}",
+                                idxpath,
+                                path
+                            )));
+                        }
                         Datafile::map(&path)
                             .chain_err(|| format!("Can't open data file {:?}", path))?
                     }
@@ -175,11 +403,76 @@ impl Revlog {
                 };
                 inner.data = Some(datafile);
             }
+
+            inner.check_data_length()?;
+        }
+
+        Ok(revlog)
+    }
+
+    /// Construct a `Revlog` from an index and data which are already in memory, such as ones
+    /// fetched over the network. Unlike `Revlog::new`, they're checked the same way as by
+    /// `Revlog::from_idx_data`: data must be provided unless the index has it inline, and
+    /// must not be if it does.
+    pub fn from_idx_data_bytes(idx: Vec<u8>, data: Option<Vec<u8>>) -> Result<Revlog> {
+        let have_datafile = data.is_some();
+        let revlog = Self::new(idx, data)?;
+
+        {
+            let mut inner = revlog.inner.lock().expect("lock poisoned");
+
+            if inner.header.is_inline() && have_datafile {
+                bail!(ErrorKind::Revlog(
+                    "index has inline data, so separate data is not used".into(),
+                ));
+            }
+            if !inner.have_data() {
+                bail!(ErrorKind::Revlog(
+                    "index has no inline data, and no data was provided".into(),
+                ));
+            }
+
+            inner.check_data_length()?;
         }
 
         Ok(revlog)
     }
 
+    /// Keep up to `capacity` reconstructed revisions in memory, evicting the least recently
+    /// used. Cached revisions are returned directly by `get_rev`, and are used as the starting
+    /// point when reconstructing later revisions in the same delta chain. The cache is disabled
+    /// by default, and is shared by all clones of this `Revlog`.
+    pub fn with_cache(self, capacity: usize) -> Self {
+        {
+            let mut inner = self.inner.lock().expect("lock poisoned");
+
+            inner.revcache_capacity = capacity;
+            while inner.revcache.len() > capacity {
+                inner.revcache.pop_front();
+            }
+        }
+
+        self
+    }
+
+    /// Refuse to reconstruct revisions larger than `bytes`, failing with
+    /// `ErrorKind::RevTooLarge` instead. This protects servers reading untrusted revlogs from
+    /// running out of memory. There is no limit by default, and the limit is shared by all
+    /// clones of this `Revlog`.
+    ///
+    /// The size of each revision in a delta chain is worked out from the deltas before any of
+    /// them are applied, so the limit applies to the revisions the chain passes through as
+    /// well as the one being reconstructed.
+    pub fn with_max_rev_size(self, bytes: usize) -> Self {
+        {
+            let mut inner = self.inner.lock().expect("lock poisoned");
+
+            inner.max_rev_size = Some(bytes);
+        }
+
+        self
+    }
+
     /// Return `true` if the `Revlog` has the data it requires - ie, the data is either inlined,
     /// or a data file has been provided.
     pub fn have_data(&self) -> bool {
@@ -202,6 +495,51 @@ impl Revlog {
         inner.get_entry(idx)
     }
 
+    /// Return an iterator over every `Entry` in index order.
+    ///
+    /// Unlike iterating over the `Revlog` itself, which stops at the first entry which can't be
+    /// read, this yields any error encountered before the end of the index and then stops.
+    pub fn entries(&self) -> EntryIter {
+        EntryIter {
+            inner: self.inner.clone(),
+            idx: RevIdx::zero(),
+            done: false,
+        }
+    }
+
+    /// Iterate over the ancestors of the revision at `RevIdx`, not including the revision
+    /// itself, from the most recent to the root. Each ancestor is yielded once, even if it can
+    /// be reached through both parents. Like `entries`, an error ends the iteration.
+    pub fn ancestors(&self, idx: RevIdx) -> AncestorIter {
+        AncestorIter {
+            inner: self.inner.clone(),
+            start: Some(idx),
+            pending: BinaryHeap::new(),
+            seen: HashSet::new(),
+            done: false,
+        }
+    }
+
+    /// Iterate over the descendants of the revision at `RevIdx`, not including the revision
+    /// itself, in ascending order. Every later entry in the index is read to find them.
+    pub fn descendants(&self, idx: RevIdx) -> DescendantIter {
+        let mut found = HashSet::new();
+        found.insert(idx);
+        DescendantIter {
+            inner: self.inner.clone(),
+            idx: idx.succ(),
+            found: found,
+            done: false,
+        }
+    }
+
+    /// Return the single nodeid in the revlog starting with `prefix`.
+    pub fn resolve_prefix(&self, prefix: &NodeHashPrefix) -> Result<NodeHash> {
+        let mut inner = self.inner.lock().expect("lock poisoned");
+
+        inner.resolve_prefix(prefix)
+    }
+
     /// Return the ordinal index of an entry with the given nodeid.
     pub fn get_idx_by_nodeid(&self, nodeid: &NodeHash) -> Result<RevIdx> {
         let mut inner = self.inner.lock().expect("lock poisoned");
@@ -228,12 +566,137 @@ impl Revlog {
         inner.get_chunk(idx)
     }
 
+    /// Return the nodeids of the parents of the revision at `RevIdx`. Missing parents are
+    /// `None`.
+    pub fn parents(&self, idx: RevIdx) -> Result<(Option<NodeHash>, Option<NodeHash>)> {
+        let mut inner = self.inner.lock().expect("lock poisoned");
+
+        inner.parents(idx)
+    }
+
+    /// Return the stored (possibly compressed) size of the revision at `RevIdx`, and the size of
+    /// its full text. Both come from the index, so nothing is decompressed or reconstructed.
+    ///
+    /// Fails for version 0 revlogs, whose entries don't record the full size.
+    pub fn sizes(&self, idx: RevIdx) -> Result<(u64, u64)> {
+        let entry = self.get_entry(idx)?;
+
+        match entry.len {
+            Some(len) => Ok((entry.compressed_len as u64, len as u64)),
+            None => bail!(ErrorKind::Revlog(
+                format!("entry {} doesn't record its full size", idx),
+            )),
+        }
+    }
+
+    /// Return the index of the changeset which introduced each revision, keyed by its nodeid.
+    /// This reads every entry under a single lock, so it's cheaper than calling `get_entry`
+    /// for each revision. Revisions which don't record a linkrev are left out.
+    ///
+    /// The map has an entry for every revision, so its size grows with the revlog.
+    pub fn linkrev_map(&self) -> Result<HashMap<NodeHash, RevIdx>> {
+        let mut inner = self.inner.lock().expect("lock poisoned");
+
+        inner.linkrev_map()
+    }
+
+    /// Return the revisions needed to reconstruct the revision at `RevIdx`, starting with the
+    /// revision itself and ending with the full snapshot the chain of deltas starts from.
+    pub fn delta_chain(&self, idx: RevIdx) -> Result<Vec<RevIdx>> {
+        let mut inner = self.inner.lock().expect("lock poisoned");
+
+        inner.delta_chain(idx)
+    }
+
+    /// Return the number of deltas which have to be applied to reconstruct the revision at
+    /// `RevIdx`, which is 0 if it's stored as a full snapshot. Only index entries are read.
+    pub fn chain_length(&self, idx: RevIdx) -> Result<usize> {
+        self.delta_chain(idx).map(|chain| chain.len() - 1)
+    }
+
+    /// Return where the bytes stored for the revision at `RevIdx` are, as an offset and length
+    /// in the data file, or in the index file if the revlog is inline, along with how they're
+    /// compressed. This lets them be served straight from the file.
+    ///
+    /// The range is the stored chunk, as returned by `get_raw_chunk`, including any
+    /// compression marker. It may be deltas rather than literal text, so it is not the
+    /// content of the revision.
+    pub fn data_location(&self, idx: RevIdx) -> Result<(u64, u64, ChunkCompression)> {
+        let mut inner = self.inner.lock().expect("lock poisoned");
+
+        inner.data_location(idx)
+    }
+
+    /// Return the total stored and full sizes of all the revisions, and how many of them are
+    /// compressed each way. The sizes come from the index, and the compression from the first
+    /// byte of each chunk, so nothing is decompressed or reconstructed.
+    ///
+    /// Fails for version 0 revlogs, whose entries don't record the full size, and for revlogs
+    /// without data.
+    pub fn compression_stats(&self) -> Result<CompressionStats> {
+        let mut inner = self.inner.lock().expect("lock poisoned");
+
+        inner.compression_stats()
+    }
+
+    /// Return `true` if the revision at `RevIdx` is stored as a full snapshot of its text,
+    /// rather than as a delta. Only its index entry is read, so this is cheaper than
+    /// `chain_length` when the length isn't needed.
+    pub fn is_snapshot(&self, idx: RevIdx) -> Result<bool> {
+        // An entry which is its own base is a snapshot, and is parsed as having no base.
+        self.get_entry(idx).map(|entry| entry.baserev.is_none())
+    }
+
+    /// Return the bytes stored for a revision at `RevIdx`, exactly as they are in the revlog.
+    ///
+    /// This is the possibly compressed literal text or deltas, including the byte marking how
+    /// it's compressed. It is not the content of the revision.
+    pub fn get_raw_chunk(&self, idx: RevIdx) -> Result<Vec<u8>> {
+        let mut inner = self.inner.lock().expect("lock poisoned");
+
+        inner.get_raw_chunk(idx).map(|(_, chunk)| chunk.to_vec())
+    }
+
     pub fn get_rev(&self, tgtidx: RevIdx) -> Result<BlobNode> {
         let mut inner = self.inner.lock().expect("lock poisoned");
 
         inner.get_rev(tgtidx)
     }
 
+    /// Reconstruct the content of the revision at `RevIdx` into `buf`, replacing anything it
+    /// held. Unlike `get_rev`, this lets a caller reading many revisions reuse one buffer
+    /// rather than allocating a new one for each.
+    pub fn read_rev_into(&self, idx: RevIdx, buf: &mut Vec<u8>) -> Result<()> {
+        let mut inner = self.inner.lock().expect("lock poisoned");
+
+        inner.read_rev_into(idx, buf)
+    }
+
+    /// Compute the `Delta`s which turn the content of revision `from` into the content of
+    /// revision `to`, in the same form as the deltas stored in the revlog. Applying them with
+    /// `bdiff::apply` gives the content of `to`.
+    pub fn delta(&self, from: RevIdx, to: RevIdx) -> Result<Vec<Delta>> {
+        let old = self.get_rev(from)?;
+        let new = self.get_rev(to)?;
+
+        match (old.as_blob().as_slice(), new.as_blob().as_slice()) {
+            (Some(old), Some(new)) => Ok(bdiff::diff(old, new)),
+            _ => bail!(ErrorKind::Revlog(
+                format!("no content for revision {} or {}", from, to),
+            )),
+        }
+    }
+
+    /// Return a reader for the content of the revision at `RevIdx`.
+    ///
+    /// Only the text the revision is a delta against is held in memory; the revision's own
+    /// deltas are applied as the content is read.
+    pub fn get_rev_reader(&self, idx: RevIdx) -> Result<RevReader> {
+        let mut inner = self.inner.lock().expect("lock poisoned");
+
+        inner.get_rev_reader(idx)
+    }
+
     pub fn get_rev_by_nodeid(&self, id: &NodeHash) -> Result<BlobNode> {
         let mut inner = self.inner.lock().expect("lock poisoned");
 
@@ -246,6 +709,192 @@ impl Revlog {
         inner.get_node_by_nodeid(id, with_data)
     }
 
+    /// Check every revision in the revlog.
+    ///
+    /// Each revision is reconstructed and hashed along with its parents, and the result
+    /// compared with the nodeid recorded in its entry. Parents must refer to earlier revisions.
+    /// Problems with individual revisions are collected into the returned `VerifyReport`; an
+    /// error is only returned if the index itself can't be read.
+    pub fn verify(&self) -> Result<VerifyReport> {
+        let mut inner = self.inner.lock().expect("lock poisoned");
+
+        inner.verify()
+    }
+
+    /// Like `verify`, but check the revisions in parallel on `pool`, producing the same
+    /// report.
+    ///
+    /// The revisions are split into contiguous ranges, each checked by a separate task with its
+    /// own view of the revlog sharing the same index and data. A delta chain which crosses the
+    /// start of a range is reconstructed from its base again by that range's task.
+    pub fn verify_parallel(&self, pool: &CpuPool) -> BoxFuture<VerifyReport, Error> {
+        self.verify_in_ranges(pool, VERIFY_RANGE_SIZE)
+    }
+
+    fn verify_in_ranges(&self, pool: &CpuPool, size: usize) -> BoxFuture<VerifyReport, Error> {
+        let ranges = {
+            let mut inner = self.inner.lock().expect("lock poisoned");
+            inner.verify_ranges(size)
+        };
+        let ranges = match ranges {
+            Ok(ranges) => ranges,
+            Err(err) => return future::err(err).boxed(),
+        };
+
+        let tasks: Vec<_> = ranges
+            .into_iter()
+            .map(|(mut inner, start, end)| pool.spawn_fn(move || inner.verify_range(start, end)))
+            .collect();
+        future::join_all(tasks)
+            .map(|reports| {
+                // The ranges are in order, so the merged problems are too.
+                reports.into_iter().fold(
+                    VerifyReport::default(),
+                    |mut report, range| {
+                        report.merge(range);
+                        report
+                    },
+                )
+            })
+            .boxed()
+    }
+
+    /// Check that the stored chunks of the revisions are laid out one after another, as
+    /// Mercurial writes them, returning any anomalies found. Each chunk should start where the
+    /// previous one ends, and with a separate data file, the last should end at the end of the
+    /// file. Only the index entries are read, so this is much cheaper than `verify`, and can
+    /// spot damage which doesn't affect the content of any revision.
+    pub fn check_layout(&self) -> Result<Vec<LayoutAnomaly>> {
+        let mut inner = self.inner.lock().expect("lock poisoned");
+
+        inner.check_layout()
+    }
+
+    /// Binary search for the first revision for which `pred` is true, assuming that once `pred`
+    /// is true for a revision it stays true for every later one. Returns `None` if `pred` isn't
+    /// true for any revision.
+    ///
+    /// Only the probed revisions are reconstructed, using the revision cache if there is one.
+    /// The revlog isn't locked while `pred` runs, so `pred` may use it.
+    pub fn bisect<F>(&self, mut pred: F) -> Result<Option<RevIdx>>
+    where
+        F: FnMut(&BlobNode) -> Result<bool>,
+    {
+        let count = {
+            let mut inner = self.inner.lock().expect("lock poisoned");
+            let mut idx = RevIdx::zero();
+            while inner.contains(idx)? {
+                idx = idx.succ();
+            }
+            u32::from(idx)
+        };
+
+        // `pred` is false for every revision before `lo`, and true from `hi` on.
+        let (mut lo, mut hi) = (0, count);
+        while lo < hi {
+            let mid = lo + (hi - lo) / 2;
+            if pred(&self.get_rev(RevIdx::from(mid))?)? {
+                hi = mid;
+            } else {
+                lo = mid + 1;
+            }
+        }
+
+        if lo < count {
+            Ok(Some(RevIdx::from(lo)))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Append a revision with the content `data` and the given parents, and return its nodeid.
+    /// The revision is stored as a full snapshot rather than as a delta, and its linkrev is its
+    /// own index. Nothing is appended if the revision is already present.
+    ///
+    /// Only version 1 revlogs which were constructed from in-memory data with `Revlog::new`
+    /// can be appended to; memory-mapped files are never modified.
+    pub fn append_full(
+        &mut self,
+        data: &[u8],
+        p1: Option<&NodeHash>,
+        p2: Option<&NodeHash>,
+    ) -> Result<NodeHash> {
+        let mut inner = self.inner.lock().expect("lock poisoned");
+
+        inner.append_full(data, p1, p2)
+    }
+
+    /// Write the revisions in `range` to `out`, in a simple format which `import_revs` can read
+    /// to add them to another revlog. This is not a Mercurial bundle.
+    ///
+    /// The output starts with a magic string, followed by a frame for each revision: its length
+    /// as a big-endian `u32`, then the revision's nodeid, the nodeids of its parents (null for a
+    /// missing parent), its linkrev as a big-endian `u32` (`0xffffffff` for none), and its full
+    /// content. Revisions are reconstructed one at a time, so only one is held in memory.
+    pub fn export_range<W: Write>(&self, range: Range<RevIdx>, out: &mut W) -> Result<()> {
+        out.write_all(EXPORT_MAGIC)?;
+
+        for idx in range.start.range_to(range.end) {
+            let entry = self.get_entry(idx)?;
+            let (p1, p2) = self.parents(idx)?;
+            let rev = self.get_rev(idx)?;
+            let content = rev.as_blob().as_slice().unwrap_or(&[]);
+
+            let len = EXPORT_FRAME_HEADER_SIZE + content.len();
+            let mut frame = Vec::with_capacity(4 + len);
+            push_u32(&mut frame, len as u32);
+            frame.extend_from_slice(entry.nodeid.as_ref());
+            frame.extend_from_slice(p1.unwrap_or(NodeHash::null()).as_ref());
+            frame.extend_from_slice(p2.unwrap_or(NodeHash::null()).as_ref());
+            push_u32(&mut frame, entry.linkrev.map_or(!0, u32::from));
+            frame.extend_from_slice(content);
+            out.write_all(&frame)?;
+        }
+
+        Ok(())
+    }
+
+    /// Append the revisions written by `export_range`, in order, with `append_full`, returning
+    /// their nodeids. Each revision's parents must already be in this revlog, or earlier in
+    /// the input. The recorded linkrevs are ignored, since `append_full` sets each revision's
+    /// linkrev to its own index.
+    ///
+    /// Fails if the input is malformed, or if a revision's content and parents don't hash to
+    /// its recorded nodeid. Revisions before the failure are left appended.
+    pub fn import_revs<R: Read>(&mut self, input: &mut R) -> Result<Vec<NodeHash>> {
+        let mut magic = vec![0; EXPORT_MAGIC.len()];
+        input.read_exact(&mut magic)?;
+        if &magic[..] != EXPORT_MAGIC {
+            bail!(ErrorKind::Revlog("input is not a revlog export".into()));
+        }
+
+        let mut nodeids = Vec::new();
+        while let Some(len) = read_frame_len(input)? {
+            if len < EXPORT_FRAME_HEADER_SIZE {
+                bail!(ErrorKind::Revlog(format!("export frame of {} bytes is too short", len)));
+            }
+            let mut frame = vec![0; len];
+            input.read_exact(&mut frame)?;
+
+            let expected = NodeHash::from_bytes(&frame[0..20])?;
+            let p1 = NodeHash::from_bytes(&frame[20..40])?;
+            let p2 = NodeHash::from_bytes(&frame[40..60])?;
+            let content = &frame[EXPORT_FRAME_HEADER_SIZE..];
+
+            let actual = self.append_full(content, Some(&p1), Some(&p2))?;
+            if actual != expected {
+                bail!(ErrorKind::Revlog(format!(
+                    "imported revision {} hashes to {}",
+                    expected,
+                    actual
+                )));
+            }
+            nodeids.push(actual);
+        }
+
+        Ok(nodeids)
+    }
+
     /// Return the set of head revisions in a revlog
     pub fn get_heads(&mut self) -> Result<HashSet<NodeHash>> {
         let mut inner = self.inner.lock().expect("lock poisoned");
@@ -258,9 +907,10 @@ impl RevlogInner {
     // Parse an entry at an offset, doing the correction for the overlap of the first
     // entry and the header.
     fn parse_entry(&self, off: usize) -> Result<Entry> {
-        let res = match self.header.version {
+        let res = match self.header.version() {
             Version::Revlog0 => parser::index0(&self.idx.as_slice()[off..]),
-            Version::RevlogNG => parser::indexng(&self.idx.as_slice()[off..]),
+            // v2 entries are NG entries with extra fields at the end
+            Version::RevlogNG | Version::RevlogV2 => parser::indexng(&self.idx.as_slice()[off..]),
         };
 
         match res {
@@ -280,40 +930,78 @@ impl RevlogInner {
     }
 
     fn fixed_entry_size(&self) -> usize {
-        match self.header.version {
-            Version::Revlog0 => parser::index0_size(),
-            Version::RevlogNG => parser::indexng_size(),
-        }
+        fixed_entry_size(self.header.version())
     }
 
     fn entry_size(&self, ent: Option<&Entry>) -> usize {
         let mut sz = self.fixed_entry_size();
-        if self.header.features.contains(parser::INLINE) {
+        if self.header.is_inline() {
             sz += ent.expect("inline needs ent").compressed_len as usize;
         }
         sz
     }
 
     fn offset_for_idx(&self, idx: RevIdx) -> Option<usize> {
-        if self.header.features.contains(parser::INLINE) {
+        if self.header.is_inline() {
             self.idxoff.get(&idx).cloned()
         } else {
             Some(idx * self.entry_size(None) as usize)
         }
     }
 
+    // Return true if there's an entry for `idx` in the index.
+    fn contains(&mut self, idx: RevIdx) -> Result<bool> {
+        let off = match self.offset_for_idx(idx) {
+            Some(off) => off,
+            None => {
+                // inline, so the offset is only known once the previous entry has been read
+                let prev = idx.pred();
+                let ent = self.get_entry(prev)?;
+                let off = self.offset_for_idx(prev).expect("not cached?");
+                off + self.entry_size(Some(&ent))
+            }
+        };
+
+        Ok(off < self.idx.as_slice().len())
+    }
+
+    // Check that a separate data file is long enough to hold the chunk of the last revision.
+    // This quickly catches an index being paired with the data file of a different revlog,
+    // which would otherwise only show up as corrupt revisions.
+    fn check_data_length(&mut self) -> Result<()> {
+        let actual = match self.data {
+            Some(ref data) if !self.header.is_inline() => data.as_slice().len() as u64,
+            _ => return Ok(()),
+        };
+        let entries = self.idx.as_slice().len() / self.fixed_entry_size();
+        if entries == 0 {
+            return Ok(());
+        }
+
+        let last = self.get_entry(RevIdx::from(entries - 1))?;
+        let expected = last.offset + last.compressed_len as u64;
+        if expected > actual {
+            bail!(ErrorKind::DataFileTooShort(expected, actual));
+        }
+        Ok(())
+    }
+
     fn have_data(&self) -> bool {
         // inline implies no data
-        assert!(!self.header.features.contains(parser::INLINE) || self.data.is_none());
+        assert!(!self.header.is_inline() || self.data.is_none());
 
         // have data if inline or data is non-None
-        self.header.features.contains(parser::INLINE) || self.data.is_some()
+        self.header.is_inline() || self.data.is_some()
     }
 
     /// Return an `Entry` entry from the `RevIdx`.
     fn get_entry(&mut self, idx: RevIdx) -> Result<Entry> {
+        let idxlen = self.idx.as_slice().len();
         let mut entry = if let Some(off) = self.offset_for_idx(idx) {
             // cache hit or computed
+            if off >= idxlen {
+                bail!(ErrorKind::NoSuchRevision(idx));
+            }
             self.parse_entry(off)?
         } else {
             // cache miss - find last cached offset and go from there
@@ -327,6 +1015,9 @@ impl RevlogInner {
             );
 
             for curidx in last.range_to(idx.succ()) {
+                if off >= idxlen {
+                    bail!(ErrorKind::NoSuchRevision(idx));
+                }
                 let ent = self.parse_entry(off)?;
 
                 self.idxoff.insert(curidx, off);
@@ -348,32 +1039,45 @@ impl RevlogInner {
     }
 
     /// Return the ordinal index of an entry with the given nodeid.
+    ///
+    /// The first lookup which misses the cache reads the whole index to fill it, so that all
+    /// later lookups are a single hash probe.
     fn get_idx_by_nodeid(&mut self, nodeid: &NodeHash) -> Result<RevIdx> {
-        let idx = self.nodeidx.get(nodeid).cloned();
+        if let Some(idx) = self.nodeidx.get(nodeid) {
+            return Ok(*idx); // cache hit
+        }
 
-        match idx {
-            Some(idx) => Ok(idx),   // cache hit
+        if !self.nodeidx_complete {
+            // get_entry adds each entry to the cache as a side-effect. An entry which can't be
+            // read is reported, rather than making every later nodeid look missing.
+            let mut idx = RevIdx::zero();
+            while self.contains(idx)? {
+                self.get_entry(idx)?;
+                idx = idx.succ();
+            }
+            self.nodeidx_complete = true;
+        }
 
-            None => {
-                // search the hard way
-                let mut posidx = None;
-                let res = self.into_iter().position(|(i, entry)| {
-                    posidx = Some(i);
-                    &entry.nodeid == nodeid
-                });
+        match self.nodeidx.get(nodeid) {
+            Some(idx) => Ok(*idx),
+            None => Err(ErrorKind::NoSuchNode(*nodeid).into()),
+        }
+    }
 
-                match res {
-                    None => Err(
-                        ErrorKind::Revlog(format!("nodeid {} not found", nodeid)).into(),
-                    ),
-                    Some(idx) => {
-                        let idx = RevIdx::from(idx);
-                        assert_eq!(posidx, Some(idx));
-                        self.nodeidx.insert(*nodeid, idx);
-                        Ok(idx)
-                    }
-                }
-            }
+    fn resolve_prefix(&mut self, prefix: &NodeHashPrefix) -> Result<NodeHash> {
+        if let Some(nodeid) = prefix.to_full() {
+            return self.get_idx_by_nodeid(&nodeid).map(|_| nodeid);
+        }
+
+        let mut candidates: Vec<_> = self.into_iter()
+            .map(|(_, entry)| entry.nodeid)
+            .filter(|nodeid| prefix.matches(nodeid))
+            .collect();
+
+        match candidates.len() {
+            0 => bail!(ErrorKind::NoMatchingNode(prefix.clone())),
+            1 => Ok(candidates.pop().unwrap()),
+            _ => bail!(ErrorKind::AmbiguousPrefix(prefix.clone(), candidates)),
         }
     }
 
@@ -382,38 +1086,68 @@ impl RevlogInner {
             .and_then(|idx| self.get_entry(idx))
     }
 
-    /// Return a `Chunk` for a revision at `RevIdx`.
-    ///
-    /// A `Chunk` either represents the literal
-    /// text of the change, or a series of deltas against a previous version; the exact
-    /// mechanism of applying the deltas depends on whether the `RevLog` has the `GENERAL_DELTA`
-    /// flag set or not.
-    fn get_chunk(&mut self, idx: RevIdx) -> Result<Chunk> {
+    // Return an entry and the bytes stored for it, which may be compressed.
+    fn get_raw_chunk(&mut self, idx: RevIdx) -> Result<(Entry, &[u8])> {
+        let (entry, start, end) = self.chunk_location(idx)?;
+
+        let chunkdata = if self.header.is_inline() {
+            self.idx.as_slice()
+        } else {
+            self.data
+                .as_ref()
+                .expect("non-inline has no data")
+                .as_slice()
+        };
+        //println!("{:?}: {:?} chunk {}-{}", idx, entry, start, end);
+
+        Ok((entry, &chunkdata[start..end]))
+    }
+
+    // Return an entry and where the bytes stored for it start and end, in the index if the
+    // revlog is inline and otherwise in the data.
+    fn chunk_location(&mut self, idx: RevIdx) -> Result<(Entry, usize, usize)> {
         if !self.have_data() {
             return Err("Can't get chunks without data".into());
         }
 
         let entry = self.get_entry(idx)?;
 
-        let (chunkdata, start) = if self.header.features.contains(parser::INLINE) {
+        let start = if self.header.is_inline() {
             let off = self.offset_for_idx(idx).expect("not cached?");
-            let start = off + self.fixed_entry_size();
-
-            (self.idx.as_slice(), start)
+            off + self.fixed_entry_size()
         } else {
-            let start = entry.offset as usize;
-
-            (
-                self.data
-                    .as_ref()
-                    .expect("non-inline has no data")
-                    .as_slice(),
-                start,
-            )
+            entry.offset as usize
         };
         let end = start + (entry.compressed_len as usize);
-        let chunkdata = &chunkdata[start..end];
-        //println!("{:?}: {:?} chunk {}-{}", idx, entry, start, end);
+
+        Ok((entry, start, end))
+    }
+
+    fn data_location(&mut self, idx: RevIdx) -> Result<(u64, u64, ChunkCompression)> {
+        let (start, end) = {
+            let (_, start, end) = self.chunk_location(idx)?;
+            (start, end)
+        };
+        let compression = {
+            let (_, chunk) = self.get_raw_chunk(idx)?;
+            ChunkCompression::of_chunk(chunk)?
+        };
+
+        Ok((start as u64, (end - start) as u64, compression))
+    }
+
+    /// Return a `Chunk` for a revision at `RevIdx`.
+    ///
+    /// A `Chunk` either represents the literal
+    /// text of the change, or a series of deltas against a previous version; the exact
+    /// mechanism of applying the deltas depends on whether the `RevLog` has the `GENERAL_DELTA`
+    /// flag set or not.
+    fn get_chunk(&mut self, idx: RevIdx) -> Result<Chunk> {
+        let (entry, chunkdata) = self.get_raw_chunk(idx)?;
+
+        // Check the marker first, so that an unknown one is reported as such rather than as a
+        // parse failure.
+        let compression = ChunkCompression::of_chunk(chunkdata)?;
 
         // If the entry has no baserev then the chunk is literal data, Otherwise
         // its 0 or more deltas against the baserev. If its general delta, then the
@@ -440,7 +1174,17 @@ impl RevlogInner {
         } else if chunkdata.len() == 0 {
             Ok(Chunk::Literal(vec![]))
         } else {
-            let literal = match parser::literal(chunkdata) {
+            // Text stored as is is copied straight out, without going through the parser.
+            let parsed = match compression {
+                ChunkCompression::None => IResult::Done(&chunkdata[..0], chunkdata.to_vec()),
+                ChunkCompression::Uncompressed => {
+                    IResult::Done(&chunkdata[..0], chunkdata[1..].to_vec())
+                }
+                ChunkCompression::Zlib | ChunkCompression::Lz4 | ChunkCompression::Zstd => {
+                    parser::literal(chunkdata)
+                }
+            };
+            let literal = match parsed {
                 IResult::Done(rest, _) if rest.len() != 0 => {
                     return Err(
                         ErrorKind::Revlog(format!(
@@ -462,7 +1206,28 @@ impl RevlogInner {
     }
 
     fn is_general_delta(&self) -> bool {
-        self.header.features.contains(parser::GENERAL_DELTA)
+        self.header.is_generaldelta()
+    }
+
+    fn delta_chain(&mut self, idx: RevIdx) -> Result<Vec<RevIdx>> {
+        let mut chain = vec![idx];
+        let mut entry = self.get_entry(idx)?;
+
+        // without general delta, each delta is against the previous revision
+        let general = self.is_general_delta();
+        while let Some(baserev) = entry.baserev {
+            let idx = *chain.last().unwrap();
+            let next = if general { baserev } else { idx.pred() };
+            if next >= idx {
+                bail!(ErrorKind::Revlog(
+                    format!("baserev {:?} >= idx {:?}", next, idx),
+                ));
+            }
+            chain.push(next);
+            entry = self.get_entry(next)?;
+        }
+
+        Ok(chain)
     }
 
     fn construct_simple(&mut self, tgtidx: RevIdx) -> Result<Vec<u8>> {
@@ -479,7 +1244,17 @@ impl RevlogInner {
         // each delta up to idx
         let mut data = Vec::new();
         let mut chain = Vec::new();
-        for idx in baserev.range_to(tgtidx.succ()) {
+
+        // start from the last cached revision in the chain, if there is one
+        let mut startrev = baserev;
+        for idx in baserev.range_to(tgtidx) {
+            if let Some(cached) = self.revcache.get_refresh(&idx) {
+                data = cached.clone();
+                startrev = idx.succ();
+            }
+        }
+
+        for idx in startrev.range_to(tgtidx.succ()) {
             let chunk = self.get_chunk(idx).chain_err::<_, Error>(|| {
                 format!("simple tgtidx {:?} idx {:?}", tgtidx, idx).into()
             });
@@ -495,7 +1270,9 @@ impl RevlogInner {
             }
         }
 
-        delta::compat::apply_deltas(data.as_ref(), chain);
+        self.check_rev_size(tgtidx, data.len(), &chain)?;
+        self.deltas_applied += chain.len();
+        data = delta::compat::apply_deltas(data.as_ref(), chain);
 
         Ok(data)
     }
@@ -506,9 +1283,17 @@ impl RevlogInner {
         let mut chunks = Vec::new();
         let mut idx = tgtidx;
 
-        // general delta - walk backwards until we hit a literal, collecting chunks on the way
+        // general delta - walk backwards until we hit a literal or a cached revision,
+        // collecting chunks on the way
         let mut data;
         loop {
+            if idx != tgtidx {
+                if let Some(cached) = self.revcache.get_refresh(&idx) {
+                    data = cached.clone();
+                    break;
+                }
+            }
+
             chunks.push(idx);
 
             let entry = self.get_entry(idx)?;
@@ -534,24 +1319,54 @@ impl RevlogInner {
         }
 
         // XXX: Fix this to use delta::Delta instead of bdiff::Delta.
-        let chain = chunks.into_iter().rev().map(|idx| {
-            let chunk = self.get_chunk(idx);
-
+        let mut chain = Vec::new();
+        for idx in chunks.into_iter().rev() {
+            let chunk = self.get_chunk(idx)
+                .chain_err::<_, Error>(|| {
+                    format!("construct_general tgtidx {:?} idx {:?}", tgtidx, idx).into()
+                })?;
             match chunk {
-                Ok(Chunk::Deltas(_, deltas)) => deltas,
-                _ => panic!("Literal text found in delta chain."),
+                Chunk::Deltas(_, deltas) => chain.push(deltas),
+                Chunk::Literal(_) => Err(ErrorKind::Revlog(
+                    format!("literal text found in delta chain at idx {:?}", idx),
+                ))?,
             }
-        });
+        }
 
+        self.check_rev_size(tgtidx, data.len(), &chain)?;
+        self.deltas_applied += chain.len();
         data = delta::compat::apply_deltas(data.as_ref(), chain);
 
         Ok(data)
     }
 
-    fn make_node<T>(&mut self, entry: &Entry, blob: Blob<T>) -> Result<BlobNode<T>>
-    where
-        T: AsRef<[u8]>,
-    {
+    // Fail if reconstructing `tgtidx` by applying `chain` to a text of `len` bytes would pass
+    // through a text larger than the maximum revision size.
+    fn check_rev_size(&self, tgtidx: RevIdx, len: usize, chain: &[Vec<Delta>]) -> Result<()> {
+        let limit = match self.max_rev_size {
+            Some(limit) => limit,
+            None => return Ok(()),
+        };
+
+        let mut size = len;
+        if size > limit {
+            bail!(ErrorKind::RevTooLarge(tgtidx, size, limit));
+        }
+        for deltas in chain {
+            for delta in deltas {
+                size = size.saturating_sub(delta.end.saturating_sub(delta.start));
+                size = size.saturating_add(delta.content.len());
+            }
+            if size > limit {
+                bail!(ErrorKind::RevTooLarge(tgtidx, size, limit));
+            }
+        }
+        Ok(())
+    }
+
+    // Return the nodeids of an entry's parents. Missing parents may be recorded either as a
+    // null index or as an index of an entry with the null nodeid.
+    fn parent_nodeids(&mut self, entry: &Entry) -> Result<(Option<NodeHash>, Option<NodeHash>)> {
         let mut pnodeid = |p| {
             let pn = self.get_entry(p);
             pn.map(|n| n.nodeid)
@@ -559,6 +1374,118 @@ impl RevlogInner {
         let p1 = map_io(entry.p1, &mut pnodeid)?;
         let p2 = map_io(entry.p2, &mut pnodeid)?;
 
+        let not_null = |p: Option<NodeHash>| match p {
+            Some(p) if p.is_null() => None,
+            p => p,
+        };
+        Ok((not_null(p1), not_null(p2)))
+    }
+
+    fn parents(&mut self, idx: RevIdx) -> Result<(Option<NodeHash>, Option<NodeHash>)> {
+        let entry = self.get_entry(idx)?;
+
+        self.parent_nodeids(&entry)
+    }
+
+    fn append_full(
+        &mut self,
+        text: &[u8],
+        p1: Option<&NodeHash>,
+        p2: Option<&NodeHash>,
+    ) -> Result<NodeHash> {
+        if self.header.version() != Version::RevlogNG {
+            bail!(ErrorKind::Revlog(
+                format!("can't append to {:?} revlogs", self.header.version()),
+            ));
+        }
+        if !self.have_data() {
+            return Err("Need data to append revision".into());
+        }
+        let inline = self.header.is_inline();
+        let loaded = if inline {
+            self.idx.as_mut_vec().is_some()
+        } else {
+            self.idx.as_mut_vec().is_some() &&
+                self.data.as_mut().and_then(Datafile::as_mut_vec).is_some()
+        };
+        if !loaded {
+            return Err("Can't append to a memory-mapped revlog".into());
+        }
+
+        let p1 = match p1 {
+            Some(p1) if !p1.is_null() => Some(p1),
+            _ => None,
+        };
+        let p2 = match p2 {
+            Some(p2) if !p2.is_null() => Some(p2),
+            _ => None,
+        };
+        let nodeid = BlobNode::new(Blob::from(text.to_vec()), p1, p2)
+            .nodeid()
+            .expect("blob has data");
+        match self.get_idx_by_nodeid(&nodeid) {
+            Ok(_) => return Ok(nodeid),
+            Err(Error(ErrorKind::NoSuchNode(_), _)) => (),
+            Err(err) => return Err(err),
+        }
+        let p1 = map_io(p1, &mut |p| self.get_idx_by_nodeid(p))?;
+        let p2 = map_io(p2, &mut |p| self.get_idx_by_nodeid(p))?;
+
+        // Find the new revision's index, and where its data goes.
+        let mut idx = RevIdx::zero();
+        let mut offset = 0;
+        while self.contains(idx)? {
+            let entry = self.get_entry(idx)?;
+            offset = entry.offset + entry.compressed_len as u64;
+            idx = idx.succ();
+        }
+
+        let chunk = compress_chunk(text)?;
+        let entry = Entry {
+            offset: offset,
+            flags: parser::IdxFlags::empty(),
+            compressed_len: chunk.len() as u32,
+            len: Some(text.len() as u32),
+            baserev: Some(idx),
+            linkrev: Some(idx),
+            p1: p1,
+            p2: p2,
+            nodeid: nodeid,
+        };
+        let encoded = encode_entry_ng(&entry);
+
+        let idxlen = self.idx.as_slice().len();
+        if inline {
+            let idxdata = self.idx.as_mut_vec().expect("checked above");
+            idxdata.extend_from_slice(&encoded);
+            idxdata.extend_from_slice(&chunk);
+            self.idxoff.insert(idx, idxlen);
+        } else {
+            {
+                let data = self.data.as_mut().and_then(Datafile::as_mut_vec);
+                let data = data.expect("checked above");
+                if data.len() as u64 != offset {
+                    bail!(ErrorKind::Revlog(format!(
+                        "data file is {} bytes, but the index expects {}",
+                        data.len(),
+                        offset
+                    )));
+                }
+                data.extend_from_slice(&chunk);
+            }
+            self.idx.as_mut_vec().expect("checked above").extend_from_slice(&encoded);
+        }
+        self.nodeidx.insert(nodeid, idx);
+
+        Ok(nodeid)
+    }
+
+    fn make_node<T>(&mut self, entry: &Entry, blob: Blob<T>) -> Result<BlobNode<T>>
+    where
+        T: AsRef<[u8]>,
+    {
+        let (p1, p2) = self.parent_nodeids(entry)?;
+
         Ok(BlobNode::new(blob, p1.as_ref(), p2.as_ref()))
     }
 
@@ -568,6 +1495,34 @@ impl RevlogInner {
         }
 
         let entry = self.get_entry(tgtidx)?;
+        check_flags(tgtidx, &entry)?;
+        let data = self.get_text(tgtidx)?;
+
+        self.make_node(&entry, Blob::from(data))
+    }
+
+    fn read_rev_into(&mut self, idx: RevIdx, buf: &mut Vec<u8>) -> Result<()> {
+        if !self.have_data() {
+            return Err("Need data to assemble revision".into());
+        }
+        check_flags(idx, &self.get_entry(idx)?)?;
+
+        buf.clear();
+        // Copy a cached revision directly, rather than cloning it with `get_text`.
+        if let Some(data) = self.revcache.get_refresh(&idx) {
+            buf.extend_from_slice(data);
+            return Ok(());
+        }
+        buf.extend_from_slice(&self.get_text(idx)?);
+
+        Ok(())
+    }
+
+    // Return the full text of a revision, from the cache if possible.
+    fn get_text(&mut self, tgtidx: RevIdx) -> Result<Vec<u8>> {
+        if let Some(data) = self.revcache.get_refresh(&tgtidx) {
+            return Ok(data.clone());
+        }
 
         let data = if self.is_general_delta() {
             self.construct_general(tgtidx)?
@@ -575,7 +1530,45 @@ impl RevlogInner {
             self.construct_simple(tgtidx)?
         };
 
-        self.make_node(&entry, Blob::from(data))
+        if self.revcache_capacity > 0 {
+            self.revcache.insert(tgtidx, data.clone());
+            while self.revcache.len() > self.revcache_capacity {
+                self.revcache.pop_front();
+            }
+        }
+
+        Ok(data)
+    }
+
+    fn get_rev_reader(&mut self, idx: RevIdx) -> Result<RevReader> {
+        if !self.have_data() {
+            return Err("Need data to assemble revision".into());
+        }
+        check_flags(idx, &self.get_entry(idx)?)?;
+
+        let (base, deltas) = if self.revcache.contains_key(&idx) {
+            (self.get_text(idx)?, Vec::new())
+        } else {
+            match self.get_chunk(idx)? {
+                Chunk::Literal(data) => (data, Vec::new()),
+                Chunk::Deltas(baserev, deltas) => {
+                    // without general delta, each delta is against the previous revision
+                    let baserev = if self.is_general_delta() {
+                        baserev
+                    } else {
+                        idx.pred()
+                    };
+                    (self.get_text(baserev)?, deltas)
+                }
+            }
+        };
+
+        Ok(RevReader {
+            base: base,
+            basepos: 0,
+            deltas: deltas.into_iter(),
+            content: io::Cursor::new(Vec::new()),
+        })
     }
 
     fn get_rev_by_nodeid(&mut self, id: &NodeHash) -> Result<BlobNode> {
@@ -596,6 +1589,193 @@ impl RevlogInner {
         }
     }
 
+    fn verify(&mut self) -> Result<VerifyReport> {
+        if !self.have_data() {
+            return Err("Need data to verify revlog".into());
+        }
+
+        let mut report = VerifyReport::default();
+        let mut idx = RevIdx::zero();
+
+        while self.contains(idx)? {
+            self.verify_rev(idx, &mut report)?;
+            idx = idx.succ();
+        }
+
+        Ok(report)
+    }
+
+    fn linkrev_map(&mut self) -> Result<HashMap<NodeHash, RevIdx>> {
+        let mut map = HashMap::new();
+        let mut idx = RevIdx::zero();
+
+        while self.contains(idx)? {
+            let entry = self.get_entry(idx)?;
+            if let Some(linkrev) = entry.linkrev {
+                map.insert(entry.nodeid, linkrev);
+            }
+            idx = idx.succ();
+        }
+
+        Ok(map)
+    }
+
+    fn compression_stats(&mut self) -> Result<CompressionStats> {
+        let mut stats = CompressionStats::default();
+        let mut idx = RevIdx::zero();
+
+        while self.contains(idx)? {
+            let (entry, chunk) = self.get_raw_chunk(idx)?;
+            let len = match entry.len {
+                Some(len) => len,
+                None => bail!(ErrorKind::Revlog(
+                    format!("entry {} doesn't record its full size", idx),
+                )),
+            };
+
+            stats.revisions += 1;
+            stats.stored += entry.compressed_len as u64;
+            stats.full += len as u64;
+            match ChunkCompression::of_chunk(chunk)? {
+                ChunkCompression::None => stats.none += 1,
+                ChunkCompression::Uncompressed => stats.uncompressed += 1,
+                ChunkCompression::Zlib => stats.zlib += 1,
+                ChunkCompression::Lz4 => stats.lz4 += 1,
+                ChunkCompression::Zstd => stats.zstd += 1,
+            }
+            idx = idx.succ();
+        }
+
+        Ok(stats)
+    }
+
+    fn check_layout(&mut self) -> Result<Vec<LayoutAnomaly>> {
+        let mut anomalies = Vec::new();
+        // The previous revision, and where its chunk ends
+        let mut prev: Option<(RevIdx, u64)> = None;
+        let mut idx = RevIdx::zero();
+
+        while self.contains(idx)? {
+            let entry = self.get_entry(idx)?;
+            let expected = prev.map_or(0, |(_, end)| end);
+            let before = prev.map(|(prev, _)| prev);
+
+            if entry.offset > expected {
+                anomalies.push(LayoutAnomaly::Gap {
+                    before: before,
+                    after: idx,
+                    len: entry.offset - expected,
+                });
+            } else if entry.offset < expected {
+                anomalies.push(LayoutAnomaly::Overlap {
+                    before: before.expect("first chunk can't overlap"),
+                    after: idx,
+                    len: expected - entry.offset,
+                });
+            }
+
+            prev = Some((idx, entry.offset + entry.compressed_len as u64));
+            idx = idx.succ();
+        }
+
+        if let Some(ref data) = self.data {
+            let datalen = data.as_slice().len() as u64;
+            let (last, end) = match prev {
+                Some((last, end)) => (Some(last), end),
+                None => (None, 0),
+            };
+            if end != datalen {
+                anomalies.push(LayoutAnomaly::DataLength {
+                    last: last,
+                    end: end,
+                    len: datalen,
+                });
+            }
+        }
+
+        Ok(anomalies)
+    }
+
+    /// Check the revisions from `start` up to but not including `end`.
+    fn verify_range(&mut self, start: RevIdx, end: RevIdx) -> Result<VerifyReport> {
+        let mut report = VerifyReport::default();
+        let mut idx = start;
+
+        while idx < end {
+            self.verify_rev(idx, &mut report)?;
+            idx = idx.succ();
+        }
+
+        Ok(report)
+    }
+
+    fn verify_rev(&mut self, idx: RevIdx, report: &mut VerifyReport) -> Result<()> {
+        let entry = self.get_entry(idx)?;
+        report.revisions += 1;
+
+        let parents = [entry.p1, entry.p2];
+        if parents.iter().any(|p| p.map_or(false, |p| p >= idx)) {
+            report.bad_parents.push(idx);
+        } else {
+            match self.get_rev(idx) {
+                Ok(node) => {
+                    let actual = node.nodeid().expect("node has no data");
+                    if actual != entry.nodeid {
+                        report.hash_mismatches.push(HashMismatch {
+                            idx: idx,
+                            expected: entry.nodeid,
+                            actual: actual,
+                        });
+                    }
+                }
+                Err(err) => report.errors.push((idx, err)),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Split the revisions into ranges of at most `size` revisions for `verify_parallel`,
+    /// each with a fork of the revlog to check it with.
+    fn verify_ranges(&mut self, size: usize) -> Result<Vec<(RevlogInner, RevIdx, RevIdx)>> {
+        if !self.have_data() {
+            return Err("Need data to verify revlog".into());
+        }
+
+        // Finding the end fills in the offset of every entry, which the forks then share.
+        let mut end = RevIdx::zero();
+        while self.contains(end)? {
+            end = end.succ();
+        }
+        let end = u32::from(end) as usize;
+
+        let mut ranges = Vec::new();
+        let mut start = 0;
+        while start < end {
+            let limit = cmp::min(start + size, end);
+            ranges.push((self.fork(), RevIdx::from(start), RevIdx::from(limit)));
+            start = limit;
+        }
+        Ok(ranges)
+    }
+
+    /// Make an independent `RevlogInner` for the same revlog. The index and data are shared
+    /// rather than copied. Of the caches, only the entry offsets are kept.
+    fn fork(&self) -> RevlogInner {
+        RevlogInner {
+            header: self.header,
+            idx: self.idx.clone(),
+            data: self.data.clone(),
+            idxoff: self.idxoff.clone(),
+            nodeidx: HashMap::new(),
+            nodeidx_complete: false,
+            revcache: LinkedHashMap::new(),
+            revcache_capacity: cmp::max(self.revcache_capacity, VERIFY_REVCACHE_CAPACITY),
+            deltas_applied: 0,
+            max_rev_size: self.max_rev_size,
+        }
+    }
+
     /// Return the set of head revisions in a revlog
     fn get_heads(&mut self) -> Result<HashSet<NodeHash>> {
         // Current set of candidate heads
@@ -620,6 +1800,185 @@ impl RevlogInner {
     }
 }
 
+/// Reader for the content of a revision, returned by `Revlog::get_rev_reader`.
+#[derive(Debug)]
+pub struct RevReader {
+    base: Vec<u8>, // text the deltas apply to
+    basepos: usize, // next unread offset in base
+    deltas: vec::IntoIter<Delta>, // deltas which haven't been reached yet
+    content: io::Cursor<Vec<u8>>, // content of the current delta
+}
+
+impl Read for RevReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let read = self.content.read(buf)?;
+            if read > 0 {
+                return Ok(read);
+            }
+
+            // copy unchanged text up to the start of the next delta
+            let end = match self.deltas.as_slice().first() {
+                Some(delta) => delta.start,
+                None => self.base.len(),
+            };
+            if self.basepos < end {
+                let len = cmp::min(buf.len(), end - self.basepos);
+                buf[..len].copy_from_slice(&self.base[self.basepos..self.basepos + len]);
+                self.basepos += len;
+                return Ok(len);
+            }
+
+            match self.deltas.next() {
+                Some(delta) => {
+                    self.basepos = delta.end;
+                    self.content = io::Cursor::new(delta.content);
+                }
+                None => return Ok(0),
+            }
+        }
+    }
+}
+
+// Number of revisions each task checks in `Revlog::verify_parallel`.
+const VERIFY_RANGE_SIZE: usize = 4096;
+
+// Revisions cached by each `Revlog::verify_parallel` task, so that consecutive revisions in a
+// delta chain don't each reconstruct it from the base.
+const VERIFY_REVCACHE_CAPACITY: usize = 1;
+
+/// Problems found by `Revlog::verify`.
+#[derive(Debug, Default)]
+pub struct VerifyReport {
+    /// Number of revisions checked
+    pub revisions: usize,
+    /// Revisions whose content doesn't match their nodeid
+    pub hash_mismatches: Vec<HashMismatch>,
+    /// Revisions with a parent which isn't an earlier revision
+    pub bad_parents: Vec<RevIdx>,
+    /// Revisions which couldn't be reconstructed
+    pub errors: Vec<(RevIdx, Error)>,
+}
+
+impl VerifyReport {
+    /// Return `true` if no problems were found.
+    pub fn is_ok(&self) -> bool {
+        self.hash_mismatches.is_empty() && self.bad_parents.is_empty() && self.errors.is_empty()
+    }
+
+    // Add the results for a later range of revisions.
+    fn merge(&mut self, other: VerifyReport) {
+        self.revisions += other.revisions;
+        self.hash_mismatches.extend(other.hash_mismatches);
+        self.bad_parents.extend(other.bad_parents);
+        self.errors.extend(other.errors);
+    }
+}
+
+/// A problem with where revisions are stored, found by `Revlog::check_layout`. Offsets are in
+/// the data, not counting the index entries interleaved with it in an inline revlog.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum LayoutAnomaly {
+    /// There are `len` unused bytes between the chunks of `before` and `after`, or before the
+    /// chunk of `after` if it's the first revision.
+    Gap {
+        before: Option<RevIdx>,
+        after: RevIdx,
+        len: u64,
+    },
+    /// The last `len` bytes of the chunk of `before` are also part of the chunk of `after`.
+    Overlap {
+        before: RevIdx,
+        after: RevIdx,
+        len: u64,
+    },
+    /// The data file is `len` bytes long, but the chunk of the last revision ends at `end`.
+    DataLength {
+        last: Option<RevIdx>,
+        end: u64,
+        len: u64,
+    },
+}
+
+/// A revision whose reconstructed content doesn't hash to its recorded nodeid.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct HashMismatch {
+    pub idx: RevIdx,
+    /// Nodeid recorded in the index
+    pub expected: NodeHash,
+    /// Nodeid computed from the content and parents
+    pub actual: NodeHash,
+}
+
+/// Totals over all the revisions in a revlog, returned by `Revlog::compression_stats`.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct CompressionStats {
+    /// Number of revisions
+    pub revisions: usize,
+    /// Total size of the stored chunks, including compression markers
+    pub stored: u64,
+    /// Total size of the full texts of the revisions
+    pub full: u64,
+    /// Number of chunks of each `ChunkCompression`
+    pub none: usize,
+    pub uncompressed: usize,
+    pub zlib: usize,
+    pub lz4: usize,
+    pub zstd: usize,
+}
+
+impl CompressionStats {
+    /// Return the stored size as a fraction of the full size, or `None` if the revisions are
+    /// all empty. This counts the savings from storing deltas as well as from compression.
+    pub fn ratio(&self) -> Option<f64> {
+        if self.full == 0 {
+            None
+        } else {
+            Some(self.stored as f64 / self.full as f64)
+        }
+    }
+
+    /// Return the fraction of the chunks which are compressed, or `None` if there are no
+    /// revisions.
+    pub fn compressed_ratio(&self) -> Option<f64> {
+        if self.revisions == 0 {
+            None
+        } else {
+            let compressed = self.zlib + self.lz4 + self.zstd;
+            Some(compressed as f64 / self.revisions as f64)
+        }
+    }
+}
+
+/// How the bytes stored for a revision are compressed, as reported by `Revlog::data_location`.
+/// This is given by the first byte of the stored chunk.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ChunkCompression {
+    /// Stored as is, with no marker. The chunk is empty or starts with a NUL byte.
+    None,
+    /// Stored as is after a `u` marker byte.
+    Uncompressed,
+    /// Compressed with zlib, whose header starts with `x`.
+    Zlib,
+    /// Compressed with LZ4 after a `4` marker byte.
+    Lz4,
+    /// Compressed with zstd, whose frame magic number starts with 0x28.
+    Zstd,
+}
+
+impl ChunkCompression {
+    fn of_chunk(chunk: &[u8]) -> Result<Self> {
+        match chunk.first() {
+            None | Some(&b'\0') => Ok(ChunkCompression::None),
+            Some(&b'u') => Ok(ChunkCompression::Uncompressed),
+            Some(&b'x') => Ok(ChunkCompression::Zlib),
+            Some(&b'4') => Ok(ChunkCompression::Lz4),
+            Some(&0x28) => Ok(ChunkCompression::Zstd),
+            Some(&marker) => bail!(ErrorKind::UnknownCompression(marker)),
+        }
+    }
+}
+
 /// Data associated with a revision.
 ///
 /// XXX internal detail?
@@ -692,3 +2051,146 @@ impl Iterator for RevlogIter {
         ret.map(|r| (idx, r))
     }
 }
+
+/// Iterator over the `Entry`s in a `Revlog`, returned by `Revlog::entries`.
+#[derive(Debug)]
+pub struct EntryIter {
+    inner: Arc<Mutex<RevlogInner>>,
+    idx: RevIdx,
+    done: bool,
+}
+
+impl Iterator for EntryIter {
+    type Item = Result<Entry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+
+        let mut revlog = self.inner.lock().expect("lock poisoned");
+        let idx = self.idx;
+
+        let ret = match revlog.contains(idx) {
+            Ok(false) => None,
+            Ok(true) => Some(revlog.get_entry(idx)),
+            Err(err) => Some(Err(err)),
+        };
+
+        match ret {
+            Some(Ok(_)) => self.idx = idx.succ(),
+            _ => self.done = true,
+        }
+        ret
+    }
+}
+
+/// Iterator over the ancestors of a revision, made by `Revlog::ancestors`.
+pub struct AncestorIter {
+    inner: Arc<Mutex<RevlogInner>>,
+    start: Option<RevIdx>, // revision whose parents haven't been read yet
+    pending: BinaryHeap<RevIdx>, // ancestors found but not yet yielded
+    seen: HashSet<RevIdx>, // every ancestor found so far
+    done: bool,
+}
+
+impl AncestorIter {
+    // Queue the parents of `idx` which haven't been seen already.
+    fn push_parents(&mut self, revlog: &mut RevlogInner, idx: RevIdx) -> Result<()> {
+        let entry = revlog.get_entry(idx)?;
+        for parent in entry.p1.into_iter().chain(entry.p2) {
+            if parent >= idx {
+                bail!(ErrorKind::Revlog(
+                    format!("parent {:?} >= idx {:?}", parent, idx),
+                ));
+            }
+            if self.seen.insert(parent) {
+                self.pending.push(parent);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Iterator for AncestorIter {
+    type Item = Result<RevIdx>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+
+        let inner = self.inner.clone();
+        let mut revlog = inner.lock().expect("lock poisoned");
+
+        if let Some(start) = self.start.take() {
+            if let Err(err) = self.push_parents(&mut revlog, start) {
+                self.done = true;
+                return Some(Err(err));
+            }
+        }
+
+        // Parents always come before their children, so taking the latest pending revision
+        // each time yields them in descending order.
+        let idx = match self.pending.pop() {
+            Some(idx) => idx,
+            None => {
+                self.done = true;
+                return None;
+            }
+        };
+        match self.push_parents(&mut revlog, idx) {
+            Ok(()) => Some(Ok(idx)),
+            Err(err) => {
+                self.done = true;
+                Some(Err(err))
+            }
+        }
+    }
+}
+
+/// Iterator over the descendants of a revision, made by `Revlog::descendants`.
+pub struct DescendantIter {
+    inner: Arc<Mutex<RevlogInner>>,
+    idx: RevIdx, // next revision to check
+    found: HashSet<RevIdx>, // the revision and the descendants found so far
+    done: bool,
+}
+
+impl Iterator for DescendantIter {
+    type Item = Result<RevIdx>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+
+        let mut revlog = self.inner.lock().expect("lock poisoned");
+
+        loop {
+            let idx = self.idx;
+            let entry = match revlog.contains(idx) {
+                Ok(false) => None,
+                Ok(true) => Some(revlog.get_entry(idx)),
+                Err(err) => Some(Err(err)),
+            };
+            let entry = match entry {
+                Some(Ok(entry)) => entry,
+                Some(Err(err)) => {
+                    self.done = true;
+                    return Some(Err(err));
+                }
+                None => {
+                    self.done = true;
+                    return None;
+                }
+            };
+            self.idx = idx.succ();
+
+            if entry.p1.into_iter().chain(entry.p2).any(|p| self.found.contains(&p)) {
+                self.found.insert(idx);
+                return Some(Ok(idx));
+            }
+        }
+    }
+}
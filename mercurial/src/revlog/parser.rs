@@ -6,7 +6,6 @@
 
 // Nom parser for Mercurial revlogs
 use std::io::Read;
-use std::fmt::Debug;
 
 use flate2::read::ZlibDecoder;
 use nom::{ErrorKind, IResult, Needed, be_u16, be_u32};
@@ -17,6 +16,7 @@ use mercurial_types::NodeHash;
 use revlog::revidx::RevIdx;
 
 use super::lz4;
+use super::zstd;
 
 // #[derive(Copy, Clone, Debug, Eq, PartialEq)]
 // pub enum Badness {
@@ -38,6 +38,8 @@ pub mod Badness {
     pub const Features: Error = 2;
     pub const BadZlib: Error = 3;
     pub const BadLZ4: Error = 4;
+    pub const BadZstd: Error = 5;
+    pub const Flags: Error = 6;
 }
 
 /// `Revlog` features
@@ -51,7 +53,12 @@ bitflags! {
 /// Per-revision flags
 bitflags! {
     pub struct IdxFlags: u16 {
+        /// The content has been replaced with a tombstone
         const CENSORED      = 1 << 15;
+        /// The revision is an ellipsis node in a narrow clone, and its parents are not real
+        const ELLIPSIS      = 1 << 14;
+        /// The content is stored elsewhere, and the revlog holds a pointer to it (eg. LFS)
+        const EXTSTORED     = 1 << 13;
     }
 }
 
@@ -60,6 +67,18 @@ bitflags! {
 pub enum Version {
     Revlog0 = 0,
     RevlogNG = 1,
+    RevlogV2 = 0xDEAD,
+}
+
+impl Version {
+    pub fn from_u16(version: u16) -> Option<Version> {
+        match version {
+            0 => Some(Version::Revlog0),
+            1 => Some(Version::RevlogNG),
+            0xDEAD => Some(Version::RevlogV2),
+            _ => None,
+        }
+    }
 }
 
 /// Revlog header
@@ -69,6 +88,23 @@ pub struct Header {
     pub features: Features,
 }
 
+impl Header {
+    pub fn version(&self) -> Version {
+        self.version
+    }
+
+    /// Return `true` if revision data is stored in the index file, after each entry.
+    pub fn is_inline(&self) -> bool {
+        self.features.contains(INLINE)
+    }
+
+    /// Return `true` if deltas may be against any earlier revision, rather than always
+    /// against the previous one.
+    pub fn is_generaldelta(&self) -> bool {
+        self.features.contains(GENERAL_DELTA)
+    }
+}
+
 /// Entry entry for a revision
 #[derive(Copy, Clone, Debug)]
 pub struct Entry {
@@ -87,29 +123,36 @@ impl Entry {
     pub fn nodeid(&self) -> &NodeHash {
         &self.nodeid
     }
+
+    pub fn flags(&self) -> IdxFlags {
+        self.flags
+    }
+
+    /// Return `true` if the content of this revision has been censored, and replaced with a
+    /// tombstone.
+    pub fn is_censored(&self) -> bool {
+        self.flags.contains(CENSORED)
+    }
+
+    /// Return the index of the changeset which introduced this revision.
+    ///
+    /// For filelogs and manifests this is an index into the changelog; for the changelog
+    /// itself it's the revision's own index.
+    pub fn linkrev(&self) -> Option<RevIdx> {
+        self.linkrev
+    }
 }
 
 /// Parse the revlog header
 named!(pub header<Header>,
     do_parse!(
-        features: return_error!(ErrorKind::Custom(Badness::IO), be_u16) >>
-        version: return_error!(ErrorKind::Custom(Badness::IO), be_u16) >>
-        ({
-            let vers = match version {
-                0 => Version::Revlog0,
-                1 => Version::RevlogNG,
-                _ => panic!("bad version"),
-            };
-
-            let features = match Features::from_bits(features) {
-                Some(f) => f,
-                None => panic!("bad features"),
-            };
-
-            Header {
-                version: vers,
-                features: features,
-            }
+        features: return_error!(ErrorKind::Custom(Badness::Features),
+                                map_opt!(be_u16, Features::from_bits)) >>
+        version: return_error!(ErrorKind::Custom(Badness::Version),
+                               map_opt!(be_u16, Version::from_u16)) >>
+        (Header {
+            version: version,
+            features: features,
         }))
 );
 
@@ -121,7 +164,8 @@ pub fn indexng_size() -> usize {
 named!(pub indexng<Entry>,
     do_parse!(
         offset: return_error!(ErrorKind::Custom(Badness::IO), be_u48) >>    // XXX if first, then only 2 bytes, implied 0 in top 4
-        flags: return_error!(ErrorKind::Custom(Badness::IO), be_u16) >>     // ?
+        flags: return_error!(ErrorKind::Custom(Badness::Flags),
+                             map_opt!(be_u16, IdxFlags::from_bits)) >>
         compressed_length: return_error!(ErrorKind::Custom(Badness::IO), be_u32) >>
         uncompressed_length: return_error!(ErrorKind::Custom(Badness::IO), be_u32) >>
         baserev: return_error!(ErrorKind::Custom(Badness::IO), be_u32) >>
@@ -132,7 +176,7 @@ named!(pub indexng<Entry>,
         ({
             Entry {
                 offset: offset,
-                flags: IdxFlags::from_bits(flags).expect("bad rev idx flags"),
+                flags: flags,
                 compressed_len: compressed_length,
                 len: Some(uncompressed_length),
                 baserev: if baserev == !0 { None } else { Some(baserev.into()) },
@@ -145,6 +189,12 @@ named!(pub indexng<Entry>,
     )
 );
 
+/// Revlog v2 entries start with the same fields as NG ones, followed by the sidedata offset and
+/// length, compression modes and padding.
+pub fn indexv2_size() -> usize {
+    indexng_size() + 8 + 4 + 1 + 1 + 18
+}
+
 pub fn index0_size() -> usize {
     4 + 4 + 4 + 4 + 4 + 4 + 4 + 20
 }
@@ -204,7 +254,8 @@ named!(pub deltachunk<Vec<Delta> >,
                 do_parse!(tag!(b"u") >> d: deltas >> (d)) |                                  // uncompressed with explicit 'u' header
                 do_parse!(peek!(tag!(b"\0")) >> d: deltas >> (d)) |                          // uncompressed with included initial 0x00
                 do_parse!(peek!(tag!(b"x")) >> d: apply!(zlib_decompress, deltas) >> (d)) |  // compressed; 'x' part of the zlib stream
-                do_parse!(tag!(b"4") >> d: apply!(lz4::lz4_decompress, deltas) >> (d))       // compressed w/ lz4
+                do_parse!(tag!(b"4") >> d: apply!(lz4::lz4_decompress, deltas) >> (d)) |     // compressed w/ lz4
+                do_parse!(peek!(tag!(b"\x28")) >> d: apply!(zstd::zstd_decompress, deltas) >> (d)) // compressed w/ zstd
             )
         ),
         |dv: Vec<_>| dv.into_iter().flat_map(|x| x).collect())
@@ -221,9 +272,10 @@ named!(remains_owned<Vec<u8> >,
 named!(pub literal<Vec<u8> >,
     alt!(
         do_parse!(peek!(tag!(b"\0")) >> d: remains >> (d.into())) |
-        do_parse!(peek!(tag!(b"x")) >> d: apply!(zlib_decompress, remains_owned) >> (d)) |
+        do_parse!(peek!(tag!(b"x")) >> d: zlib_literal >> (d)) |
         do_parse!(tag!(b"4") >> d: apply!(lz4::lz4_decompress, remains_owned) >> (d)) |
-        do_parse!(tag!(b"u") >> d: remains >> (d.into()))
+        do_parse!(tag!(b"u") >> d: remains >> (d.into())) |
+        do_parse!(peek!(tag!(b"\x28")) >> d: apply!(zstd::zstd_decompress, remains_owned) >> (d))
     )
 );
 
@@ -240,10 +292,21 @@ pub fn detach_result<'a, O, E>(
 }
 
 /// Unpack a chunk of data and apply a parse function to the output.
-fn zlib_decompress<P, R, E: Debug>(i: &[u8], parse: P) -> IResult<&[u8], R, E>
+fn zlib_decompress<P, R>(i: &[u8], parse: P) -> IResult<&[u8], R, Error>
 where
-    for<'a> P: Fn(&'a [u8]) -> IResult<&'a [u8], R, E> + 'a,
+    for<'a> P: Fn(&'a [u8]) -> IResult<&'a [u8], R, Error> + 'a,
 {
+    match zlib_literal(i) {
+        IResult::Done(remains, data) => detach_result(parse(&data[..]), remains),
+        IResult::Incomplete(n) => IResult::Incomplete(n),
+        IResult::Error(e) => IResult::Error(e),
+    }
+}
+
+/// Unpack a chunk of literal data. The decoder streams straight into the returned buffer,
+/// which becomes the base of the reconstructed revision, so the text is never copied out of
+/// a temporary buffer.
+fn zlib_literal(i: &[u8]) -> IResult<&[u8], Vec<u8>, Error> {
     let mut data = Vec::new();
 
     let inused = {
@@ -251,13 +314,11 @@ where
 
         match zdec.read_to_end(&mut data) {
             Ok(_) => zdec.total_in() as usize,
-            Err(err) => panic!("zdec failed {:?}", err),
+            Err(_) => return IResult::Error(ErrorKind::Custom(Badness::BadZlib)),
         }
     };
 
-    let remains = &i[inused..];
-
-    detach_result(parse(&data[..]), remains)
+    IResult::Done(&i[inused..], data)
 }
 
 /// Parse a 6 byte big-endian offset
@@ -274,8 +335,37 @@ fn be_u48(i: &[u8]) -> IResult<&[u8], u64> {
 
 #[cfg(test)]
 mod test {
-    use nom::IResult;
-    use super::{Features, GENERAL_DELTA, Header, INLINE, Version, header};
+    use std::io::Write;
+
+    use flate2::Compression;
+    use flate2::write::ZlibEncoder;
+    use nom::{ErrorKind, IResult};
+    use super::{Badness, Features, GENERAL_DELTA, Header, INLINE, Version, header, literal,
+                remains_owned, zlib_decompress, zlib_literal};
+
+    #[test]
+    fn zlib_streaming() {
+        let text: Vec<u8> = (0..100000u32).map(|i| (i % 251) as u8).collect();
+        let mut enc = ZlibEncoder::new(Vec::new(), Compression::Default);
+        enc.write_all(&text).unwrap();
+        let chunk = enc.finish().unwrap();
+
+        // The literal parser decodes straight into its output, and must give the same result
+        // as decompressing into a buffer and copying it out.
+        let streamed = match literal(&chunk) {
+            IResult::Done(_, data) => data,
+            err => panic!("failed to parse literal: {:?}", err),
+        };
+        let buffered = match zlib_decompress(&chunk, remains_owned) {
+            IResult::Done(_, data) => data,
+            err => panic!("failed to decompress: {:?}", err),
+        };
+        assert_eq!(streamed, buffered);
+        assert_eq!(streamed, text);
+
+        let corrupt = b"x\xff\xff\xff";
+        assert_eq!(zlib_literal(&corrupt[..]), IResult::Error(ErrorKind::Custom(Badness::BadZlib)));
+    }
 
     #[test]
     fn test_header_0() {
@@ -321,6 +411,28 @@ mod test {
         }))
     }
 
+    #[test]
+    fn test_header_accessors() {
+        let cases = [
+            ([0x00, 0x00, 0x00, 0x00], Version::Revlog0, false, false),
+            ([0x00, 0x00, 0x00, 0x01], Version::RevlogNG, false, false),
+            ([0x00, 0x01, 0x00, 0x01], Version::RevlogNG, true, false),
+            ([0x00, 0x02, 0x00, 0x01], Version::RevlogNG, false, true),
+            ([0x00, 0x03, 0x00, 0x01], Version::RevlogNG, true, true),
+            ([0x00, 0x00, 0xde, 0xad], Version::RevlogV2, false, false),
+        ];
+
+        for &(ref d, version, inline, generaldelta) in cases.iter() {
+            let hdr = match header(&d[..]) {
+                IResult::Done(_, hdr) => hdr,
+                err => panic!("failed to parse header {:?}: {:?}", d, err),
+            };
+            assert_eq!(hdr.version(), version);
+            assert_eq!(hdr.is_inline(), inline);
+            assert_eq!(hdr.is_generaldelta(), generaldelta);
+        }
+    }
+
     #[test]
     fn test_header_feat_3() {
         let d = [0x00, 0x03, 0x00, 0x01];
@@ -4,6 +4,7 @@
 // This software may be used and distributed according to the terms of the
 // GNU General Public License version 2 or any later version.
 
+use std::fmt::{self, Display};
 use std::str::FromStr;
 use std::ops::{Add, Mul};
 use std::u32;
@@ -59,6 +60,13 @@ impl From<usize> for RevIdx {
     }
 }
 
+// Return the `RevIdx` as a plain `u32`
+impl From<RevIdx> for u32 {
+    fn from(v: RevIdx) -> Self {
+        v.0
+    }
+}
+
 // Construct a `RevIdx` from a string (which may fail)
 impl FromStr for RevIdx {
     type Err = <u32 as FromStr>::Err;
@@ -68,6 +76,13 @@ impl FromStr for RevIdx {
     }
 }
 
+// Display a `RevIdx` as the bare number
+impl Display for RevIdx {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        Display::fmt(&self.0, fmt)
+    }
+}
+
 // Multiply operator for RevIdx * usize -> usize
 // Used for constructing a byte offset for an index
 impl Mul<usize> for RevIdx {
@@ -167,6 +182,17 @@ mod test {
         assert_eq!(idx, RevIdx(555));
     }
 
+    #[test]
+    fn into_u32() {
+        assert_eq!(u32::from(RevIdx(7)), 7);
+    }
+
+    #[test]
+    fn display() {
+        assert_eq!(format!("{}", RevIdx(42)), "42");
+        assert_eq!(format!("{:>4}", RevIdx(42)), "  42");
+    }
+
     #[test]
     fn fromstr_bad1() {
         match RevIdx::from_str("abc123") {
@@ -4,10 +4,60 @@
 // This software may be used and distributed according to the terms of the
 // GNU General Public License version 2 or any later version.
 
+use std::fs::File;
+use std::io::{Read, Write};
+use std::str::FromStr;
+
+use tempdir::TempDir;
+
 use super::*;
 
 static EMPTY: &[u8] = include_bytes!("empty.i.bin");
 
+// Four revisions; 1 is a delta against 0, and 3 is a delta against 2. Both 2 and 3 have 1 as
+// their parent.
+static SIMPLE_IDX: &[u8] = include_bytes!("simple.i.bin");
+static SIMPLE_DATA: &[u8] = include_bytes!("simple.d.bin");
+
+// The same revisions as `SIMPLE`, but inlined and with 3 stored as a delta against 1.
+static GENERALDELTA_IDX: &[u8] = include_bytes!("generaldelta.i.bin");
+
+// `SIMPLE_IDX` with the last five bytes missing, as if writing the last entry was interrupted.
+static TRUNCATED_IDX: &[u8] = include_bytes!("simple-truncated.i.bin");
+
+// `SIMPLE_IDX` converted to revlog v2, which has wider entries.
+static SIMPLE_V2_IDX: &[u8] = include_bytes!("simple-v2.i.bin");
+
+// A zstd frame containing `TEXTS[0]`.
+static ZSTD: &[u8] = include_bytes!("zstd.bin");
+
+static NODEIDS: [&str; 4] = [
+    "1aa8663bd94a3cf6065c24e16463707c2cfa7610",
+    "a383dc3b93c51c7012f03c8360fdf58479030266",
+    "33d68faa8d39ec00ce3850870c4e3ee6c8493e4b",
+    "9ae37abdde3ae6c95ec70eac859e3b589372f3d9",
+];
+
+static TEXTS: [&[u8]; 4] = [
+    b"alpha\nbeta\ngamma\n",
+    b"alpha\nBETA\ngamma\n",
+    b"a completely new text\na completely new text\n\
+      a completely new text\na completely new text\n",
+    b"alpha\nBETA\ngamma\ndelta\n",
+];
+
+fn nodeid(idx: usize) -> NodeHash {
+    NodeHash::from_str(NODEIDS[idx]).expect("bad nodeid")
+}
+
+fn simple() -> Revlog {
+    Revlog::new(SIMPLE_IDX.to_vec(), Some(SIMPLE_DATA.to_vec())).expect("construction failed")
+}
+
+fn generaldelta() -> Revlog {
+    Revlog::new(GENERALDELTA_IDX.to_vec(), None).expect("construction failed")
+}
+
 #[test]
 fn emptyrev() {
     let revlog = Revlog::new(EMPTY.to_vec(), None).expect("construction failed");
@@ -17,3 +67,933 @@ fn emptyrev() {
 
     assert_eq!(node.size(), Some(0));
 }
+
+#[test]
+fn lookup_by_nodeid() {
+    let revlog = simple();
+
+    for idx in 0..NODEIDS.len() {
+        assert_eq!(
+            revlog.get_idx_by_nodeid(&nodeid(idx)).expect("lookup failed"),
+            RevIdx::from(idx)
+        );
+    }
+
+    let node = generaldelta()
+        .get_rev_by_nodeid(&nodeid(2))
+        .expect("failed to get rev");
+    assert_eq!(node.nodeid(), Some(nodeid(2)));
+
+    let missing = NodeHash::from_str("0123456789012345678901234567890123456789").unwrap();
+    match revlog.get_idx_by_nodeid(&missing) {
+        Err(Error(ErrorKind::NoSuchNode(ref id), _)) => assert_eq!(*id, missing),
+        res => panic!("unexpected result {:?}", res),
+    }
+}
+
+#[test]
+fn lookup_by_nodeid_damaged() {
+    // Cut the inline index off partway through the entry for revision 2.
+    let (off, len, _) = generaldelta()
+        .data_location(RevIdx::from(1u32))
+        .expect("data_location failed");
+    let end = (off + len) as usize + 10;
+    let revlog = Revlog::new(GENERALDELTA_IDX[..end].to_vec(), None).expect("construction failed");
+
+    // The damage is reported, rather than the later revisions being missing, and the lookup
+    // doesn't go on to trust a partial cache.
+    for _ in 0..2 {
+        match revlog.get_idx_by_nodeid(&nodeid(3)) {
+            Err(Error(ErrorKind::NoSuchNode(_), _)) => panic!("damaged entry not reported"),
+            Err(_) => (),
+            Ok(idx) => panic!("unexpected revision {}", idx),
+        }
+    }
+}
+
+#[test]
+fn entries() {
+    let revlog = simple();
+    let entries: Vec<_> = revlog
+        .entries()
+        .collect::<Result<_>>()
+        .expect("failed to read entries");
+
+    // Every entry in a non-inline index has the same size.
+    assert_eq!(entries.len(), SIMPLE_IDX.len() / parser::indexng_size());
+    for (idx, entry) in entries.iter().enumerate() {
+        assert_eq!(entry.nodeid, nodeid(idx));
+    }
+
+    let entries: Vec<_> = generaldelta()
+        .entries()
+        .collect::<Result<_>>()
+        .expect("failed to read inline entries");
+    assert_eq!(entries.len(), NODEIDS.len());
+
+    assert_eq!(Revlog::new(EMPTY.to_vec(), None).unwrap().entries().count(), 1);
+}
+
+#[test]
+fn verify() {
+    for revlog in vec![simple(), generaldelta()] {
+        let report = revlog.verify().expect("verify failed");
+        assert!(report.is_ok(), "{:?}", report);
+        assert_eq!(report.revisions, NODEIDS.len());
+    }
+
+    // Corrupt the text of revision 0, which revision 1 is a delta against.
+    let mut data = SIMPLE_DATA.to_vec();
+    data[1] = b'A';
+    let revlog = Revlog::new(SIMPLE_IDX.to_vec(), Some(data)).unwrap();
+    let report = revlog.verify().expect("verify failed");
+
+    let bad: Vec<_> = report.hash_mismatches.iter().map(|m| m.idx).collect();
+    assert_eq!(bad, vec![RevIdx::from(0u32), RevIdx::from(1u32)]);
+    assert_eq!(report.hash_mismatches[0].expected, nodeid(0));
+    assert!(report.bad_parents.is_empty());
+    assert!(report.errors.is_empty());
+}
+
+#[test]
+fn verify_parallel() {
+    fn summary(report: &VerifyReport) -> (usize, Vec<HashMismatch>, Vec<RevIdx>, Vec<RevIdx>) {
+        (
+            report.revisions,
+            report.hash_mismatches.clone(),
+            report.bad_parents.clone(),
+            report.errors.iter().map(|&(idx, _)| idx).collect(),
+        )
+    }
+
+    let pool = CpuPool::new(4);
+    let mut data = SIMPLE_DATA.to_vec();
+    data[1] = b'A';
+    let corrupt = Revlog::new(SIMPLE_IDX.to_vec(), Some(data)).unwrap();
+
+    for revlog in vec![simple(), generaldelta(), corrupt] {
+        let expected = summary(&revlog.verify().expect("verify failed"));
+        let report = revlog.verify_parallel(&pool).wait().expect("verify failed");
+        assert_eq!(summary(&report), expected);
+
+        // Ranges which split delta chains give the same result.
+        for size in 1..NODEIDS.len() {
+            let report = revlog.verify_in_ranges(&pool, size).wait().expect("verify failed");
+            assert_eq!(summary(&report), expected);
+        }
+    }
+
+    let nodata = Revlog::new(SIMPLE_IDX.to_vec(), None).unwrap();
+    assert!(nodata.verify_parallel(&pool).wait().is_err());
+}
+
+#[test]
+fn data_location() {
+    let simple = simple();
+    let expected = [
+        ChunkCompression::Uncompressed,
+        ChunkCompression::None,
+        ChunkCompression::Zlib,
+        ChunkCompression::Zlib,
+    ];
+    for (idx, compression) in RevIdx::zero().range_to(RevIdx::from(4u32)).zip(&expected) {
+        let (off, len, actual) = simple.data_location(idx).unwrap();
+        let range = &SIMPLE_DATA[off as usize..(off + len) as usize];
+        assert_eq!(range, &simple.get_raw_chunk(idx).unwrap()[..]);
+        assert_eq!(actual, *compression);
+    }
+
+    // Inline data is located in the index.
+    let generaldelta = generaldelta();
+    for idx in RevIdx::zero().range_to(RevIdx::from(4u32)) {
+        let (off, len, _) = generaldelta.data_location(idx).unwrap();
+        let range = &GENERALDELTA_IDX[off as usize..(off + len) as usize];
+        assert_eq!(range, &generaldelta.get_raw_chunk(idx).unwrap()[..]);
+    }
+
+    let nodata = Revlog::new(SIMPLE_IDX.to_vec(), None).unwrap();
+    assert!(nodata.data_location(RevIdx::zero()).is_err());
+}
+
+#[test]
+fn compression_stats() {
+    let stats = simple().compression_stats().unwrap();
+    let full: usize = TEXTS.iter().map(|text| text.len()).sum();
+    assert_eq!(
+        stats,
+        CompressionStats {
+            revisions: 4,
+            stored: SIMPLE_DATA.len() as u64,
+            full: full as u64,
+            none: 1,
+            uncompressed: 1,
+            zlib: 2,
+            lz4: 0,
+            zstd: 0,
+        }
+    );
+    assert_eq!(stats.ratio(), Some(SIMPLE_DATA.len() as f64 / full as f64));
+    assert_eq!(stats.compressed_ratio(), Some(0.5));
+
+    // The counts agree with the compression of each revision.
+    let generaldelta = generaldelta();
+    let stats = generaldelta.compression_stats().unwrap();
+    let mut zlib = 0;
+    for idx in RevIdx::zero().range_to(RevIdx::from(4u32)) {
+        let (_, _, compression) = generaldelta.data_location(idx).unwrap();
+        if compression == ChunkCompression::Zlib {
+            zlib += 1;
+        }
+    }
+    assert_eq!(stats.revisions, 4);
+    assert_eq!(stats.zlib, zlib);
+    assert_eq!(stats.none + stats.uncompressed + stats.zlib, 4);
+
+    let nodata = Revlog::new(SIMPLE_IDX.to_vec(), None).unwrap();
+    assert!(nodata.compression_stats().is_err());
+}
+
+#[test]
+fn max_rev_size() {
+    for revlog in vec![simple(), generaldelta()] {
+        let revlog = revlog.with_max_rev_size(20);
+        for idx in 0..2 {
+            let node = revlog.get_rev(RevIdx::from(idx)).unwrap();
+            assert_eq!(node.as_blob().as_slice(), Some(TEXTS[idx]));
+        }
+
+        match revlog.get_rev(RevIdx::from(2u32)) {
+            Err(Error(ErrorKind::RevTooLarge(idx, size, limit), _)) => {
+                assert_eq!((idx, size, limit), (RevIdx::from(2u32), TEXTS[2].len(), 20))
+            }
+            res => panic!("unexpected result {:?}", res),
+        }
+        assert!(revlog.get_rev(RevIdx::from(3u32)).is_err());
+    }
+
+    // In the generaldelta revlog, revision 3 is a delta against 1, so only its own size is
+    // over the limit.
+    match generaldelta().with_max_rev_size(20).get_rev(RevIdx::from(3u32)) {
+        Err(Error(ErrorKind::RevTooLarge(_, size, _), _)) => assert_eq!(size, TEXTS[3].len()),
+        res => panic!("unexpected result {:?}", res),
+    }
+}
+
+#[test]
+fn is_snapshot() {
+    // In both revlogs, 0 and 2 are snapshots and 1 and 3 are deltas.
+    for revlog in vec![simple(), generaldelta()] {
+        let snapshots: Vec<_> = RevIdx::zero()
+            .range_to(RevIdx::from(4u32))
+            .map(|idx| revlog.is_snapshot(idx).unwrap())
+            .collect();
+        assert_eq!(snapshots, vec![true, false, true, false]);
+
+        for idx in RevIdx::zero().range_to(RevIdx::from(4u32)) {
+            assert_eq!(revlog.is_snapshot(idx).unwrap(), revlog.chain_length(idx).unwrap() == 0);
+        }
+    }
+
+    assert!(simple().is_snapshot(RevIdx::from(4u32)).is_err());
+}
+
+#[test]
+fn check_layout() {
+    for revlog in vec![simple(), generaldelta()] {
+        assert_eq!(revlog.check_layout().unwrap(), vec![]);
+    }
+
+    // Move the chunk of revision 2 four bytes later, into the one of revision 3, and add some
+    // data after the end of the last chunk.
+    let mut idx = SIMPLE_IDX.to_vec();
+    assert_eq!(idx[2 * 64 + 5], 35);
+    idx[2 * 64 + 5] = 39;
+    let mut data = SIMPLE_DATA.to_vec();
+    data.push(0);
+    let revlog = Revlog::new(idx, Some(data)).unwrap();
+
+    assert_eq!(
+        revlog.check_layout().unwrap(),
+        vec![
+            LayoutAnomaly::Gap {
+                before: Some(RevIdx::from(1u32)),
+                after: RevIdx::from(2u32),
+                len: 4,
+            },
+            LayoutAnomaly::Overlap {
+                before: RevIdx::from(2u32),
+                after: RevIdx::from(3u32),
+                len: 4,
+            },
+            LayoutAnomaly::DataLength {
+                last: Some(RevIdx::from(3u32)),
+                end: SIMPLE_DATA.len() as u64,
+                len: SIMPLE_DATA.len() as u64 + 1,
+            },
+        ]
+    );
+}
+
+#[test]
+fn parents() {
+    let revlog = simple();
+
+    assert_eq!(revlog.parents(RevIdx::from(0u32)).unwrap(), (None, None));
+    assert_eq!(revlog.parents(RevIdx::from(1u32)).unwrap(), (Some(nodeid(0)), None));
+    assert_eq!(revlog.parents(RevIdx::from(2u32)).unwrap(), (Some(nodeid(1)), None));
+    assert_eq!(revlog.parents(RevIdx::from(3u32)).unwrap(), (Some(nodeid(1)), None));
+}
+
+#[test]
+fn reconstruct() {
+    let simple = simple();
+    let generaldelta = generaldelta();
+
+    assert!(!simple.get_header().features.contains(parser::GENERAL_DELTA));
+    assert!(generaldelta.get_header().features.contains(parser::GENERAL_DELTA));
+
+    // In the generaldelta revlog, revision 3 is a delta against 1 rather than 2.
+    let entry = generaldelta.get_entry(RevIdx::from(3u32)).unwrap();
+    assert_eq!(entry.baserev, Some(RevIdx::from(1u32)));
+
+    for revlog in vec![simple, generaldelta] {
+        for (idx, text) in TEXTS.iter().enumerate() {
+            let node = revlog.get_rev(RevIdx::from(idx)).expect("failed to get rev");
+            assert_eq!(node.as_blob().as_slice(), Some(*text));
+            assert_eq!(node.nodeid(), Some(nodeid(idx)));
+        }
+    }
+}
+
+#[test]
+fn rev_cache() {
+    let deltas_applied = |revlog: &Revlog| revlog.inner.lock().unwrap().deltas_applied;
+
+    for revlog in vec![simple(), generaldelta()] {
+        let revlog = revlog.with_cache(2);
+        let tip = RevIdx::from(3u32);
+
+        revlog.get_rev(tip).expect("failed to get rev");
+        let first = deltas_applied(&revlog);
+        assert!(first > 0);
+
+        // The second fetch is served from the cache.
+        let node = revlog.get_rev(tip).expect("failed to get rev");
+        assert_eq!(node.as_blob().as_slice(), Some(TEXTS[3]));
+        assert_eq!(deltas_applied(&revlog), first);
+    }
+
+    // In the generaldelta revlog, 3 is a delta against 1, so having 1 cached means only one
+    // delta needs applying.
+    let revlog = generaldelta().with_cache(1);
+    revlog.get_rev(RevIdx::from(1u32)).unwrap();
+    let before = deltas_applied(&revlog);
+    let node = revlog.get_rev(RevIdx::from(3u32)).unwrap();
+    assert_eq!(node.as_blob().as_slice(), Some(TEXTS[3]));
+    assert_eq!(deltas_applied(&revlog), before + 1);
+
+    // Without a cache, nothing is remembered.
+    let revlog = simple();
+    revlog.get_rev(RevIdx::from(1u32)).unwrap();
+    revlog.get_rev(RevIdx::from(1u32)).unwrap();
+    assert_eq!(deltas_applied(&revlog), 2);
+}
+
+// A revlog whose only revision is stored as `chunk`.
+fn single_chunk(chunk: &[u8]) -> Revlog {
+    let mut idx = SIMPLE_IDX[..parser::indexng_size()].to_vec();
+    // The compressed length is the third field of the entry.
+    let len = chunk.len() as u32;
+    let len = [(len >> 24) as u8, (len >> 16) as u8, (len >> 8) as u8, len as u8];
+    idx[8..12].copy_from_slice(&len);
+    Revlog::new(idx, Some(chunk.to_vec())).expect("construction failed")
+}
+
+#[test]
+fn chunk_markers() {
+    let literal = |chunk: &[u8]| match single_chunk(chunk).get_chunk(RevIdx::zero()) {
+        Ok(Chunk::Literal(text)) => text,
+        res => panic!("unexpected result {:?}", res),
+    };
+
+    let mut uncompressed = b"u".to_vec();
+    uncompressed.extend_from_slice(TEXTS[0]);
+    assert_eq!(literal(&uncompressed), TEXTS[0]);
+
+    // Text starting with a NUL is stored as is, without a marker.
+    assert_eq!(literal(b"\0binary"), b"\0binary");
+
+    let mut enc = ZlibEncoder::new(Vec::new(), Compression::Default);
+    enc.write_all(TEXTS[0]).unwrap();
+    assert_eq!(literal(&enc.finish().unwrap()), TEXTS[0]);
+
+    assert_eq!(literal(ZSTD), TEXTS[0]);
+
+    // The full text is reconstructed from each of them.
+    for chunk in vec![&uncompressed[..], ZSTD] {
+        let node = single_chunk(chunk).get_rev(RevIdx::zero()).unwrap();
+        assert_eq!(node.as_blob().as_slice(), Some(TEXTS[0]));
+    }
+
+    let mut unknown = uncompressed.clone();
+    unknown[0] = b'Q';
+    match single_chunk(&unknown).get_rev(RevIdx::zero()) {
+        Err(Error(ErrorKind::UnknownCompression(b'Q'), _)) => (),
+        res => panic!("unexpected result {:?}", res),
+    }
+}
+
+#[test]
+fn zstd_chunk() {
+    match parser::literal(ZSTD) {
+        IResult::Done(rest, literal) => {
+            assert!(rest.is_empty());
+            assert_eq!(literal, TEXTS[0]);
+        }
+        err => panic!("failed to decompress: {:?}", err),
+    }
+
+    // Replace the 'u' marker of revision 0 with something unknown.
+    let mut data = SIMPLE_DATA.to_vec();
+    data[0] = b'Q';
+    let revlog = Revlog::new(SIMPLE_IDX.to_vec(), Some(data)).unwrap();
+    match revlog.get_chunk(RevIdx::from(0u32)) {
+        Err(Error(ErrorKind::UnknownCompression(b'Q'), _)) => (),
+        res => panic!("unexpected result {:?}", res),
+    }
+}
+
+#[test]
+fn linkrev() {
+    // The fixtures were written as if each revision was introduced by the changeset with
+    // the same index.
+    for revlog in vec![simple(), generaldelta()] {
+        let entry = revlog.get_entry(RevIdx::from(2u32)).unwrap();
+        assert_eq!(entry.linkrev(), Some(RevIdx::from(2u32)));
+    }
+}
+
+#[test]
+fn linkrev_map() {
+    for revlog in vec![simple(), generaldelta()] {
+        let map = revlog.linkrev_map().unwrap();
+        assert_eq!(map.len(), NODEIDS.len());
+        assert_eq!(map.get(&nodeid(2)), Some(&RevIdx::from(2u32)));
+        for entry in revlog.entries() {
+            let entry = entry.unwrap();
+            assert_eq!(map.get(entry.nodeid()).cloned(), entry.linkrev());
+        }
+    }
+}
+
+#[test]
+fn from_idx_data() {
+    let dir = TempDir::new("revlog").expect("tempdir failed");
+    let write = |name: &str, data: &[u8]| {
+        let path = dir.path().join(name);
+        File::create(&path).unwrap().write_all(data).unwrap();
+        path
+    };
+
+    // The data file is found next to a non-inline index.
+    let idxpath = write("simple.i", SIMPLE_IDX);
+    write("simple.d", SIMPLE_DATA);
+    let revlog = Revlog::from_idx_data(&idxpath, None as Option<String>).unwrap();
+    assert!(revlog.verify().unwrap().is_ok());
+
+    // An inline index doesn't need one.
+    let idxpath = write("generaldelta.i", GENERALDELTA_IDX);
+    let revlog = Revlog::from_idx_data(&idxpath, None as Option<String>).unwrap();
+    assert!(revlog.have_data());
+    assert!(revlog.verify().unwrap().is_ok());
+
+    // And it's an error to provide one.
+    let datapath = dir.path().join("simple.d");
+    assert!(Revlog::from_idx_data(&idxpath, Some(&datapath)).is_err());
+
+    // A data file which is too short for the index is rejected straight away.
+    let short = write("short.d", &SIMPLE_DATA[..50]);
+    match Revlog::from_idx_data(dir.path().join("simple.i"), Some(&short)) {
+        Err(Error(ErrorKind::DataFileTooShort(expected, actual), _)) => {
+            assert_eq!(expected, SIMPLE_DATA.len() as u64);
+            assert_eq!(actual, 50);
+        }
+        res => panic!("unexpected result {:?}", res),
+    }
+    match Revlog::from_idx_data_bytes(SIMPLE_IDX.to_vec(), Some(SIMPLE_DATA[..50].to_vec())) {
+        Err(Error(ErrorKind::DataFileTooShort(..), _)) => {}
+        res => panic!("unexpected result {:?}", res),
+    }
+
+    // Without a data file next to a non-inline index, the error says where it looked.
+    let idxpath = write("lonely.i", SIMPLE_IDX);
+    match Revlog::from_idx_data(&idxpath, None as Option<String>) {
+        Err(err) => assert!(format!("{}", err).contains("lonely.d")),
+        Ok(_) => panic!("opened a revlog without its data"),
+    }
+}
+
+#[test]
+fn delta() {
+    for revlog in vec![simple(), generaldelta()] {
+        for (from, old) in TEXTS.iter().enumerate() {
+            for (to, new) in TEXTS.iter().enumerate() {
+                let deltas = revlog
+                    .delta(RevIdx::from(from), RevIdx::from(to))
+                    .expect("delta failed");
+                assert_eq!(&bdiff::apply(old, &deltas)[..], *new);
+            }
+        }
+    }
+
+    assert!(simple().delta(RevIdx::from(0u32), RevIdx::from(4u32)).is_err());
+}
+
+#[test]
+fn read_rev_into() {
+    for revlog in vec![simple(), generaldelta(), generaldelta().with_cache(2)] {
+        let mut buf = Vec::new();
+        // Twice, so cached revisions are read too.
+        for _ in 0..2 {
+            for idx in 0..TEXTS.len() {
+                let idx = RevIdx::from(idx);
+                revlog.read_rev_into(idx, &mut buf).expect("read_rev_into failed");
+                let node = revlog.get_rev(idx).expect("get_rev failed");
+                assert_eq!(node.as_blob().as_slice(), Some(&buf[..]));
+            }
+        }
+    }
+
+    let mut buf = b"stale".to_vec();
+    assert!(simple().read_rev_into(RevIdx::from(4u32), &mut buf).is_err());
+}
+
+#[test]
+fn from_idx_data_bytes() {
+    let revlog = Revlog::from_idx_data_bytes(SIMPLE_IDX.to_vec(), Some(SIMPLE_DATA.to_vec()))
+        .expect("construction failed");
+    assert_eq!(revlog.get_entry(RevIdx::from(1u32)).unwrap().nodeid(), &nodeid(1));
+    assert!(revlog.verify().unwrap().is_ok());
+
+    let revlog = Revlog::from_idx_data_bytes(GENERALDELTA_IDX.to_vec(), None).unwrap();
+    let node = revlog.get_rev(RevIdx::from(3u32)).unwrap();
+    assert_eq!(node.as_blob().as_slice(), Some(TEXTS[3]));
+
+    // Data is needed for a non-inline index, and can't be given for an inline one.
+    assert!(Revlog::from_idx_data_bytes(SIMPLE_IDX.to_vec(), None).is_err());
+    assert!(
+        Revlog::from_idx_data_bytes(GENERALDELTA_IDX.to_vec(), Some(SIMPLE_DATA.to_vec()))
+            .is_err()
+    );
+}
+
+#[test]
+fn ancestors() {
+    let revs = |v: &[u32]| v.iter().map(|idx| RevIdx::from(*idx)).collect::<Vec<_>>();
+
+    // 0 is the root, 1 is its child, and 2 and 3 are both children of 1.
+    for revlog in vec![simple(), generaldelta()] {
+        let ancestors = |idx: u32| {
+            revlog
+                .ancestors(RevIdx::from(idx))
+                .collect::<Result<Vec<_>>>()
+                .unwrap()
+        };
+        assert_eq!(ancestors(0), revs(&[]));
+        assert_eq!(ancestors(1), revs(&[0]));
+        assert_eq!(ancestors(3), revs(&[1, 0]));
+
+        let descendants = |idx: u32| {
+            revlog
+                .descendants(RevIdx::from(idx))
+                .collect::<Result<Vec<_>>>()
+                .unwrap()
+        };
+        assert_eq!(descendants(0), revs(&[1, 2, 3]));
+        assert_eq!(descendants(1), revs(&[2, 3]));
+        assert_eq!(descendants(2), revs(&[]));
+    }
+
+    assert!(simple().ancestors(RevIdx::from(4u32)).next().unwrap().is_err());
+}
+
+#[test]
+fn rev_reader() {
+    for revlog in vec![simple(), generaldelta()] {
+        for (idx, text) in TEXTS.iter().enumerate() {
+            let mut reader = revlog.get_rev_reader(RevIdx::from(idx)).expect("no reader");
+
+            // Read in small pieces to make sure reads which straddle deltas work.
+            let mut content = Vec::new();
+            let mut buf = [0; 3];
+            loop {
+                match reader.read(&mut buf).expect("read failed") {
+                    0 => break,
+                    len => content.extend_from_slice(&buf[..len]),
+                }
+            }
+
+            let node = revlog.get_rev(RevIdx::from(idx)).unwrap();
+            assert_eq!(node.as_blob().as_slice(), Some(&content[..]));
+            assert_eq!(&content[..], *text);
+        }
+    }
+}
+
+#[test]
+fn censored() {
+    // Set the censored flag on revision 2.
+    let mut idx = SIMPLE_IDX.to_vec();
+    idx[2 * parser::indexng_size() + 6] = 0x80;
+    let revlog = Revlog::new(idx, Some(SIMPLE_DATA.to_vec())).unwrap();
+
+    assert!(!revlog.get_entry(RevIdx::from(1u32)).unwrap().is_censored());
+    assert!(revlog.get_entry(RevIdx::from(2u32)).unwrap().is_censored());
+
+    assert!(revlog.get_rev(RevIdx::from(1u32)).is_ok());
+    match revlog.get_rev(RevIdx::from(2u32)) {
+        Err(Error(ErrorKind::Censored(idx), _)) => assert_eq!(idx, RevIdx::from(2u32)),
+        res => panic!("unexpected result {:?}", res),
+    }
+    assert!(revlog.get_rev_reader(RevIdx::from(2u32)).is_err());
+}
+
+#[test]
+fn delta_chain() {
+    let revs = |v: &[u32]| v.iter().map(|idx| RevIdx::from(*idx)).collect::<Vec<_>>();
+    let simple = simple();
+    let generaldelta = generaldelta();
+
+    assert_eq!(simple.delta_chain(RevIdx::from(0u32)).unwrap(), revs(&[0]));
+    assert_eq!(simple.delta_chain(RevIdx::from(1u32)).unwrap(), revs(&[1, 0]));
+    assert_eq!(simple.delta_chain(RevIdx::from(3u32)).unwrap(), revs(&[3, 2]));
+    assert_eq!(generaldelta.delta_chain(RevIdx::from(2u32)).unwrap(), revs(&[2]));
+    assert_eq!(generaldelta.delta_chain(RevIdx::from(3u32)).unwrap(), revs(&[3, 1, 0]));
+}
+
+#[test]
+fn chain_length() {
+    let lengths = |revlog: &Revlog| {
+        (0..NODEIDS.len())
+            .map(|idx| revlog.chain_length(RevIdx::from(idx)).unwrap())
+            .collect::<Vec<_>>()
+    };
+
+    // Snapshots have no deltas.
+    assert_eq!(lengths(&simple()), vec![0, 1, 0, 1]);
+    assert_eq!(lengths(&generaldelta()), vec![0, 1, 0, 2]);
+    assert!(simple().chain_length(RevIdx::from(4u32)).is_err());
+}
+
+#[test]
+fn no_such_revision() {
+    for revlog in vec![simple(), generaldelta()] {
+        for idx in vec![4u32, 100] {
+            match revlog.get_entry(RevIdx::from(idx)) {
+                Err(Error(ErrorKind::NoSuchRevision(missing), _)) => {
+                    assert_eq!(missing, RevIdx::from(idx))
+                }
+                res => panic!("unexpected result {:?}", res),
+            }
+        }
+    }
+}
+
+#[test]
+fn raw_chunk() {
+    let revlog = simple();
+
+    // Revision 0 is stored uncompressed with a 'u' marker.
+    let mut expected = b"u".to_vec();
+    expected.extend_from_slice(TEXTS[0]);
+    assert_eq!(revlog.get_raw_chunk(RevIdx::from(0u32)).unwrap(), expected);
+
+    // Revision 2 is zlib compressed.
+    let entry = revlog.get_entry(RevIdx::from(2u32)).unwrap();
+    let chunk = revlog.get_raw_chunk(RevIdx::from(2u32)).unwrap();
+    assert_eq!(chunk.len(), entry.compressed_len as usize);
+    assert_eq!(chunk[0], b'x');
+}
+
+#[test]
+fn resolve_prefix() {
+    let revlog = simple();
+    let prefix = |prefix| NodeHash::from_prefix(prefix).unwrap();
+
+    assert_eq!(revlog.resolve_prefix(&prefix(NODEIDS[2])).unwrap(), nodeid(2));
+    assert_eq!(revlog.resolve_prefix(&prefix("33d6")).unwrap(), nodeid(2));
+    match revlog.resolve_prefix(&prefix("ffff")) {
+        Err(Error(ErrorKind::NoMatchingNode(_), _)) => (),
+        res => panic!("unexpected result {:?}", res),
+    }
+
+    // Give revision 3 a nodeid which differs from revision 2's only in the last byte.
+    let mut idx = SIMPLE_IDX.to_vec();
+    let (node2, node3) = (2 * 64 + 32, 3 * 64 + 32);
+    let nodeid2 = idx[node2..node2 + 20].to_vec();
+    idx[node3..node3 + 20].copy_from_slice(&nodeid2);
+    idx[node3 + 19] ^= 1;
+    let revlog = Revlog::new(idx, Some(SIMPLE_DATA.to_vec())).unwrap();
+
+    match revlog.resolve_prefix(&prefix("33d6")) {
+        Err(Error(ErrorKind::AmbiguousPrefix(_, ref candidates), _)) => {
+            assert_eq!(candidates.len(), 2);
+            assert!(candidates.contains(&nodeid(2)));
+        }
+        res => panic!("unexpected result {:?}", res),
+    }
+}
+
+#[test]
+fn versions() {
+    let v1 = simple();
+    assert_eq!(v1.get_header().version, Version::RevlogNG);
+
+    let v2 = Revlog::new(SIMPLE_V2_IDX.to_vec(), Some(SIMPLE_DATA.to_vec())).unwrap();
+    assert_eq!(v2.get_header().version, Version::RevlogV2);
+    let entries: Vec<_> = v2.entries().collect::<Result<_>>().unwrap();
+    assert_eq!(entries.len(), SIMPLE_V2_IDX.len() / parser::indexv2_size());
+    assert_eq!(entries.len(), NODEIDS.len());
+    for (idx, entry) in entries.iter().enumerate() {
+        assert_eq!(entry.nodeid, nodeid(idx));
+    }
+    assert!(v2.verify().unwrap().is_ok());
+
+    let mut idx = SIMPLE_IDX.to_vec();
+    idx[3] = 3;
+    match Revlog::new(idx, None) {
+        Err(Error(ErrorKind::UnsupportedRevlogVersion(3), _)) => (),
+        res => panic!("unexpected result {:?}", res),
+    }
+}
+
+#[test]
+fn mmap_matches_loaded() {
+    let dir = TempDir::new("revlog").expect("tempdir failed");
+    let idxpath = dir.path().join("simple.i");
+    File::create(&idxpath).unwrap().write_all(SIMPLE_IDX).unwrap();
+    File::create(dir.path().join("simple.d")).unwrap().write_all(SIMPLE_DATA).unwrap();
+
+    let mapped = Revlog::from_idx_data(&idxpath, None as Option<String>).unwrap();
+    let loaded = simple();
+
+    let nodeids = |revlog: &Revlog| {
+        revlog
+            .entries()
+            .map(|entry| entry.map(|entry| (entry.nodeid, entry.offset, entry.p1)))
+            .collect::<Result<Vec<_>>>()
+            .unwrap()
+    };
+    assert_eq!(nodeids(&mapped), nodeids(&loaded));
+    for idx in 0..NODEIDS.len() {
+        let idx = RevIdx::from(idx);
+        assert_eq!(
+            mapped.get_rev(idx).unwrap().as_blob().as_slice(),
+            loaded.get_rev(idx).unwrap().as_blob().as_slice()
+        );
+    }
+}
+
+#[test]
+fn header_fields() {
+    let cases = vec![
+        (simple(), Version::RevlogNG, false, false),
+        (generaldelta(), Version::RevlogNG, true, true),
+        (Revlog::new(EMPTY.to_vec(), None).unwrap(), Version::RevlogNG, true, true),
+        (
+            Revlog::new(SIMPLE_V2_IDX.to_vec(), Some(SIMPLE_DATA.to_vec())).unwrap(),
+            Version::RevlogV2,
+            false,
+            false,
+        ),
+    ];
+
+    for (revlog, version, inline, generaldelta) in cases {
+        let hdr = revlog.get_header();
+        assert_eq!(hdr.version(), version);
+        assert_eq!(hdr.is_inline(), inline);
+        assert_eq!(hdr.is_generaldelta(), generaldelta);
+    }
+}
+
+#[test]
+fn bisect() {
+    for revlog in vec![simple(), generaldelta().with_cache(4)] {
+        // Only revisions 2 and 3 are longer than 20 bytes.
+        let mut probes = 0;
+        let first = revlog
+            .bisect(|node| {
+                probes += 1;
+                Ok(node.size().expect("node has no data") > 20)
+            })
+            .unwrap();
+        assert_eq!(first, Some(RevIdx::from(2u32)));
+        assert!(probes <= 3);
+
+        assert_eq!(revlog.bisect(|_| Ok(true)).unwrap(), Some(RevIdx::zero()));
+        assert_eq!(revlog.bisect(|_| Ok(false)).unwrap(), None);
+        assert!(revlog.bisect(|_| Err("failed".into())).is_err());
+    }
+}
+
+#[test]
+fn append_full() {
+    let long: Vec<u8> = b"a long and repetitive text\n"
+        .iter()
+        .cycle()
+        .take(1000)
+        .cloned()
+        .collect();
+    let texts: [&[u8]; 4] = [b"epsilon\n", &long, b"\0binary", b""];
+
+    for mut revlog in vec![simple(), generaldelta()] {
+        let mut parent = nodeid(3);
+        for (i, text) in texts.iter().enumerate() {
+            let node = revlog.append_full(text, Some(&parent), None).unwrap();
+            assert_eq!(node, BlobNode::new(text.to_vec(), Some(&parent), None).nodeid().unwrap());
+
+            let idx = revlog.get_idx_by_nodeid(&node).unwrap();
+            assert_eq!(idx, RevIdx::from(NODEIDS.len() + i));
+            assert_eq!(revlog.get_entry(idx).unwrap().linkrev(), Some(idx));
+            assert_eq!(revlog.parents(idx).unwrap(), (Some(parent), None));
+            let rev = revlog.get_rev(idx).unwrap();
+            assert_eq!(rev.as_blob().as_slice(), Some(*text));
+            parent = node;
+        }
+
+        // Appending an existing revision doesn't add another copy.
+        assert_eq!(revlog.append_full(TEXTS[0], None, None).unwrap(), nodeid(0));
+        assert_eq!(revlog.entries().count(), NODEIDS.len() + texts.len());
+        assert!(revlog.verify().unwrap().is_ok());
+
+        // Unknown parents are rejected.
+        let unknown = NodeHash::from_str("0123456789012345678901234567890123456789").unwrap();
+        assert!(revlog.append_full(b"orphan", Some(&unknown), None).is_err());
+    }
+
+    // Mapped files can't be appended to.
+    let dir = TempDir::new("revlog").expect("tempdir failed");
+    let idxpath = dir.path().join("simple.i");
+    File::create(&idxpath).unwrap().write_all(SIMPLE_IDX).unwrap();
+    File::create(dir.path().join("simple.d")).unwrap().write_all(SIMPLE_DATA).unwrap();
+    let mut revlog = Revlog::from_idx_data(&idxpath, None as Option<String>).unwrap();
+    assert!(revlog.append_full(b"epsilon\n", None, None).is_err());
+}
+
+// A revlog holding just the first revision of the fixtures, which the others descend from.
+fn first_rev() -> Revlog {
+    let idx = SIMPLE_IDX[..parser::indexng_size()].to_vec();
+    let data = SIMPLE_DATA[..simple().sizes(RevIdx::zero()).unwrap().0 as usize].to_vec();
+    Revlog::new(idx, Some(data)).expect("construction failed")
+}
+
+#[test]
+fn export_range() {
+    for revlog in vec![simple(), generaldelta()] {
+        let mut export = Vec::new();
+        revlog
+            .export_range(RevIdx::from(1u32)..RevIdx::from(4u32), &mut export)
+            .unwrap();
+
+        let mut imported = first_rev();
+        let nodeids = imported.import_revs(&mut &export[..]).unwrap();
+        assert_eq!(nodeids, vec![nodeid(1), nodeid(2), nodeid(3)]);
+
+        for (idx, text) in TEXTS.iter().enumerate() {
+            let idx = RevIdx::from(idx);
+            let node = imported.get_rev(idx).unwrap();
+            assert_eq!(node.as_blob().as_slice(), Some(*text));
+            assert_eq!(imported.parents(idx).unwrap(), revlog.parents(idx).unwrap());
+        }
+        assert!(imported.verify().unwrap().is_ok());
+    }
+
+    // An empty range is just the magic string.
+    let mut export = Vec::new();
+    simple().export_range(RevIdx::zero()..RevIdx::zero(), &mut export).unwrap();
+    assert!(first_rev().import_revs(&mut &export[..]).unwrap().is_empty());
+
+    // Truncated or altered exports are rejected.
+    let mut export = Vec::new();
+    simple().export_range(RevIdx::from(1u32)..RevIdx::from(3u32), &mut export).unwrap();
+    assert!(first_rev().import_revs(&mut &export[..export.len() - 1]).is_err());
+    let mut altered = export.clone();
+    let last = altered.len() - 1;
+    altered[last] ^= 1;
+    assert!(first_rev().import_revs(&mut &altered[..]).is_err());
+    assert!(first_rev().import_revs(&mut &b"not an export"[..]).is_err());
+
+    assert!(simple().export_range(RevIdx::zero()..RevIdx::from(5u32), &mut Vec::new()).is_err());
+}
+
+#[test]
+fn sizes() {
+    for revlog in vec![simple(), generaldelta()] {
+        for idx in 0..NODEIDS.len() {
+            let idx = RevIdx::from(idx);
+            let (stored, full) = revlog.sizes(idx).unwrap();
+            let node = revlog.get_rev(idx).unwrap();
+            assert_eq!(full, node.size().unwrap() as u64);
+            assert_eq!(stored, revlog.get_raw_chunk(idx).unwrap().len() as u64);
+        }
+    }
+}
+
+#[test]
+fn flags() {
+    // Mark revision 2 as stored externally, as LFS does.
+    let mut idx = SIMPLE_IDX.to_vec();
+    idx[2 * parser::indexng_size() + 6] = 0x20;
+    let revlog = Revlog::new(idx, Some(SIMPLE_DATA.to_vec())).unwrap();
+
+    assert_eq!(revlog.get_entry(RevIdx::from(1u32)).unwrap().flags(), parser::IdxFlags::empty());
+    assert_eq!(revlog.get_entry(RevIdx::from(2u32)).unwrap().flags(), parser::EXTSTORED);
+
+    // The stored pointer isn't returned as if it were the content.
+    assert!(revlog.get_rev(RevIdx::from(1u32)).is_ok());
+    match revlog.get_rev(RevIdx::from(2u32)) {
+        Err(Error(ErrorKind::UnsupportedFlag(idx, flag), _)) => {
+            assert_eq!(idx, RevIdx::from(2u32));
+            assert_eq!(flag, parser::EXTSTORED.bits());
+        }
+        res => panic!("unexpected result {:?}", res),
+    }
+    assert!(revlog.get_rev_reader(RevIdx::from(2u32)).is_err());
+
+    // Unknown flags make the entry unreadable.
+    let mut idx = SIMPLE_IDX.to_vec();
+    idx[2 * parser::indexng_size() + 7] = 0x01;
+    let revlog = Revlog::new(idx, Some(SIMPLE_DATA.to_vec())).unwrap();
+    assert!(revlog.get_entry(RevIdx::from(1u32)).is_ok());
+    assert!(revlog.get_entry(RevIdx::from(2u32)).is_err());
+}
+
+#[test]
+fn truncated_index() {
+    match Revlog::new(TRUNCATED_IDX.to_vec(), Some(SIMPLE_DATA.to_vec())) {
+        Err(Error(ErrorKind::TruncatedIndex(valid, extra), _)) => {
+            assert_eq!(valid, 3);
+            assert_eq!(extra, parser::indexng_size() - 5);
+        }
+        res => panic!("unexpected result {:?}", res),
+    }
+
+    let dir = TempDir::new("revlog").expect("tempdir failed");
+    let idxpath = dir.path().join("simple.i");
+    File::create(&idxpath).unwrap().write_all(TRUNCATED_IDX).unwrap();
+    File::create(dir.path().join("simple.d")).unwrap().write_all(SIMPLE_DATA).unwrap();
+    assert!(Revlog::from_idx_data(&idxpath, None as Option<String>).is_err());
+
+    // Tolerating the truncation exposes just the complete entries, whether the index is
+    // loaded or mapped.
+    let loaded = Revlog::new_truncated(TRUNCATED_IDX.to_vec(), Some(SIMPLE_DATA.to_vec()));
+    let mapped = Revlog::from_idx_data_truncated(&idxpath, None as Option<String>);
+    for revlog in vec![loaded.unwrap(), mapped.unwrap()] {
+        assert_eq!(revlog.entries().count(), 3);
+        for (idx, text) in TEXTS.iter().take(3).enumerate() {
+            let node = revlog.get_rev(RevIdx::from(idx)).expect("get_rev failed");
+            assert_eq!(node.as_blob().as_slice(), Some(*text));
+        }
+        assert!(revlog.get_rev(RevIdx::from(3u32)).is_err());
+    }
+}
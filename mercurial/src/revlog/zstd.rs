@@ -0,0 +1,27 @@
+// Copyright (c) 2004-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+// Support for zstd compressed revlogs (format.revlog-compression=zstd)
+use nom::{self, IResult};
+use zstd::stream::decode_all;
+
+use super::parser::{Badness, Error, detach_result};
+
+// The zstd frame magic number starts with 0x28, which doubles as the compression marker, so
+// the whole chunk is a single zstd frame.
+pub fn zstd_decompress<P, R>(i: &[u8], parse: P) -> IResult<&[u8], R, Error>
+where
+    for<'a> P: Fn(&'a [u8]) -> IResult<&'a [u8], R, Error> + 'a,
+{
+    let data = match decode_all(i) {
+        Ok(data) => data,
+        Err(_) => return IResult::Error(nom::ErrorKind::Custom(Badness::BadZstd)),
+    };
+
+    let remains = &i[i.len()..];
+
+    detach_result(parse(&data[..]), remains)
+}
@@ -24,7 +24,7 @@ use bookmarks::{Bookmarks, ListBookmarks};
 use mercurial;
 use mercurial::changeset;
 use mercurial_bundles::{Bundle2EncodeBuilder, parts};
-use mercurial_types::{Changeset, NULL_HASH, NodeHash, Parents, percent_encode};
+use mercurial_types::{Changeset, NodeHash, Parents, percent_encode};
 
 use hgproto::{self, GetbundleArgs, HgCommandRes, HgCommands};
 
@@ -174,7 +174,7 @@ impl HgCommands for RepoClient {
             type Error = hgproto::Error;
 
             fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
-                if self.n == self.bottom || self.n == NULL_HASH {
+                if self.n == self.bottom || self.n.is_null() {
                     return Ok(Async::Ready(None));
                 }
 
@@ -185,7 +185,7 @@ impl HgCommands for RepoClient {
                 self.wait_cs = None; // got it
 
                 let p = match cs.parents() {
-                    &Parents::None => NULL_HASH,
+                    &Parents::None => NodeHash::null(),
                     &Parents::One(ref p) => *p,
                     &Parents::Two(ref p, _) => *p,
                 };